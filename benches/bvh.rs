@@ -0,0 +1,80 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use ray::bvh::*;
+use ray::geom::*;
+use ray::material::Lambertian;
+use ray::object::*;
+use ray::scenes::*;
+use ray::sphere::Sphere;
+use std::sync::Arc;
+
+fn camera_rays(environment: &Environment, n: usize) -> Vec<Ray> {
+    let mut rng = SmallRng::seed_from_u64(0);
+    let mut rays = Vec::with_capacity(n * n);
+    for j in 0..n {
+        for i in 0..n {
+            let u = (i as Float + 0.5) / n as Float;
+            let v = (j as Float + 0.5) / n as Float;
+            rays.push(environment.camera.get_ray(&mut rng, u, v));
+        }
+    }
+    rays
+}
+
+fn trace(scene: &dyn Object, rays: &[Ray]) -> usize {
+    let mut rng = SmallRng::seed_from_u64(0);
+    rays.iter()
+        .filter(|r| scene.hit(&mut rng, r, 0.001, INFINITY).is_some())
+        .count()
+}
+
+fn random_spheres(n: usize) -> Objects {
+    let mut rng = SmallRng::seed_from_u64(7);
+    let material = Arc::new(Lambertian::new(color(0.5, 0.5, 0.5)));
+    let mut objects = Objects::new(Vec::new());
+    for _ in 0..n {
+        let center = point3(
+            rng.gen_range(-100.0..100.0),
+            rng.gen_range(-100.0..100.0),
+            rng.gen_range(-100.0..100.0),
+        );
+        objects.add(Sphere::new(center, rng.gen_range(0.1..2.0), material.clone()));
+    }
+    objects
+}
+
+fn traversal(c: &mut Criterion) {
+    let tree = book2_final_scene_with(BvhNode::new);
+    let linear = book2_final_scene_with(LinearBvh::new);
+    let rays = camera_rays(&tree, 128);
+    let mut group = c.benchmark_group("book2_final_scene");
+    group.bench_function("BvhNode", |b| {
+        b.iter(|| trace(black_box(&*tree.scene), &rays))
+    });
+    group.bench_function("LinearBvh", |b| {
+        b.iter(|| trace(black_box(&*linear.scene), &rays))
+    });
+    group.finish();
+}
+
+fn build(c: &mut Criterion) {
+    const N: usize = 10_000;
+    let mut group = c.benchmark_group("build_10k_spheres");
+    group.bench_function("BvhNode", |b| {
+        b.iter_with_setup(
+            || random_spheres(N),
+            |mut objects| BvhNode::new(&mut objects, 0, N, 0.0..1.0),
+        )
+    });
+    group.bench_function("LinearBvh", |b| {
+        b.iter_with_setup(
+            || random_spheres(N),
+            |mut objects| LinearBvh::new(&mut objects, 0, N, 0.0..1.0),
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, traversal, build);
+criterion_main!(benches);
@@ -25,22 +25,52 @@ impl Aabb {
         },
     };
 
-    pub fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> bool {
-        for a in 0..3 {
-            let inv_d = 1.0 / r.direction[a];
-            let mut t0 = (self.box_min[a] - r.origin[a]) * inv_d;
-            let mut t1 = (self.box_max[a] - r.origin[a]) * inv_d;
-            if inv_d < 0.0 {
+    /// Give flat boxes, e.g. around axis aligned triangles, a little thickness
+    /// so rays can still hit them.
+    pub fn padded(self) -> Self {
+        const DELTA: Float = 0.0001;
+        let mut b = self;
+        for a in 0..3u8 {
+            if b.box_max[a] - b.box_min[a] < 2.0 * DELTA {
+                b.box_min[a] -= DELTA;
+                b.box_max[a] += DELTA;
+            }
+        }
+        b
+    }
+
+    pub fn centroid(&self) -> Point3 {
+        0.5 * (self.box_min + self.box_max)
+    }
+
+    pub fn surface_area(&self) -> Float {
+        let d = self.box_max - self.box_min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Slab test with the reciprocal of the ray direction computed once by the caller.
+    pub fn hit_inv(&self, origin: Point3, inv_dir: Vec3, t_min: Float, t_max: Float) -> bool {
+        let mut t_min = t_min;
+        let mut t_max = t_max;
+        for a in 0..3u8 {
+            let mut t0 = (self.box_min[a] - origin[a]) * inv_dir[a];
+            let mut t1 = (self.box_max[a] - origin[a]) * inv_dir[a];
+            if inv_dir[a] < 0.0 {
                 (t0, t1) = (t1, t0)
             }
-            let t_min = if t0 > t_min { t0 } else { t_min };
-            let t_max = if t1 < t_max { t1 } else { t_max };
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
             if t_max <= t_min {
                 return false;
             }
         }
         true
     }
+
+    pub fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> bool {
+        let inv_dir = vec3(1.0 / r.direction.x, 1.0 / r.direction.y, 1.0 / r.direction.z);
+        self.hit_inv(r.origin, inv_dir, t_min, t_max)
+    }
 }
 
 pub fn surrounding_box(box0: Aabb, box1: Aabb) -> Aabb {
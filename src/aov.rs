@@ -0,0 +1,225 @@
+use crate::geom::*;
+use crate::material::Material;
+use crate::object::{HitRecord, Ray};
+use crate::sampler::splitmix64;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Arbitrary output variables, render passes written alongside the beauty
+/// image. The surface passes describe the first hit of the camera ray and
+/// are black where it escapes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Aov {
+    /// The distance to the first hit.
+    Depth,
+    /// The outward normal at the first hit in world space.
+    Normal,
+    /// The color the first hit scatters or emits, the background where the
+    /// ray escapes.
+    Albedo,
+    /// One more than the `HitRecord::object` of the first hit.
+    ObjectId,
+    /// A color per material, the same for the whole of one render.
+    MaterialId,
+    /// Light reaching the camera straight from emitters and the background.
+    Emission,
+    /// Light reaching the camera after one bounce.
+    Direct,
+    /// Light reaching the camera after two or more bounces.
+    Indirect,
+    /// The light from each emitting object, a layer per object.
+    Lights,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 9] = [
+        Aov::Depth,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::ObjectId,
+        Aov::MaterialId,
+        Aov::Emission,
+        Aov::Direct,
+        Aov::Indirect,
+        Aov::Lights,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::Emission => "emission",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Lights => "lights",
+        }
+    }
+}
+
+impl FromStr for Aov {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.replace('-', "_");
+        Aov::ALL
+            .into_iter()
+            .find(|aov| aov.name() == name)
+            .ok_or_else(|| format!("unknown AOV '{}'", s))
+    }
+}
+
+/// A layer of the film: an AOV and, for `Aov::Lights`, the object the light
+/// comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Layer {
+    pub aov: Aov,
+    pub light: u32,
+}
+
+impl Layer {
+    pub fn new(aov: Aov) -> Self {
+        Self { aov, light: 0 }
+    }
+
+    pub fn light(object: u32) -> Self {
+        Self {
+            aov: Aov::Lights,
+            light: object,
+        }
+    }
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.aov {
+            Aov::Lights => write!(f, "light_{}", self.light),
+            aov => write!(f, "{}", aov.name()),
+        }
+    }
+}
+
+/// A random color for `material`, which stays the same while it is alive.
+pub fn material_color(material: &Arc<dyn Material>) -> Color {
+    let id = splitmix64(Arc::as_ptr(material) as *const () as usize as u64);
+    let channel = |shift: u32| ((id >> shift) & 0xff) as Float / 255.0;
+    color(channel(0), channel(8), channel(16))
+}
+
+/// The AOVs of one sample, only those asked for are kept.
+#[derive(Clone, Debug, Default)]
+pub struct AovSample {
+    aovs: Vec<Aov>,
+    pub values: Vec<(Layer, Color)>,
+}
+
+impl AovSample {
+    pub fn new(aovs: &[Aov]) -> Self {
+        Self {
+            aovs: aovs.to_vec(),
+            values: Vec::new(),
+        }
+    }
+
+    pub fn wants(&self, aov: Aov) -> bool {
+        self.aovs.contains(&aov)
+    }
+
+    /// Forget the values for the next sample.
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    pub fn add(&mut self, layer: Layer, c: Color) {
+        if !self.wants(layer.aov) {
+            return;
+        }
+        match self.values.iter_mut().find(|(l, _)| *l == layer) {
+            Some((_, value)) => *value += c,
+            None => self.values.push((layer, c)),
+        }
+    }
+
+    /// Add the surface AOVs of `rec`, the first hit of the camera ray `r`,
+    /// which scatters or emits `albedo`.
+    pub fn add_surface(&mut self, r: &Ray, rec: &HitRecord, albedo: Color) {
+        let depth = rec.t * r.direction.length();
+        let normal = if rec.front_face {
+            rec.normal
+        } else {
+            -rec.normal
+        };
+        let id = (rec.object + 1) as Float;
+        self.add(Layer::new(Aov::Depth), color(depth, depth, depth));
+        self.add(Layer::new(Aov::Normal), normal);
+        self.add(Layer::new(Aov::Albedo), albedo);
+        self.add(Layer::new(Aov::ObjectId), color(id, id, id));
+        if self.wants(Aov::MaterialId) {
+            self.add(Layer::new(Aov::MaterialId), material_color(&rec.material));
+        }
+    }
+
+    /// Add the surface AOVs of a camera ray that escapes to `background`.
+    pub fn add_miss(&mut self, background: Color) {
+        for aov in [Aov::Depth, Aov::Normal, Aov::ObjectId, Aov::MaterialId] {
+            self.add(Layer::new(aov), BLACK);
+        }
+        self.add(Layer::new(Aov::Albedo), background);
+    }
+
+    /// Add light `c` reaching the camera after `bounces` bounces, emitted by
+    /// `object` or the background if None.
+    pub fn add_light(&mut self, c: Color, bounces: u32, object: Option<u32>) {
+        let aov = match bounces {
+            0 => Aov::Emission,
+            1 => Aov::Direct,
+            _ => Aov::Indirect,
+        };
+        self.add(Layer::new(aov), c);
+        if let Some(object) = object {
+            self.add(Layer::light(object), c);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aov_names() {
+        for aov in Aov::ALL {
+            assert_eq!(aov.name().parse(), Ok(aov));
+        }
+        assert_eq!("object-id".parse(), Ok(Aov::ObjectId));
+        assert!("beauty".parse::<Aov>().is_err());
+        assert_eq!(Layer::light(3).to_string(), "light_3");
+        assert_eq!(Layer::new(Aov::Depth).to_string(), "depth");
+    }
+
+    #[test]
+    fn test_aov_sample() {
+        let mut sample = AovSample::new(&[Aov::Direct, Aov::Indirect, Aov::Lights]);
+        sample.add_light(WHITE, 0, Some(1));
+        sample.add_light(WHITE, 1, Some(2));
+        sample.add_light(WHITE, 2, Some(2));
+        sample.add_light(WHITE, 3, None);
+        sample.add_miss(WHITE);
+        let mut values = sample.values.clone();
+        values.sort_by_key(|(l, _)| *l);
+        assert_eq!(
+            values,
+            vec![
+                (Layer::new(Aov::Direct), WHITE),
+                (Layer::new(Aov::Indirect), 2.0 * WHITE),
+                (Layer::light(1), WHITE),
+                (Layer::light(2), 2.0 * WHITE),
+            ]
+        );
+        sample.clear();
+        assert!(sample.values.is_empty());
+    }
+}
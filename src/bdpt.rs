@@ -0,0 +1,520 @@
+use crate::geom::*;
+use crate::material::Reflection;
+use crate::object::{HitRecord, Object, Ray};
+use crate::pdf::{CosinePdf, Pdf};
+use crate::render::{PathStats, BOUNCE_DIMENSIONS};
+use crate::sampler::SampleStream;
+use crate::scenes::Environment;
+use rand::{Rng, RngCore};
+use std::sync::Arc;
+
+// How a vertex scatters the light arriving along the path.
+enum Bsdf {
+    // Directions are sampled from the pdf, and the material's scattering pdf
+    // times the attenuation is the BSDF times the cosine.
+    Diffuse(Arc<dyn Pdf>, Color),
+    Specular,
+    // Absorbs everything, like the lights.
+    None,
+}
+
+enum Kind {
+    Camera,
+    // A point on a light with the record facing the side it emits to.
+    Light(HitRecord),
+    Surface {
+        rec: HitRecord,
+        r_in: Ray,
+        bsdf: Bsdf,
+    },
+}
+
+struct Vertex {
+    p: Point3,
+    // The normal on the side the path arrived from, None in volumes and at the
+    // camera.
+    normal: Option<Vec3>,
+    // The throughput of the subpath up to and excluding this vertex.
+    beta: Color,
+    kind: Kind,
+}
+
+impl Vertex {
+    fn camera(p: Point3) -> Self {
+        Self {
+            p,
+            normal: None,
+            beta: WHITE,
+            kind: Kind::Camera,
+        }
+    }
+
+    fn light(rec: HitRecord) -> Self {
+        Self {
+            p: rec.p,
+            normal: Some(rec.normal),
+            beta: WHITE,
+            kind: Kind::Light(rec),
+        }
+    }
+
+    fn rec(&self) -> Option<&HitRecord> {
+        match &self.kind {
+            Kind::Camera => None,
+            Kind::Light(rec) | Kind::Surface { rec, .. } => Some(rec),
+        }
+    }
+
+    fn is_delta(&self) -> bool {
+        matches!(
+            self.kind,
+            Kind::Surface {
+                bsdf: Bsdf::Specular,
+                ..
+            }
+        )
+    }
+
+    fn is_diffuse(&self) -> bool {
+        matches!(
+            self.kind,
+            Kind::Surface {
+                bsdf: Bsdf::Diffuse(..),
+                ..
+            }
+        )
+    }
+
+    // The BSDF times the cosine for light leaving along `direction`.
+    fn f(&self, direction: Vec3) -> Color {
+        match &self.kind {
+            Kind::Surface {
+                rec,
+                r_in,
+                bsdf: Bsdf::Diffuse(_, attenuation),
+            } => {
+                let out = Ray::new(self.p, direction, r_in.time);
+                *attenuation * rec.material.scattering_pdf(r_in, rec, &out)
+            }
+            _ => BLACK,
+        }
+    }
+
+    // The density in solid angle of scattering along `direction`.
+    fn pdf(&self, direction: Vec3) -> Float {
+        match &self.kind {
+            Kind::Surface {
+                bsdf: Bsdf::Diffuse(pdf, _),
+                ..
+            } => pdf.value(direction),
+            _ => 0.0,
+        }
+    }
+
+    // Radiance emitted along `direction`.
+    fn le(&self, direction: Vec3) -> Color {
+        match (self.rec(), self.normal) {
+            (Some(rec), Some(n)) if dot(n, direction) > 0.0 => {
+                rec.material.color_emitted(rec, rec.u, rec.v, rec.p)
+            }
+            _ => BLACK,
+        }
+    }
+
+    // The density in solid angle of a light at this vertex emitting along
+    // `direction`, cosine weighted around the normal.
+    fn emission_pdf(&self, direction: Vec3) -> Float {
+        self.normal
+            .map_or(0.0, |n| dot(n, direction.normalize()).max(0.0) / PI)
+    }
+
+    fn cos(&self, direction: Vec3) -> Float {
+        self.normal
+            .map_or(1.0, |n| dot(n, direction.normalize()).abs())
+    }
+
+    // Convert a density in solid angle at `self` of the direction towards `to`
+    // into a density per area at `to`.
+    fn to_area(&self, pdf: Float, to: &Vertex) -> Float {
+        let d = to.p - self.p;
+        pdf * to.cos(d) / d.length2()
+    }
+}
+
+pub(crate) fn flipped(rec: &HitRecord) -> HitRecord {
+    let mut flipped = HitRecord::new(
+        rec.p,
+        -rec.normal,
+        rec.material.clone(),
+        rec.t,
+        rec.u,
+        rec.v,
+        !rec.front_face,
+    );
+    flipped.object = rec.object;
+    flipped
+}
+
+pub(crate) fn emits(rec: &HitRecord) -> bool {
+    rec.material.color_emitted(rec, rec.u, rec.v, rec.p) != BLACK
+}
+
+// Light paths start at a point sampled on `lights` as seen from the camera,
+// on the side facing the camera if that emits and the far side otherwise.
+// Returns the point and its density per area.
+fn sample_light_origin(
+    rng: &mut dyn RngCore,
+    environment: &Environment,
+    time: Float,
+    stats: &mut PathStats,
+) -> Option<(HitRecord, Float)> {
+    let o = environment.camera.origin;
+    let lights = &*environment.lights;
+    let v = lights.random(rng, o);
+    let pdf = lights.pdf_value(o, v);
+    if pdf <= 0.0 || !pdf.is_finite() {
+        return None;
+    }
+    stats.rays += 1;
+    let ray = Ray::new(o, v, time);
+    let t = lights.hit(rng, &ray, 0.001, INFINITY)?.t;
+    let rec = environment
+        .scene
+        .hit(rng, &ray, t * (1.0 - 1e-3), t * (1.0 + 1e-3))?;
+    let rec = if emits(&rec) { rec } else { flipped(&rec) };
+    if !emits(&rec) {
+        return None;
+    }
+    let d = rec.p - o;
+    let area_pdf = pdf * dot(rec.normal, d.normalize()).abs() / d.length2();
+    Some((rec, area_pdf))
+}
+
+// The density per area with which `sample_light_origin` picks the light
+// vertex `x0`.
+fn light_origin_pdf(
+    rng: &mut SampleStream,
+    environment: &Environment,
+    x0: &Vertex,
+    time: Float,
+) -> Float {
+    let (Some(rec), Some(n)) = (x0.rec(), x0.normal) else {
+        return 0.0;
+    };
+    let o = environment.camera.origin;
+    let v = x0.p - o;
+    let lights = &*environment.lights;
+    match lights.hit(rng, &Ray::new(o, v, time), 0.001, INFINITY) {
+        Some(hit) if (hit.t - 1.0).abs() < 1e-3 => {}
+        _ => return 0.0,
+    }
+    // Paths start on the far side only when the near one is dark.
+    if dot(n, v) > 0.0 && emits(&flipped(rec)) {
+        return 0.0;
+    }
+    lights.pdf_value(o, v) * x0.cos(v) / v.length2()
+}
+
+// Whether nothing blocks the segment from `a` to `b`.
+fn visible(
+    rng: &mut SampleStream,
+    environment: &Environment,
+    a: Point3,
+    b: Point3,
+    time: Float,
+    stats: &mut PathStats,
+) -> bool {
+    stats.rays += 1;
+    let d = b - a;
+    let length = d.length();
+    let ray = Ray::new(a, d / length, time);
+    environment
+        .scene
+        .hit(rng, &ray, 0.001, length - 0.001)
+        .is_none()
+}
+
+// Trace a subpath from `ray` and append its vertices to `path` until it
+// escapes, is absorbed, has `max_vertices` vertices or Russian roulette ends
+// it. Returns the background the path escaped to, weighted by its throughput.
+#[allow(clippy::too_many_arguments)]
+fn random_walk(
+    rng: &mut SampleStream,
+    environment: &Environment,
+    mut ray: Ray,
+    mut beta: Color,
+    max_vertices: usize,
+    path: &mut Vec<Vertex>,
+    stats: &mut PathStats,
+    from_camera: bool,
+) -> Color {
+    while path.len() < max_vertices {
+        let depth = path.len() as u32 - 1;
+        stats.rays += 1;
+        if from_camera {
+            PathStats::count(&mut stats.depths, depth);
+        }
+        rng.next_block(BOUNCE_DIMENSIONS);
+        let Some(rec) = environment.scene.hit(rng, &ray, 0.001, INFINITY) else {
+            return beta * environment.background();
+        };
+        let mut attenuation = WHITE;
+        let (bsdf, next) = match rec.material.scatter(rng, &ray, &rec) {
+            None => (Bsdf::None, None),
+            Some(scatter) => {
+                attenuation = scatter.attenuation;
+                match scatter.reflection {
+                    Reflection::Specular(next) => (Bsdf::Specular, Some((next, 1.0))),
+                    Reflection::Scatter(pdf) => {
+                        let direction = pdf.generate(rng);
+                        let pdf_val = pdf.value(direction);
+                        let next = Ray::new(rec.p, direction, ray.time);
+                        (Bsdf::Diffuse(pdf, attenuation), Some((next, pdf_val)))
+                    }
+                }
+            }
+        };
+        let normal = (!rec.material.is_volume()).then_some(rec.normal);
+        path.push(Vertex {
+            p: rec.p,
+            normal,
+            beta,
+            kind: Kind::Surface {
+                rec,
+                r_in: ray,
+                bsdf,
+            },
+        });
+        let vertex = path.last().unwrap();
+        let Some((next, pdf_val)) = next else {
+            break;
+        };
+        if vertex.is_delta() {
+            beta = beta * attenuation;
+        } else if pdf_val > 0.0 {
+            beta = beta * vertex.f(next.direction) / pdf_val;
+        } else {
+            break;
+        }
+        ray = next;
+        if depth + 1 >= environment.params.rr_depth && path.len() < max_vertices {
+            let survival = beta.x.max(beta.y).max(beta.z).min(1.0);
+            if rng.gen::<Float>() >= survival {
+                if from_camera {
+                    PathStats::count(&mut stats.terminated, depth);
+                }
+                break;
+            }
+            beta /= survival;
+        }
+    }
+    BLACK
+}
+
+// The power heuristic weight of joining the first `s` vertices of `path`,
+// listed from the light to the camera, to the others, against the other
+// strategies that could have made the same path.
+fn mis_weight(
+    rng: &mut SampleStream,
+    environment: &Environment,
+    path: &[&Vertex],
+    s: usize,
+    time: Float,
+) -> Float {
+    let n = path.len();
+    let (w, h) = (environment.width() as Float, environment.height() as Float);
+    let pixels = (w - 1.0) * (h - 1.0) / (w * h);
+    let max_vertices = environment.max_depth() as usize + 1;
+    // The density per area of every vertex when sampled from its neighbor on
+    // the light side and on the camera side. Specular bounces count as 1.
+    let mut fwd = vec![1.0; n];
+    let mut rev = vec![1.0; n];
+    for k in 1..n - 1 {
+        let (prev, x) = (path[k - 1], path[k]);
+        if !prev.is_delta() {
+            let pdf = if k == 1 {
+                prev.emission_pdf(x.p - prev.p)
+            } else {
+                prev.pdf(x.p - prev.p)
+            };
+            fwd[k] = prev.to_area(pdf, x) as f64;
+        }
+    }
+    for k in 0..n - 1 {
+        let (next, x) = (path[k + 1], path[k]);
+        if !next.is_delta() {
+            let pdf = if k + 1 == n - 1 {
+                environment.camera.direction_density(x.p - next.p) * pixels
+            } else {
+                next.pdf(x.p - next.p)
+            };
+            rev[k] = next.to_area(pdf, x) as f64;
+        }
+    }
+    let origin = light_origin_pdf(rng, environment, path[0], time) as f64;
+    let nee = if n > 2 {
+        let pdf = environment
+            .lights
+            .pdf_value(path[1].p, path[0].p - path[1].p);
+        path[1].to_area(pdf, path[0]) as f64
+    } else {
+        0.0
+    };
+    // The density of the path when the light subpath has `i` vertices.
+    let density = |i: usize| -> f64 {
+        if i > max_vertices || n - i > max_vertices || (i == 1 && n == 2) {
+            return 0.0;
+        }
+        if i > 0 && (path[i - 1].is_delta() || path[i].is_delta()) {
+            return 0.0;
+        }
+        let light = match i {
+            0 => 1.0,
+            1 => nee,
+            _ => origin * fwd[1..i].iter().product::<f64>(),
+        };
+        light * rev[i..n - 1].iter().product::<f64>()
+    };
+    let sum: f64 = (0..n).map(|i| density(i).powi(2)).sum();
+    if sum > 0.0 {
+        (density(s).powi(2) / sum) as Float
+    } else {
+        0.0
+    }
+}
+
+/// Radiance arriving along the camera ray `r`, estimated by bidirectional
+/// path tracing. A light subpath is traced as well and every prefix of it is
+/// joined to every prefix of the camera subpath, each way of making a path
+/// weighted against the others with the power heuristic. Light subpaths
+/// joined straight to the camera land on other pixels and are passed to
+/// `splat` with the film position, counted from the top row.
+pub fn bdpt_color(
+    rng: &mut SampleStream,
+    r: &Ray,
+    environment: &Environment,
+    stats: &mut PathStats,
+    splat: &mut dyn FnMut(u32, u32, Color),
+) -> Color {
+    let camera = &environment.camera;
+    let (w, h) = (environment.width(), environment.height());
+    let max_vertices = environment.max_depth() as usize + 1;
+    let mut camera_path = vec![Vertex::camera(r.origin)];
+    let mut radiance = random_walk(
+        rng,
+        environment,
+        *r,
+        WHITE,
+        max_vertices,
+        &mut camera_path,
+        stats,
+        true,
+    );
+
+    let mut light_path = Vec::new();
+    if let Some((rec, pdf)) = sample_light_origin(rng, environment, r.time, stats) {
+        let y0 = Vertex::light(rec);
+        let direction = CosinePdf::with_w(y0.normal.unwrap()).generate(rng);
+        let pdf_dir = y0.emission_pdf(direction);
+        let beta = y0.le(direction) * y0.cos(direction) / (pdf * pdf_dir);
+        let ray = Ray::new(y0.p, direction, r.time);
+        light_path.push(y0);
+        if pdf_dir > 0.0 && beta != BLACK {
+            random_walk(
+                rng,
+                environment,
+                ray,
+                beta,
+                max_vertices,
+                &mut light_path,
+                stats,
+                false,
+            );
+        }
+    }
+
+    for t in 1..=camera_path.len() {
+        for s in 0..=light_path.len().max(1) {
+            if s + t < 2 || (s == 1 && t == 1) || s + t > max_vertices + 1 {
+                continue;
+            }
+            let pt = &camera_path[t - 1];
+            let camera_side = camera_path[..t].iter().rev();
+            match (s, t) {
+                (0, _) => {
+                    let c = pt.beta * pt.le(camera_path[t - 2].p - pt.p);
+                    if c != BLACK {
+                        let path: Vec<&Vertex> = camera_side.collect();
+                        radiance += mis_weight(rng, environment, &path, 0, r.time) * c;
+                    }
+                }
+                (1, _) => {
+                    if !pt.is_diffuse() {
+                        continue;
+                    }
+                    let lights = &*environment.lights;
+                    let direction = lights.random(rng, pt.p);
+                    let pdf = lights.pdf_value(pt.p, direction);
+                    let f = pt.f(direction);
+                    if pdf <= 0.0 || !pdf.is_finite() || f == BLACK {
+                        continue;
+                    }
+                    stats.rays += 1;
+                    let shadow = Ray::new(pt.p, direction, r.time);
+                    let Some(rec) = environment.scene.hit(rng, &shadow, 0.001, INFINITY) else {
+                        continue;
+                    };
+                    let x0 = Vertex::light(rec);
+                    let c = pt.beta * f * x0.le(-direction) / pdf;
+                    if c != BLACK {
+                        let path: Vec<&Vertex> = std::iter::once(&x0).chain(camera_side).collect();
+                        radiance += mis_weight(rng, environment, &path, 1, r.time) * c;
+                    }
+                }
+                (_, 1) => {
+                    let qs = &light_path[s - 1];
+                    if !qs.is_diffuse() {
+                        continue;
+                    }
+                    let lens = camera.sample_lens(rng);
+                    let Some((u, v)) = camera.raster(lens, qs.p) else {
+                        continue;
+                    };
+                    let (i, j) = (u * (w - 1) as Float, v * (h - 1) as Float);
+                    if i < 0.0 || j < 0.0 || i >= w as Float || j >= h as Float {
+                        continue;
+                    }
+                    let d = lens - qs.p;
+                    let f = qs.f(d);
+                    if f == BLACK || !visible(rng, environment, qs.p, lens, r.time, stats) {
+                        continue;
+                    }
+                    let importance = ((w - 1) * (h - 1)) as Float * camera.direction_density(-d);
+                    let z0 = Vertex::camera(lens);
+                    let path: Vec<&Vertex> =
+                        light_path[..s].iter().chain(std::iter::once(&z0)).collect();
+                    let c =
+                        mis_weight(rng, environment, &path, s, r.time) * qs.beta * f * importance
+                            / d.length2();
+                    if c.x.is_finite() && c.y.is_finite() && c.z.is_finite() && c != BLACK {
+                        splat(i as u32, h - 1 - j as u32, c);
+                    }
+                }
+                _ => {
+                    let qs = &light_path[s - 1];
+                    if !qs.is_diffuse() || !pt.is_diffuse() {
+                        continue;
+                    }
+                    let d = pt.p - qs.p;
+                    let c = qs.beta * qs.f(d) * pt.f(-d) * pt.beta / d.length2();
+                    if c == BLACK || !visible(rng, environment, qs.p, pt.p, r.time, stats) {
+                        continue;
+                    }
+                    let path: Vec<&Vertex> = light_path[..s].iter().chain(camera_side).collect();
+                    radiance += mis_weight(rng, environment, &path, s, r.time) * c;
+                }
+            }
+        }
+    }
+    radiance
+}
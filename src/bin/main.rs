@@ -1,10 +1,602 @@
+use clap::{Parser, ValueEnum};
+use ray::aov::Aov;
+use ray::film::Film;
+use ray::filter::FilterKind;
+use ray::geom::*;
+use ray::gltf_import::load_gltf;
+use ray::integrator::IntegratorKind;
+use ray::io::*;
 use ray::render::*;
-use ray::io::write_png;
+use ray::sampler::SamplerKind;
+use ray::scene_file::load_scene;
 use ray::scenes::*;
+use ray::tile::TileOrder;
+use ray::tonemap::*;
+use std::path::PathBuf;
+use std::process::exit;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Png,
+    Ppm,
+    /// OpenEXR, linear float
+    Exr,
+    /// Radiance RGBE, linear
+    Hdr,
+    /// Portable float map, linear
+    Pfm,
+}
+
+impl From<Format> for ImageFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Png => ImageFormat::Png,
+            Format::Ppm => ImageFormat::Ppm,
+            Format::Exr => ImageFormat::Exr,
+            Format::Hdr => ImageFormat::Hdr,
+            Format::Pfm => ImageFormat::Pfm,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Order {
+    Scanline,
+    Spiral,
+    Hilbert,
+}
+
+impl From<Order> for TileOrder {
+    fn from(order: Order) -> Self {
+        match order {
+            Order::Scanline => TileOrder::Scanline,
+            Order::Spiral => TileOrder::Spiral,
+            Order::Hilbert => TileOrder::Hilbert,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Sequence {
+    Independent,
+    /// Correlated multi-jittered
+    Stratified,
+    Halton,
+    /// Owen scrambled Sobol
+    Sobol,
+    BlueNoise,
+}
+
+impl From<Sequence> for SamplerKind {
+    fn from(sequence: Sequence) -> Self {
+        match sequence {
+            Sequence::Independent => SamplerKind::Independent,
+            Sequence::Stratified => SamplerKind::Stratified,
+            Sequence::Halton => SamplerKind::Halton,
+            Sequence::Sobol => SamplerKind::Sobol,
+            Sequence::BlueNoise => SamplerKind::BlueNoise,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Method {
+    /// Path tracing with light sampling
+    Path,
+    /// Bidirectional path tracing
+    Bdpt,
+    /// Stochastic progressive photon mapping, one pass per sample
+    Sppm,
+    /// Primary sample space Metropolis light transport
+    Mlt,
+    /// Surface normals
+    Normals,
+    /// Distance to the first hit over the diagonal of the scene
+    Depth,
+    /// Texture coordinates
+    Uv,
+    /// Surface color
+    Albedo,
+    /// A color per material
+    MaterialId,
+    /// Heatmap of BVH traversal steps
+    BvhCost,
+    /// Ambient occlusion
+    Ao,
+    /// Direct lighting only
+    Direct,
+}
+
+impl From<Method> for IntegratorKind {
+    fn from(method: Method) -> Self {
+        match method {
+            Method::Path => IntegratorKind::Path,
+            Method::Bdpt => IntegratorKind::Bidirectional,
+            Method::Sppm => IntegratorKind::PhotonMapping,
+            Method::Mlt => IntegratorKind::Metropolis,
+            Method::Normals => IntegratorKind::Normals,
+            Method::Depth => IntegratorKind::Depth,
+            Method::Uv => IntegratorKind::Uv,
+            Method::Albedo => IntegratorKind::Albedo,
+            Method::MaterialId => IntegratorKind::MaterialId,
+            Method::BvhCost => IntegratorKind::BvhCost,
+            Method::Ao => IntegratorKind::AmbientOcclusion,
+            Method::Direct => IntegratorKind::Direct,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Reconstruction {
+    Box,
+    Tent,
+    Gaussian,
+    /// Mitchell-Netravali, sharp with slight ringing
+    Mitchell,
+    /// Windowed sinc, the sharpest, rings the most
+    Lanczos,
+}
+
+impl From<Reconstruction> for FilterKind {
+    fn from(filter: Reconstruction) -> Self {
+        match filter {
+            Reconstruction::Box => FilterKind::Box,
+            Reconstruction::Tent => FilterKind::Tent,
+            Reconstruction::Gaussian => FilterKind::Gaussian,
+            Reconstruction::Mitchell => FilterKind::Mitchell,
+            Reconstruction::Lanczos => FilterKind::Lanczos,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Operator {
+    Clamp,
+    Reinhard,
+    /// Reinhard mapping --white to 1
+    ExtendedReinhard,
+    Aces,
+    Hable,
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "Render a built in scene or a scene file", after_help = scene_help())]
+struct Args {
+    /// Name of a built in scene
+    #[arg(short, long, default_value = "marbles", conflicts_with = "file")]
+    scene: String,
+
+    /// Load the scene from a TOML scene file or a glTF (.gltf, .glb) file
+    #[arg(short, long)]
+    file: Option<PathBuf>,
+
+    /// List the built in scenes and exit
+    #[arg(long)]
+    list: bool,
+
+    /// Image width in pixels, the height follows from the aspect ratio
+    #[arg(short, long)]
+    width: Option<u32>,
+
+    /// Samples per pixel
+    #[arg(long)]
+    samples: Option<u32>,
+
+    /// Maximum number of bounces per path
+    #[arg(long)]
+    max_depth: Option<u32>,
+
+    /// Bounces after which Russian roulette may end dim paths
+    #[arg(long)]
+    rr_depth: Option<u32>,
+
+    /// Light transport algorithm
+    #[arg(long, value_enum)]
+    integrator: Option<Method>,
+
+    /// Photons per photon mapping pass, default one per pixel
+    #[arg(long)]
+    photons: Option<u32>,
+
+    /// Initial photon gathering radius, default from the scene size
+    #[arg(long)]
+    photon_radius: Option<Float>,
+
+    /// Independent paths that normalize Metropolis light transport
+    #[arg(long)]
+    bootstrap: Option<u32>,
+
+    /// Markov chains for Metropolis light transport
+    #[arg(long)]
+    chains: Option<u32>,
+
+    /// Chance of a Metropolis mutation being a large step
+    #[arg(long)]
+    large_step: Option<Float>,
+
+    /// Size of small Metropolis mutations
+    #[arg(long)]
+    mutation_size: Option<Float>,
+
+    /// How far ambient occlusion looks for occluders
+    #[arg(long)]
+    ao_distance: Option<Float>,
+
+    /// Width / height of the image
+    #[arg(long)]
+    aspect_ratio: Option<Float>,
+
+    /// Background color as r,g,b
+    #[arg(long, value_parser = parse_color)]
+    background: Option<Color>,
+
+    /// Output file, defaults to images/image_N.<format>
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Output format, defaults to the extension of the output file or png
+    #[arg(long, value_enum)]
+    format: Option<Format>,
+
+    /// Passes to write besides the image, comma separated: depth, normal,
+    /// albedo, object-id, material-id, emission, direct, indirect and lights.
+    /// They are layers of an EXR, or files named image.<pass>.<format>
+    #[arg(long, value_delimiter = ',')]
+    aov: Vec<Aov>,
+
+    /// Filter the noise out of the image, guided by the albedo and normals
+    #[arg(long)]
+    denoise: bool,
+
+    /// Passes of the denoiser, each reaching twice as far
+    #[arg(long)]
+    denoise_passes: Option<u32>,
+
+    /// Standard errors of luminance the denoiser averages across
+    #[arg(long)]
+    denoise_sigma_color: Option<Float>,
+
+    /// Normal difference the denoiser averages across
+    #[arg(long)]
+    denoise_sigma_normal: Option<Float>,
+
+    /// Albedo difference the denoiser averages across
+    #[arg(long)]
+    denoise_sigma_albedo: Option<Float>,
+
+    /// Pixel reconstruction filter samples are spread over neighboring
+    /// pixels with
+    #[arg(long)]
+    filter: Option<Reconstruction>,
+
+    /// Radius of the reconstruction filter in pixels, defaults to 0.5 for
+    /// box, 1 for tent, 1.5 for gaussian and 2 for mitchell and lanczos
+    #[arg(long)]
+    filter_radius: Option<Float>,
+
+    /// Number of render threads, defaults to the number of cores
+    #[arg(short, long)]
+    threads: Option<usize>,
+
+    /// Seed for the sampling random number generator, renders with the same
+    /// seed are identical
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Sample generator for the camera, materials and lights
+    #[arg(long, value_enum)]
+    sampler: Option<Sequence>,
+
+    /// Width and height of the render tiles in pixels
+    #[arg(long)]
+    tile_size: Option<u32>,
+
+    /// Order in which tiles are rendered
+    #[arg(long, value_enum)]
+    tile_order: Option<Order>,
+
+    /// Tone mapping for 8 bit output, which debug methods skip
+    #[arg(long, value_enum)]
+    tone_map: Option<Operator>,
+
+    /// White point of the extended Reinhard operator
+    #[arg(long, default_value_t = 4.0)]
+    white: Float,
+
+    /// Exposure compensation in stops
+    #[arg(long)]
+    exposure: Option<Float>,
+
+    /// Render in passes of this many samples per pixel, writing the image
+    /// after every pass
+    #[arg(long)]
+    pass_samples: Option<u32>,
+
+    /// Save the accumulated samples to this file after every pass
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+
+    /// Continue from --checkpoint, adding samples up to --samples
+    #[arg(long, requires = "checkpoint")]
+    resume: bool,
+
+    /// Sample adaptively, stopping at pixels whose relative error falls
+    /// below this
+    #[arg(long)]
+    noise_threshold: Option<Float>,
+
+    /// Samples per pixel before adaptive sampling measures the error
+    #[arg(long)]
+    min_samples: Option<u32>,
+
+    /// Also write the number of samples per pixel as a png heatmap
+    #[arg(long)]
+    heatmap: Option<PathBuf>,
+
+    /// Print how many paths reached and were ended at each depth
+    #[arg(long)]
+    stats: bool,
+
+    /// Do not print progress
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+fn scene_help() -> String {
+    let mut help = String::from("Built in scenes:\n");
+    for (name, description, _) in SCENES {
+        help.push_str(&format!("  {:<16}{}\n", name, description));
+    }
+    help
+}
+
+fn parse_color(s: &str) -> Result<Color, String> {
+    let rgb = s
+        .split(',')
+        .map(|c| c.trim().parse::<Float>().map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    match rgb[..] {
+        [r, g, b] => Ok(color(r, g, b)),
+        _ => Err(format!("expected r,g,b but found '{}'", s)),
+    }
+}
+
+fn print_progress(progress: &Progress) {
+    let eta = progress.eta().map_or("--:--".to_string(), |eta| {
+        let s = eta.as_secs();
+        format!("{:02}:{:02}", s / 60, s % 60)
+    });
+    eprint!(
+        "\rtiles {}/{}  rays {:.1}M  eta {}   ",
+        progress.tiles_done,
+        progress.tiles_total,
+        progress.rays_traced as f64 / 1e6,
+        eta
+    );
+    if progress.tiles_done == progress.tiles_total {
+        eprintln!();
+    }
+}
+
+fn print_stats(stats: &PathStats) {
+    eprintln!("depth       paths  roulette");
+    for (depth, paths) in stats.depths.iter().enumerate() {
+        let ended = stats.terminated.get(depth).copied().unwrap_or(0);
+        eprintln!("{:>5} {:>11} {:>9}", depth, paths, ended);
+    }
+    eprintln!(
+        "rays {:.1}M  mean path length {:.2}",
+        stats.rays as f64 / 1e6,
+        stats.mean_length()
+    );
+}
 
 fn main() {
-    // let mut data: Vec<u8> = Vec::new();
-    let environment = marbles_scene();
-    let data = render(&environment);
-    write_png(&data, environment.width(), environment.height(), "image");
+    let args = Args::parse();
+    if args.list {
+        print!("{}", scene_help());
+        return;
+    }
+
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .unwrap();
+    }
+
+    let mut environment = match &args.file {
+        Some(path) => {
+            let gltf = path.extension().is_some_and(|ext| {
+                ext.eq_ignore_ascii_case("gltf") || ext.eq_ignore_ascii_case("glb")
+            });
+            let loaded = if gltf {
+                load_gltf(path).map_err(|e| e.to_string())
+            } else {
+                load_scene(path).map_err(|e| e.to_string())
+            };
+            loaded.unwrap_or_else(|e| {
+                eprintln!("error: {}", e);
+                exit(1);
+            })
+        }
+        None => builtin_scene(&args.scene).unwrap_or_else(|| {
+            eprintln!("error: unknown scene '{}'\n\n{}", args.scene, scene_help());
+            exit(1);
+        }),
+    };
+
+    if let Some(aspect_ratio) = args.aspect_ratio {
+        environment.set_aspect_ratio(aspect_ratio);
+    }
+    if let Some(width) = args.width {
+        environment.params.set_width(width);
+    }
+    if let Some(samples) = args.samples {
+        environment.params.samples_per_pixel = samples;
+    }
+    if let Some(max_depth) = args.max_depth {
+        environment.params.max_depth = max_depth;
+    }
+    if let Some(rr_depth) = args.rr_depth {
+        environment.params.rr_depth = rr_depth;
+    }
+    if let Some(method) = args.integrator {
+        environment.params.integrator = method.into();
+    }
+    if let Some(photons) = args.photons {
+        environment.params.photons_per_pass = photons;
+    }
+    if let Some(radius) = args.photon_radius {
+        environment.params.photon_radius = radius;
+    }
+    if let Some(bootstrap) = args.bootstrap {
+        environment.params.mlt_bootstrap = bootstrap;
+    }
+    if let Some(chains) = args.chains {
+        environment.params.mlt_chains = chains;
+    }
+    if let Some(probability) = args.large_step {
+        environment.params.large_step_probability = probability;
+    }
+    if let Some(size) = args.mutation_size {
+        environment.params.mutation_size = size;
+    }
+    if let Some(distance) = args.ao_distance {
+        environment.params.ao_distance = distance;
+    }
+    if !args.aov.is_empty() {
+        environment.params.aovs = args.aov;
+    }
+    if args.denoise {
+        environment.params.denoise = true;
+    }
+    if let Some(passes) = args.denoise_passes {
+        environment.params.denoise_passes = passes;
+    }
+    if let Some(sigma) = args.denoise_sigma_color {
+        environment.params.denoise_sigma_color = sigma;
+    }
+    if let Some(sigma) = args.denoise_sigma_normal {
+        environment.params.denoise_sigma_normal = sigma;
+    }
+    if let Some(sigma) = args.denoise_sigma_albedo {
+        environment.params.denoise_sigma_albedo = sigma;
+    }
+    if let Some(filter) = args.filter {
+        environment.params.filter = filter.into();
+    }
+    if let Some(radius) = args.filter_radius {
+        environment.params.filter_radius = radius;
+    }
+    if let Some(background) = args.background {
+        environment.params.background = background;
+    }
+    if let Some(seed) = args.seed {
+        environment.params.seed = seed;
+    }
+    if let Some(sampler) = args.sampler {
+        environment.params.sampler = sampler.into();
+    }
+    if let Some(tile_size) = args.tile_size {
+        environment.params.tile_size = tile_size;
+    }
+    if let Some(order) = args.tile_order {
+        environment.params.tile_order = order.into();
+    }
+    if let Some(operator) = args.tone_map {
+        environment.params.tone_map = match operator {
+            Operator::Clamp => ToneMap::Clamp,
+            Operator::Reinhard => ToneMap::Reinhard,
+            Operator::ExtendedReinhard => ToneMap::ExtendedReinhard { white: args.white },
+            Operator::Aces => ToneMap::AcesFitted,
+            Operator::Hable => ToneMap::Hable,
+        };
+    }
+    if let Some(exposure) = args.exposure {
+        environment.params.exposure = exposure;
+    }
+
+    let format = args.format.map(ImageFormat::from).unwrap_or_else(|| {
+        args.output
+            .as_deref()
+            .and_then(ImageFormat::from_path)
+            .unwrap_or(ImageFormat::Png)
+    });
+
+    let path = args
+        .output
+        .unwrap_or_else(|| next_path("image", format.extension()));
+
+    let mut film = Film::new(environment.width(), environment.height());
+    if let (true, Some(checkpoint)) = (args.resume, &args.checkpoint) {
+        let (saved, sampling) = load_checkpoint(checkpoint).unwrap_or_else(|e| {
+            eprintln!("error: cannot read {}: {}", checkpoint.display(), e);
+            exit(1);
+        });
+        if (saved.width(), saved.height()) != (film.width(), film.height()) {
+            eprintln!(
+                "error: {} is {}x{} but the image is {}x{}",
+                checkpoint.display(),
+                saved.width(),
+                saved.height(),
+                film.width(),
+                film.height()
+            );
+            exit(1);
+        }
+        if sampling.sampler != environment.params.sampler {
+            eprintln!(
+                "error: {} was rendered with the {:?} sampler, not {:?}",
+                checkpoint.display(),
+                sampling.sampler,
+                environment.params.sampler
+            );
+            exit(1);
+        }
+        film = saved;
+        environment.params.seed = sampling.seed;
+        environment.params.sampler_pattern = sampling.pattern;
+    }
+    if let Some(samples) = args.pass_samples {
+        environment.params.samples_per_pass = samples;
+    }
+    if let Some(threshold) = args.noise_threshold {
+        environment.params.noise_threshold = threshold;
+    }
+    if let Some(samples) = args.min_samples {
+        environment.params.min_samples = samples;
+    }
+
+    let params = &environment.params;
+    let progress: &(dyn Fn(&Progress) + Sync) = if args.quiet { &|_| {} } else { &print_progress };
+    let save = |film: &Film| {
+        let film = output_film(film, params);
+        save_film(&film, format, params, &path)
+    };
+    let fail = |path: &PathBuf, e: std::io::Error| {
+        eprintln!("error: cannot write {}: {}", path.display(), e);
+        exit(1);
+    };
+    let sampling = Sampling {
+        seed: params.seed,
+        sampler: params.sampler,
+        pattern: sampler_pattern(&environment),
+    };
+    let stats = render_progressive(&environment, &mut film, progress, &mut |film| {
+        if params.samples_per_pass != 0 {
+            save(film).unwrap_or_else(|e| fail(&path, e));
+        }
+        if let Some(checkpoint) = &args.checkpoint {
+            save_checkpoint(film, &sampling, checkpoint).unwrap_or_else(|e| fail(checkpoint, e));
+        }
+    });
+    save(&film).unwrap_or_else(|e| fail(&path, e));
+    if let Some(heatmap) = &args.heatmap {
+        save_png(&film.sample_heatmap(), film.width(), film.height(), heatmap)
+            .unwrap_or_else(|e| fail(heatmap, e));
+    }
+    if args.stats {
+        print_stats(&stats);
+    }
 }
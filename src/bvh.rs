@@ -1,97 +1,609 @@
 use crate::aabb::*;
+use crate::geom::*;
 use crate::object::*;
-use crate::geom::Float;
-use rand::prelude::*;
-use std::cmp::Ordering;
+use rand::RngCore;
+use rayon::prelude::*;
+use std::cell::Cell;
 use std::ops::Range;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub struct BvhNode {
-    pub left: Arc<dyn Object>,
-    pub right: Arc<dyn Object>,
-    pub bbox: Aabb,
+pub const SAH_BINS: usize = 12;
+const BVH_LEAF_SIZE: usize = 4;
+
+// Cost of visiting a node relative to intersecting one primitive.
+const TRAVERSAL_COST: Float = 0.125;
+
+thread_local! {
+    static TRAVERSAL_STEPS: Cell<u64> = const { Cell::new(0) };
 }
 
-impl BvhNode {
-    pub fn new(objects: &mut Objects, start: usize, end: usize, time: Range<Float>) -> Self {
-        let mut rng = rand::thread_rng();
-        let axis = rng.gen_range(0..3);
-        let comparator = match axis {
-            0 => Self::x_comparator,
-            1 => Self::y_comparator,
-            2 => Self::z_comparator,
-            _ => unreachable!("Random int in range [0, 2] must be in range"),
-        };
-        let object_span = end - start;
-        let (left, right) = match object_span {
-            1 => {
-                let first: Arc<dyn Object> = objects.objects.remove(0).into();
-                (first.clone(), first)
+// The live `TraversalCounter`s. Steps are only counted while there are any,
+// so ordinary rays pay for one relaxed load rather than a thread local update.
+static COUNTERS: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+fn count_steps(steps: u64) {
+    if COUNTERS.load(Ordering::Relaxed) > 0 {
+        TRAVERSAL_STEPS.with(|s| s.set(s.get() + steps));
+    }
+}
+
+/// Counts the nodes visited and primitives tested by hierarchies on this
+/// thread for as long as it lives.
+pub struct TraversalCounter(());
+
+impl TraversalCounter {
+    pub fn start() -> Self {
+        COUNTERS.fetch_add(1, Ordering::Relaxed);
+        TRAVERSAL_STEPS.with(|s| s.set(0));
+        Self(())
+    }
+
+    pub fn steps(&self) -> u64 {
+        TRAVERSAL_STEPS.with(|s| s.get())
+    }
+}
+
+impl Drop for TraversalCounter {
+    fn drop(&mut self) {
+        COUNTERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Partition `items` for a binned surface area heuristic split. `boxes` and
+/// `centroids` are indexed by the values in `items`. Returns the number of
+/// items moved to the front for the left child and the split axis, or `None`
+/// if a leaf is cheaper.
+/// Groups larger than `max_leaf` are always split. The result only depends on
+/// the input, ties go to the lowest axis and bin.
+pub fn sah_split(
+    items: &mut [u32],
+    boxes: &[Aabb],
+    centroids: &[Point3],
+    max_leaf: usize,
+) -> Option<(usize, Axis)> {
+    let n = items.len();
+    if n <= 1 {
+        return None;
+    }
+    let mut bbox = Aabb::EMPTY;
+    let mut bounds = Aabb::EMPTY;
+    for &i in items.iter() {
+        bbox = surrounding_box(bbox, boxes[i as usize]);
+        let c = centroids[i as usize];
+        bounds = surrounding_box(bounds, Aabb::new(c, c));
+    }
+
+    let bin_of = |axis: Axis, c: Point3| {
+        let extent = bounds.box_max[axis] - bounds.box_min[axis];
+        let b = ((c[axis] - bounds.box_min[axis]) * (SAH_BINS as Float / extent)) as usize;
+        b.min(SAH_BINS - 1)
+    };
+
+    let mut best: Option<(Float, Axis, usize)> = None;
+    for axis in [Axis::X, Axis::Y, Axis::Z] {
+        if bounds.box_max[axis] - bounds.box_min[axis] <= 0.0 {
+            continue;
+        }
+        let mut bins = [(Aabb::EMPTY, 0usize); SAH_BINS];
+        for &i in items.iter() {
+            let bin = &mut bins[bin_of(axis, centroids[i as usize])];
+            bin.0 = surrounding_box(bin.0, boxes[i as usize]);
+            bin.1 += 1;
+        }
+        // right_cost[k] is the area times count of bins k.. on the right.
+        let mut right_cost = [0.0; SAH_BINS];
+        let (mut b, mut count) = (Aabb::EMPTY, 0);
+        for k in (1..SAH_BINS).rev() {
+            b = surrounding_box(b, bins[k].0);
+            count += bins[k].1;
+            right_cost[k] = if count > 0 {
+                b.surface_area() * count as Float
+            } else {
+                0.0
+            };
+        }
+        let (mut b, mut count) = (Aabb::EMPTY, 0);
+        for k in 1..SAH_BINS {
+            b = surrounding_box(b, bins[k - 1].0);
+            count += bins[k - 1].1;
+            if count == 0 || count == n {
+                continue;
             }
-            2 => {
-                let first: Arc<dyn Object> = objects.objects.remove(0).into();
-                let second: Arc<dyn Object> = objects.objects.remove(0).into();
-                match comparator(&*first, &*second) {
-                    Ordering::Less => (first, second),
-                    _ => (second, first),
-                }
+            let cost = b.surface_area() * count as Float + right_cost[k];
+            if best.is_none_or(|(c, _, _)| cost < c) {
+                best = Some((cost, axis, k));
             }
-            _ => {
-                objects.objects.sort_by(|x, y| comparator(&**x, &**y));
-                let mid = start + object_span / 2;
-                let left: Arc<dyn Object> = Arc::new(Self::new(objects, start, mid, time.clone()));
-                let right: Arc<dyn Object> = Arc::new(Self::new(objects, mid, end, time.clone()));
+        }
+    }
 
-                (left, right)
+    let area = bbox.surface_area();
+    match best {
+        None if n <= max_leaf => None,
+        // All centroids coincide, split in the middle to keep leaves small.
+        None => Some((n / 2, Axis::X)),
+        Some((cost, _, _)) if n <= max_leaf && cost_ratio(cost, area) >= n as Float => None,
+        Some((_, axis, k)) => {
+            let mut mid = 0;
+            for j in 0..n {
+                if bin_of(axis, centroids[items[j] as usize]) < k {
+                    items.swap(mid, j);
+                    mid += 1;
+                }
             }
-        };
+            Some((mid, axis))
+        }
+    }
+}
+
+fn cost_ratio(cost: Float, area: Float) -> Float {
+    if area > 0.0 {
+        TRAVERSAL_COST + cost / area
+    } else {
+        TRAVERSAL_COST
+    }
+}
+
+// Keeps the traversal stack below 64 entries.
+const FLAT_MAX_DEPTH: usize = 62;
+
+// Groups at least this large build their two subtrees in parallel.
+const PARALLEL_BUILD_SIZE: usize = 4096;
 
-        let box_left = left.bounding_box(&time).unwrap();
-        let box_right = right.bounding_box(&time).unwrap();
-        let bbox = surrounding_box(box_left, box_right);
-        Self { left, right, bbox }
-    }
-
-    fn comparator(x: &dyn Object, y: &dyn Object, axis: u8) -> Ordering {
-        let box_x = x.bounding_box(&(0.0..0.0)).unwrap();
-        let box_y = y.bounding_box(&(0.0..0.0)).unwrap();
-        let x = box_x.box_min[axis];
-        let y = box_y.box_min[axis];
-        if x < y {
-            Ordering::Less
-        } else if x > y {
-            Ordering::Greater
+// Leaves hold `count` primitives starting at `offset` in the primitive order.
+// Interior nodes have `count == 0`, their first child follows them, `offset`
+// is the index of the second child and `axis` is the split axis.
+#[derive(Clone, Copy, Debug)]
+struct FlatNode {
+    bbox: Aabb,
+    offset: u32,
+    count: u32,
+    axis: u8,
+}
+
+impl FlatNode {
+    fn leaf(bbox: Aabb, offset: usize, count: usize) -> Self {
+        Self {
+            bbox,
+            offset: offset as u32,
+            count: count as u32,
+            axis: 0,
+        }
+    }
+
+    fn interior(bbox: Aabb, second: usize, axis: Axis) -> Self {
+        Self {
+            bbox,
+            offset: second as u32,
+            count: 0,
+            axis: axis as u8,
+        }
+    }
+}
+
+/// A bounding volume hierarchy stored depth first in one array. It only
+/// indexes primitives, the owner keeps them in `order` and intersects them.
+pub struct FlatBvh {
+    nodes: Vec<FlatNode>,
+    pub order: Vec<u32>,
+}
+
+impl FlatBvh {
+    pub fn new(boxes: &[Aabb], max_leaf: usize) -> Self {
+        let centroids: Vec<Point3> = boxes.par_iter().map(|b| b.centroid()).collect();
+        let mut order: Vec<u32> = (0..boxes.len() as u32).collect();
+        let nodes = if boxes.is_empty() {
+            Vec::new()
         } else {
-            Ordering::Equal
+            Self::build_parallel(&mut order, 0, 0, boxes, &centroids, max_leaf)
+        };
+        Self { nodes, order }
+    }
+
+    fn split(
+        items: &mut [u32],
+        depth: usize,
+        boxes: &[Aabb],
+        centroids: &[Point3],
+        max_leaf: usize,
+    ) -> (Aabb, Option<(usize, Axis)>) {
+        let bbox = items
+            .iter()
+            .fold(Aabb::EMPTY, |b, &i| surrounding_box(b, boxes[i as usize]));
+        if depth == FLAT_MAX_DEPTH {
+            return (bbox, None);
         }
+        (bbox, sah_split(items, boxes, centroids, max_leaf))
     }
-    pub fn x_comparator(x: &dyn Object, y: &dyn Object) -> Ordering {
-        Self::comparator(x, y, 0)
+
+    fn build(
+        nodes: &mut Vec<FlatNode>,
+        items: &mut [u32],
+        offset: usize,
+        depth: usize,
+        boxes: &[Aabb],
+        centroids: &[Point3],
+        max_leaf: usize,
+    ) {
+        let index = nodes.len();
+        let (bbox, split) = Self::split(items, depth, boxes, centroids, max_leaf);
+        nodes.push(FlatNode::leaf(bbox, offset, items.len()));
+        if let Some((mid, axis)) = split {
+            let (left, right) = items.split_at_mut(mid);
+            Self::build(nodes, left, offset, depth + 1, boxes, centroids, max_leaf);
+            let second = nodes.len();
+            Self::build(
+                nodes,
+                right,
+                offset + mid,
+                depth + 1,
+                boxes,
+                centroids,
+                max_leaf,
+            );
+            nodes[index] = FlatNode::interior(bbox, second, axis);
+        }
     }
-    pub fn y_comparator(x: &dyn Object, y: &dyn Object) -> Ordering {
-        Self::comparator(x, y, 1)
+
+    // Subtrees are built into their own arrays and concatenated, which
+    // shifts the child indices of the second subtree.
+    fn build_parallel(
+        items: &mut [u32],
+        offset: usize,
+        depth: usize,
+        boxes: &[Aabb],
+        centroids: &[Point3],
+        max_leaf: usize,
+    ) -> Vec<FlatNode> {
+        let mut nodes = Vec::new();
+        if items.len() < PARALLEL_BUILD_SIZE {
+            Self::build(&mut nodes, items, offset, depth, boxes, centroids, max_leaf);
+            return nodes;
+        }
+        let (bbox, split) = Self::split(items, depth, boxes, centroids, max_leaf);
+        let Some((mid, axis)) = split else {
+            nodes.push(FlatNode::leaf(bbox, offset, items.len()));
+            return nodes;
+        };
+        let (left, right) = items.split_at_mut(mid);
+        let (left, right) = rayon::join(
+            || Self::build_parallel(left, offset, depth + 1, boxes, centroids, max_leaf),
+            || Self::build_parallel(right, offset + mid, depth + 1, boxes, centroids, max_leaf),
+        );
+        let second = 1 + left.len();
+        nodes.reserve_exact(second + right.len());
+        nodes.push(FlatNode::interior(bbox, second, axis));
+        for (shift, subtree) in [(1, left), (second, right)] {
+            nodes.extend(subtree.into_iter().map(|mut node| {
+                if node.count == 0 {
+                    node.offset += shift as u32;
+                }
+                node
+            }));
+        }
+        nodes
     }
-    pub fn z_comparator(x: &dyn Object, y: &dyn Object) -> Ordering {
-        Self::comparator(x, y, 2)
+
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        self.nodes.first().map(|n| n.bbox)
+    }
+
+    /// Visit the primitives whose boxes the ray enters before `t_max`, nearer
+    /// children first. `hit` gets a position in `order` and the current closest
+    /// distance and returns the distance of a closer hit if there is one.
+    pub fn traverse<F>(&self, r: &Ray, t_min: Float, t_max: Float, mut hit: F)
+    where
+        F: FnMut(usize, Float) -> Option<Float>,
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let inv_dir = vec3(
+            1.0 / r.direction.x,
+            1.0 / r.direction.y,
+            1.0 / r.direction.z,
+        );
+        let dir_neg = [inv_dir.x < 0.0, inv_dir.y < 0.0, inv_dir.z < 0.0];
+        let mut closest = t_max;
+        let mut stack = [0u32; 64];
+        let mut sp = 0;
+        let mut current = 0;
+        let mut steps = 0;
+        loop {
+            let node = &self.nodes[current as usize];
+            steps += 1;
+            if node.bbox.hit_inv(r.origin, inv_dir, t_min, closest) {
+                if node.count > 0 {
+                    let start = node.offset as usize;
+                    steps += node.count as u64;
+                    for i in start..start + node.count as usize {
+                        if let Some(t) = hit(i, closest) {
+                            closest = t;
+                        }
+                    }
+                } else {
+                    let (near, far) = if dir_neg[node.axis as usize] {
+                        (node.offset, current + 1)
+                    } else {
+                        (current + 1, node.offset)
+                    };
+                    stack[sp] = far;
+                    sp += 1;
+                    current = near;
+                    continue;
+                }
+            }
+            if sp == 0 {
+                break;
+            }
+            sp -= 1;
+            current = stack[sp];
+        }
+        count_steps(steps);
+    }
+}
+
+// Moves `objects[start..end]` out of `objects` and builds a flat hierarchy,
+// returning the objects in the hierarchy's order.
+fn build_objects(
+    objects: &mut Objects,
+    start: usize,
+    end: usize,
+    time: Range<Float>,
+) -> (FlatBvh, Vec<Box<dyn Object>>) {
+    let objects: Vec<Box<dyn Object>> = objects.objects.drain(start..end).collect();
+    let boxes: Vec<Aabb> = objects
+        .par_iter()
+        .map(|o| {
+            o.bounding_box(&time)
+                .expect("objects in a bvh must have a bounding box")
+        })
+        .collect();
+    let bvh = FlatBvh::new(&boxes, BVH_LEAF_SIZE);
+    let mut slots: Vec<Option<Box<dyn Object>>> = objects.into_iter().map(Some).collect();
+    let objects = bvh
+        .order
+        .iter()
+        .map(|&i| slots[i as usize].take().unwrap())
+        .collect();
+    (bvh, objects)
+}
+
+enum BvhChildren {
+    // The objects with their indices before the hierarchy reordered them.
+    Leaf(Vec<(u32, Box<dyn Object>)>),
+    Split(Box<BvhNode>, Box<BvhNode>),
+}
+
+pub struct BvhNode {
+    pub bbox: Aabb,
+    children: BvhChildren,
+}
+
+impl BvhNode {
+    /// Build a hierarchy over `objects[start..end]`, which are moved out of `objects`.
+    pub fn new(objects: &mut Objects, start: usize, end: usize, time: Range<Float>) -> Self {
+        let (bvh, objects) = build_objects(objects, start, end, time);
+        if bvh.nodes.is_empty() {
+            return Self {
+                bbox: Aabb::EMPTY,
+                children: BvhChildren::Leaf(Vec::new()),
+            };
+        }
+        let mut objects = bvh.order.iter().copied().zip(objects);
+        Self::from_flat(&bvh.nodes, 0, &mut objects)
+    }
+
+    // Leaves are visited in the order of their primitives.
+    fn from_flat(
+        nodes: &[FlatNode],
+        index: usize,
+        objects: &mut impl Iterator<Item = (u32, Box<dyn Object>)>,
+    ) -> Self {
+        let node = nodes[index];
+        let children = if node.count > 0 {
+            BvhChildren::Leaf(objects.take(node.count as usize).collect())
+        } else {
+            let left = Self::from_flat(nodes, index + 1, objects);
+            let right = Self::from_flat(nodes, node.offset as usize, objects);
+            BvhChildren::Split(Box::new(left), Box::new(right))
+        };
+        Self {
+            bbox: node.bbox,
+            children,
+        }
     }
 }
 
 impl Object for BvhNode {
-    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+    fn hit(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        count_steps(1);
         if !self.bbox.hit(ray, t_min, t_max) {
             return None;
         }
-        let left_record = self.left.hit(ray, t_min, t_max);
-        let t = if let Some(record) = &left_record {
-            record.t
-        } else {
-            t_max
-        };
-        let right_record = self.right.hit(ray, t_min, t);
-        right_record.or(left_record)
+        match &self.children {
+            BvhChildren::Leaf(objects) => {
+                count_steps(objects.len() as u64);
+                let mut closest = t_max;
+                let mut record = None;
+                for (i, object) in objects {
+                    if let Some(mut rec) = object.hit(rng, ray, t_min, closest) {
+                        closest = rec.t;
+                        rec.object = *i;
+                        record = Some(rec);
+                    }
+                }
+                record
+            }
+            BvhChildren::Split(left, right) => {
+                let left_record = left.hit(rng, ray, t_min, t_max);
+                let t = if let Some(record) = &left_record {
+                    record.t
+                } else {
+                    t_max
+                };
+                let right_record = right.hit(rng, ray, t_min, t);
+                right_record.or(left_record)
+            }
+        }
     }
 
     fn bounding_box(&self, _time_range: &std::ops::Range<Float>) -> Option<Aabb> {
         Some(self.bbox)
     }
 }
+
+/// A flattened hierarchy over objects, interchangeable with `BvhNode`.
+pub struct LinearBvh {
+    bvh: FlatBvh,
+    objects: Vec<Box<dyn Object>>,
+}
+
+impl LinearBvh {
+    /// Build a hierarchy over `objects[start..end]`, which are moved out of `objects`.
+    pub fn new(objects: &mut Objects, start: usize, end: usize, time: Range<Float>) -> Self {
+        let (bvh, objects) = build_objects(objects, start, end, time);
+        Self { bvh, objects }
+    }
+}
+
+impl Object for LinearBvh {
+    fn hit(&self, rng: &mut dyn RngCore, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let mut record = None;
+        self.bvh.traverse(ray, t_min, t_max, |i, closest| {
+            let mut rec = self.objects[i].hit(rng, ray, t_min, closest)?;
+            rec.object = self.bvh.order[i];
+            let t = rec.t;
+            record = Some(rec);
+            Some(t)
+        });
+        record
+    }
+
+    fn bounding_box(&self, _time_range: &Range<Float>) -> Option<Aabb> {
+        self.bvh.bounding_box()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+    use crate::material::Lambertian;
+    use crate::sphere::Sphere;
+    use std::sync::Arc;
+
+    fn spheres() -> Objects {
+        let material = Arc::new(Lambertian::new(color(0.5, 0.5, 0.5)));
+        let mut objects = Objects::new(Vec::new());
+        for i in 0..10 {
+            for j in 0..10 {
+                let center = point3(i as Float, (i * j % 7) as Float, j as Float);
+                objects.add(Sphere::new(center, 0.3, material.clone()));
+            }
+        }
+        objects
+    }
+
+    #[test]
+    fn test_sah_split_leaves() {
+        let boxes: Vec<Aabb> = (0..8)
+            .map(|i| {
+                let p = point3(i as Float, 0.0, 0.0);
+                Aabb::new(p, p + vec3(0.5, 0.5, 0.5))
+            })
+            .collect();
+        let centroids: Vec<Point3> = boxes.iter().map(|b| b.centroid()).collect();
+        let mut items: Vec<u32> = (0..8).rev().collect();
+        let (mid, axis) = sah_split(&mut items, &boxes, &centroids, 4).unwrap();
+        assert_eq!(axis, Axis::X);
+        assert!(mid > 0 && mid < 8);
+        assert!(items[..mid]
+            .iter()
+            .all(|&l| items[mid..].iter().all(|&r| l < r)));
+
+        let p = point3(1.0, 1.0, 1.0);
+        let same = vec![Aabb::new(p, p); 6];
+        let mut items: Vec<u32> = (0..6).collect();
+        assert_eq!(sah_split(&mut items[..3], &same, &[p; 6], 4), None);
+        assert_eq!(sah_split(&mut items, &same, &[p; 6], 4), Some((3, Axis::X)));
+    }
+
+    #[test]
+    fn test_bvh_matches_brute_force() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut objects = spheres();
+        let n = objects.objects.len();
+        let bvh = BvhNode::new(&mut objects, 0, n, 0.0..1.0);
+        assert!(objects.objects.is_empty());
+        let mut objects = spheres();
+        let linear = LinearBvh::new(&mut objects, 0, n, 0.0..1.0);
+        let brute = spheres();
+        for k in 0..200 {
+            let a = k as Float * 0.37;
+            let origin = point3(
+                4.5 + 20.0 * a.cos(),
+                3.0 + (a * 0.3).sin(),
+                4.5 + 20.0 * a.sin(),
+            );
+            let target = point3((k % 10) as Float, (k % 5) as Float, (k % 9) as Float);
+            let ray = Ray::new(origin, target - origin, 0.0);
+            let expected = brute.hit(&mut rng, &ray, 0.001, INFINITY).map(|r| r.t);
+            assert_eq!(bvh.hit(&mut rng, &ray, 0.001, INFINITY).map(|r| r.t), expected);
+            assert_eq!(
+                linear.hit(&mut rng, &ray, 0.001, INFINITY).map(|r| r.t),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_traversal_counter() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut objects = spheres();
+        let n = objects.objects.len();
+        let linear = LinearBvh::new(&mut objects, 0, n, 0.0..1.0);
+        let ray = Ray::new(point3(4.5, 20.0, 4.5), vec3(0.0, -1.0, 0.0), 0.0);
+        let counter = TraversalCounter::start();
+        linear.hit(&mut rng, &ray, 0.001, INFINITY);
+        let steps = counter.steps();
+        assert!(steps > 1);
+        drop(counter);
+        // A new counter starts from zero.
+        assert_eq!(TraversalCounter::start().steps(), 0);
+    }
+
+    #[test]
+    fn test_parallel_build() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let material = Arc::new(Lambertian::new(color(0.5, 0.5, 0.5)));
+        let grid = || {
+            let mut objects = Objects::new(Vec::new());
+            for i in 0..20 {
+                for j in 0..20 {
+                    for k in 0..20 {
+                        let center = point3(i as Float, j as Float, (k * k % 23) as Float);
+                        objects.add(Sphere::new(center, 0.2, material.clone()));
+                    }
+                }
+            }
+            objects
+        };
+        let n = 8000;
+        let first = LinearBvh::new(&mut grid(), 0, n, 0.0..1.0);
+        let second = LinearBvh::new(&mut grid(), 0, n, 0.0..1.0);
+        assert_eq!(first.bvh.order, second.bvh.order);
+        let tree = BvhNode::new(&mut grid(), 0, n, 0.0..1.0);
+        let brute = grid();
+        for k in 0..200 {
+            let a = k as Float * 0.41;
+            let origin = point3(10.0 + 40.0 * a.cos(), 10.0 * a.sin(), 10.0 + 40.0 * a.sin());
+            let target = point3((k % 20) as Float, (k % 7) as Float, (k % 11) as Float);
+            let ray = Ray::new(origin, target - origin, 0.0);
+            let expected = brute.hit(&mut rng, &ray, 0.001, INFINITY).map(|r| r.t);
+            assert_eq!(first.hit(&mut rng, &ray, 0.001, INFINITY).map(|r| r.t), expected);
+            assert_eq!(tree.hit(&mut rng, &ray, 0.001, INFINITY).map(|r| r.t), expected);
+        }
+    }
+}
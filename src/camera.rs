@@ -1,6 +1,6 @@
 use crate::geom::*;
 use crate::object::*;
-use rand::prelude::*;
+use rand::{Rng, RngCore};
 
 #[derive(Debug, Clone)]
 pub struct Camera {
@@ -16,6 +16,7 @@ pub struct Camera {
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         origin: Point3,
         lookat: Point3,
@@ -69,11 +70,66 @@ impl Camera {
         )
     }
 
-    pub fn get_ray(&self, s: Float, t: Float) -> Ray {
-        let mut rng = thread_rng();
-        let rd = self.aperture / 2.0 * random_in_unit_disk(&mut rng);
-        let offset = self.u * rd.x + self.v * rd.y;
-        let time = rng.gen_range(self.exposure.start..self.exposure.end);
+    /// Keep the vertical field of view and stretch the viewport horizontally.
+    pub fn set_aspect_ratio(&mut self, aspect_ratio: Float) {
+        let center = self.lower_left_corner + self.horizontal / 2.0 + self.vertical / 2.0;
+        self.horizontal = self.u * self.vertical.length() * aspect_ratio;
+        self.lower_left_corner = center - self.horizontal / 2.0 - self.vertical / 2.0;
+    }
+
+    /// A point on the lens, uniform over its disk.
+    pub fn sample_lens(&self, rng: &mut dyn RngCore) -> Point3 {
+        let rd = self.aperture / 2.0 * random_in_unit_disk(rng);
+        self.origin + self.u * rd.x + self.v * rd.y
+    }
+
+    fn forward(&self) -> Vec3 {
+        cross(self.vertical, self.horizontal).normalize()
+    }
+
+    /// The (s, t) that `get_ray` takes to send a ray from `lens` through `p`,
+    /// or None when `p` is behind the camera.
+    pub fn raster(&self, lens: Point3, p: Point3) -> Option<(Float, Float)> {
+        let forward = self.forward();
+        let direction = p - lens;
+        let along = dot(direction, forward);
+        if along <= 0.0 {
+            return None;
+        }
+        let q = lens + dot(self.lower_left_corner - lens, forward) / along * direction;
+        let d = q - self.lower_left_corner;
+        Some((
+            dot(d, self.horizontal) / self.horizontal.length2(),
+            dot(d, self.vertical) / self.vertical.length2(),
+        ))
+    }
+
+    /// The density in solid angle of rays leaving the lens in `direction`
+    /// when (s, t) is uniform over the unit square.
+    pub fn direction_density(&self, direction: Vec3) -> Float {
+        let forward = self.forward();
+        let cos = dot(direction.normalize(), forward);
+        if cos <= 0.0 {
+            return 0.0;
+        }
+        let focus_dist = dot(self.origin - self.lower_left_corner, -forward);
+        let area = self.horizontal.length() * self.vertical.length();
+        focus_dist * focus_dist / (area * cos * cos * cos)
+    }
+
+    /// A time uniform over the exposure.
+    pub fn sample_time(&self, rng: &mut dyn RngCore) -> Float {
+        if self.exposure.is_empty() {
+            self.exposure.start
+        } else {
+            rng.gen_range(self.exposure.clone())
+        }
+    }
+
+    pub fn get_ray(&self, rng: &mut dyn RngCore, s: Float, t: Float) -> Ray {
+        let lens = self.sample_lens(rng);
+        let offset = lens - self.origin;
+        let time = self.sample_time(rng);
         Ray::new(
             self.origin + offset,
             self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
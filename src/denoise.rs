@@ -0,0 +1,229 @@
+use crate::aov::{Aov, Layer};
+use crate::film::Film;
+use crate::geom::*;
+use crate::integrator::IntegratorKind;
+use crate::scenes::RenderParams;
+
+// The B3 spline the à-trous filter spreads over 5 taps in each direction.
+const KERNEL: [Float; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// Albedo channels darker than this are not divided out.
+const ALBEDO_FLOOR: Float = 0.01;
+
+// The variance of the luminance of each pixel's mean. Pixels with splats
+// do not know theirs, nor do any when `unknown` is set, and they take the
+// variance of the means around them.
+fn variances(film: &Film, pixels: &[Color], unknown: bool) -> Vec<Float> {
+    let (w, h) = (film.width() as i64, film.height() as i64);
+    let mut variances = Vec::with_capacity(pixels.len());
+    for y in 0..h {
+        for x in 0..w {
+            let p = film.pixel(x as u32, y as u32);
+            if p.splat == BLACK && !unknown {
+                variances.push(luminance(p.mean_variance()));
+                continue;
+            }
+            let (mut sum, mut sum_sq, mut n) = (0.0, 0.0, 0.0);
+            for qy in (y - 1).max(0)..(y + 2).min(h) {
+                for qx in (x - 1).max(0)..(x + 2).min(w) {
+                    let l = luminance(pixels[(qy * w + qx) as usize]);
+                    sum += l;
+                    sum_sq += l * l;
+                    n += 1.0;
+                }
+            }
+            variances.push(((sum_sq - sum * sum / n) / n).max(0.0));
+        }
+    }
+    variances
+}
+
+/// A copy of `film` with its pixels smoothed by an edge avoiding à-trous
+/// wavelet filter, after Dammertz et al. and the variance guided version by
+/// Schied et al.
+///
+/// Each of `denoise_passes` passes averages 5 by 5 pixels spaced twice as far
+/// apart as the last. Neighbors count less the further their luminance is
+/// from the pixel's, measured in `denoise_sigma_color` standard errors, and
+/// the further their normal and albedo are, measured against
+/// `denoise_sigma_normal` and `denoise_sigma_albedo`. The albedo is divided
+/// out while filtering, so that textures stay sharp. Films without the
+/// normal or albedo layers are filtered without them.
+///
+/// Photon mapped films carry no variance per pixel, their pixels use the
+/// variance of the means around them instead.
+///
+/// Pixels keep their sample counts, and layers `params.aovs` did not ask for
+/// are dropped.
+pub fn denoise(film: &Film, params: &RenderParams) -> Film {
+    let (w, h) = (film.width() as i64, film.height() as i64);
+    let pixels = film.to_rgb();
+    let n = pixels.len();
+    let normals = film
+        .layer_rgb(Layer::new(Aov::Normal))
+        .unwrap_or_else(|| vec![ZERO; n]);
+    let albedos: Vec<Color> = film
+        .layer_rgb(Layer::new(Aov::Albedo))
+        .unwrap_or_else(|| vec![WHITE; n])
+        .into_iter()
+        .map(|a| a.map(|v| if v > ALBEDO_FLOOR { v } else { 1.0 }))
+        .collect();
+    // Each pass of photon mapping refines one estimate rather than adding a
+    // sample, so the spread of the passes is not the error of the mean.
+    let unknown = params.integrator == IntegratorKind::PhotonMapping;
+    let mut variance = variances(film, &pixels, unknown);
+    let mut illumination: Vec<Color> = pixels
+        .iter()
+        .zip(&albedos)
+        .zip(&mut variance)
+        .map(|((&c, &a), v)| {
+            *v /= luminance(a) * luminance(a);
+            color(c.x / a.x, c.y / a.y, c.z / a.z)
+        })
+        .collect();
+
+    let squared = |sigma: Float| (sigma * sigma).max(Float::MIN_POSITIVE);
+    let (sigma_normal, sigma_albedo) = (
+        squared(params.denoise_sigma_normal),
+        squared(params.denoise_sigma_albedo),
+    );
+    for pass in 0..params.denoise_passes {
+        let step = 1i64 << pass;
+        let mut filtered = Vec::with_capacity(n);
+        let mut filtered_variance = Vec::with_capacity(n);
+        for y in 0..h {
+            for x in 0..w {
+                let p = (y * w + x) as usize;
+                let l = luminance(illumination[p]);
+                let sigma_l = params.denoise_sigma_color * variance[p].sqrt() + 1e-6;
+                let (mut sum, mut sum_variance, mut weights) = (BLACK, 0.0, 0.0);
+                for (j, ky) in KERNEL.iter().enumerate() {
+                    let qy = y + (j as i64 - 2) * step;
+                    if !(0..h).contains(&qy) {
+                        continue;
+                    }
+                    for (i, kx) in KERNEL.iter().enumerate() {
+                        let qx = x + (i as i64 - 2) * step;
+                        if !(0..w).contains(&qx) {
+                            continue;
+                        }
+                        let q = (qy * w + qx) as usize;
+                        let d_l = (l - luminance(illumination[q])).abs() / sigma_l;
+                        let d_n = (normals[p] - normals[q]).length2() / sigma_normal;
+                        let d_a = (albedos[p] - albedos[q]).length2() / sigma_albedo;
+                        let weight = kx * ky * (-d_l - d_n - d_a).exp();
+                        sum += weight * illumination[q];
+                        sum_variance += weight * weight * variance[q];
+                        weights += weight;
+                    }
+                }
+                filtered.push(sum / weights);
+                filtered_variance.push(sum_variance / (weights * weights));
+            }
+        }
+        illumination = filtered;
+        variance = filtered_variance;
+    }
+
+    let mut denoised = Film::new(film.width(), film.height());
+    for y in 0..film.height() {
+        for x in 0..film.width() {
+            let i = (y * film.width() + x) as usize;
+            let c = illumination[i] * albedos[i];
+            let p = denoised.pixel_mut(x, y);
+            p.count = film.pixel(x, y).count.max(1);
+            p.sum = p.count as Float * c;
+            p.sum_sq = p.count as Float * c * c;
+        }
+    }
+    for layer in film.layers() {
+        if params.aovs.contains(&layer.aov) {
+            let sums = film.layer_sums(layer).unwrap();
+            denoised.layer_sums_mut(layer).copy_from_slice(sums);
+        }
+    }
+    denoised
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    fn params() -> RenderParams {
+        let mut params = RenderParams::new(BLACK, 1.0, 32, 16, 8);
+        params.denoise = true;
+        params
+    }
+
+    // A 32 by 32 film, the left half with albedo `left` and the right half
+    // with `right`, lit by 1 with noise of deviation `noise`.
+    fn halves(left: Color, right: Color, noise: Float) -> Film {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let mut film = Film::new(32, 32);
+        for y in 0..32 {
+            for x in 0..32 {
+                let albedo = if x < 16 { left } else { right };
+                for _ in 0..16 {
+                    let c = albedo * (1.0 + noise * (rng.gen::<Float>() - 0.5) * 12.0f32.sqrt());
+                    film.add_sample(x, y, c);
+                }
+                let i = (y * 32 + x) as usize;
+                film.layer_sums_mut(Layer::new(Aov::Albedo))[i] = 16.0 * albedo;
+                film.layer_sums_mut(Layer::new(Aov::Normal))[i] = 16.0 * color(0.0, 0.0, 1.0);
+            }
+        }
+        film
+    }
+
+    fn error(pixels: &[Color], left: Color, right: Color) -> Float {
+        let sum: Float = pixels
+            .iter()
+            .enumerate()
+            .map(|(i, &c)| (c - if i % 32 < 16 { left } else { right }).length2())
+            .sum();
+        (sum / pixels.len() as Float).sqrt()
+    }
+
+    #[test]
+    fn test_denoise_keeps_flat_images() {
+        let (left, right) = (color(0.2, 0.4, 0.6), color(0.9, 0.1, 0.1));
+        let film = halves(left, right, 0.0);
+        let denoised = denoise(&film, &params());
+        assert!(error(&denoised.to_rgb(), left, right) < 1e-4);
+        assert_eq!(denoised.samples(), film.samples());
+        assert_eq!(denoised.layers().count(), 0);
+    }
+
+    #[test]
+    fn test_denoise_removes_noise_not_edges() {
+        let (left, right) = (color(0.2, 0.4, 0.6), color(0.9, 0.1, 0.1));
+        let film = halves(left, right, 0.5);
+        let before = error(&film.to_rgb(), left, right);
+        let denoised = denoise(&film, &params());
+        let after = error(&denoised.to_rgb(), left, right);
+        assert!(after < 0.25 * before, "{after} {before}");
+
+        // Photon mapping leaves every pixel's spread at zero, the noise is
+        // still measured and removed.
+        let mut photons = film.clone();
+        for y in 0..32 {
+            for x in 0..32 {
+                let p = photons.pixel_mut(x, y);
+                p.sum_sq = p.sum * p.sum / p.count as Float;
+            }
+        }
+        let mut params = params();
+        params.integrator = IntegratorKind::PhotonMapping;
+        let after = error(&denoise(&photons, &params).to_rgb(), left, right);
+        assert!(after < 0.5 * before, "{after} {before}");
+
+        // The columns either side of the albedo edge keep their color.
+        let pixels = denoised.to_rgb();
+        for y in 0..32 {
+            assert!((pixels[y * 32 + 15] - left).length() < 0.1);
+            assert!((pixels[y * 32 + 16] - right).length() < 0.1);
+        }
+    }
+}
@@ -0,0 +1,501 @@
+use crate::aov::{AovSample, Layer};
+use crate::filter::Filter;
+use crate::geom::*;
+use crate::tonemap::{to_srgb8, ToneMap};
+
+/// Running sums of the samples that landed in one pixel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilmPixel {
+    pub sum: Color,
+    pub sum_sq: Color,
+    pub count: u32,
+    /// Radiance splatted onto the pixel by paths started elsewhere, which is
+    /// averaged over all the samples in the film rather than this pixel's.
+    pub splat: Color,
+    /// Samples from around the pixel weighted by the reconstruction filter,
+    /// and the sum of their weights. Both stay 0 with the pixel box filter,
+    /// which only needs `sum` and `count`.
+    pub filtered: Color,
+    pub weight: Float,
+}
+
+impl FilmPixel {
+    pub const EMPTY: Self = FilmPixel {
+        sum: BLACK,
+        sum_sq: BLACK,
+        count: 0,
+        splat: BLACK,
+        filtered: BLACK,
+        weight: 0.0,
+    };
+
+    pub fn add(&mut self, c: Color) {
+        self.sum += c;
+        self.sum_sq += c * c;
+        self.count += 1;
+    }
+
+    pub fn merge(&mut self, other: &FilmPixel) {
+        self.sum += other.sum;
+        self.sum_sq += other.sum_sq;
+        self.count += other.count;
+        self.splat += other.splat;
+        self.filtered += other.filtered;
+        self.weight += other.weight;
+    }
+
+    pub fn mean(&self) -> Color {
+        if self.count == 0 {
+            BLACK
+        } else {
+            self.sum / self.count as Float
+        }
+    }
+
+    /// The filtered mean, or the plain mean if no weight landed here.
+    pub fn reconstruct(&self) -> Color {
+        if self.weight > 0.0 {
+            self.filtered / self.weight
+        } else {
+            self.mean()
+        }
+    }
+
+    /// Unbiased sample variance of each channel.
+    pub fn variance(&self) -> Color {
+        if self.count < 2 {
+            return BLACK;
+        }
+        let n = self.count as Float;
+        let v = (self.sum_sq - self.sum * self.sum / n) / (n - 1.0);
+        color(v.x.max(0.0), v.y.max(0.0), v.z.max(0.0))
+    }
+
+    /// Variance of the pixel's mean, which shrinks as samples are added.
+    pub fn mean_variance(&self) -> Color {
+        if self.count < 2 {
+            return BLACK;
+        }
+        self.variance() / self.count as Float
+    }
+
+    /// Standard error of the mean relative to the mean, the largest over the
+    /// channels. Channels darker than `ERROR_FLOOR` are measured against it so
+    /// that black pixels converge.
+    pub fn relative_error(&self) -> Float {
+        if self.count < 2 {
+            return INFINITY;
+        }
+        let (m, v) = (self.mean(), self.mean_variance());
+        (0..3u8)
+            .map(|i| v[i].sqrt() / m[i].max(ERROR_FLOOR))
+            .fold(0.0, Float::max)
+    }
+}
+
+const ERROR_FLOOR: Float = 0.01;
+
+// Stops of the heatmaps, from the least to the most.
+const HEATMAP: [[Float; 3]; 5] = [
+    [0.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0],
+    [1.0, 0.0, 0.0],
+    [1.0, 1.0, 0.0],
+    [1.0, 1.0, 1.0],
+];
+
+/// The heatmap color of `t` in [0, 1], running from black through blue, red
+/// and yellow to white.
+pub fn heat_color(t: Float) -> Color {
+    let f = t.clamp(0.0, 1.0) * (HEATMAP.len() - 1) as Float;
+    let i = (f as usize).min(HEATMAP.len() - 2);
+    let t = f - i as Float;
+    let ([r0, g0, b0], [r1, g1, b1]) = (HEATMAP[i], HEATMAP[i + 1]);
+    (1.0 - t) * color(r0, g0, b0) + t * color(r1, g1, b1)
+}
+
+/// An accumulation buffer of linear radiance, rows from the top down.
+#[derive(Clone, Debug)]
+pub struct Film {
+    width: u32,
+    height: u32,
+    pixels: Vec<FilmPixel>,
+    // Sums of the AOV samples, sorted by layer. Every sample of a pixel
+    // counts towards its layers too, so they share `FilmPixel::count`.
+    layers: Vec<(Layer, Vec<Color>)>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![FilmPixel::EMPTY; (width * height) as usize],
+            layers: Vec::new(),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[FilmPixel] {
+        &self.pixels
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> &FilmPixel {
+        &self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn pixel_mut(&mut self, x: u32, y: u32) -> &mut FilmPixel {
+        &mut self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn add_sample(&mut self, x: u32, y: u32, c: Color) {
+        self.pixel_mut(x, y).add(c);
+    }
+
+    /// Add a sample at (`px`, `py`) in pixel units from the top left corner
+    /// to the pixels `filter` reaches from there.
+    pub fn add_filtered(&mut self, px: Float, py: Float, c: Color, filter: &Filter) {
+        let r = filter.radius;
+        let range = |p: Float, size: u32| {
+            let start = (p - 0.5 - r).ceil().max(0.0) as u32;
+            let end = ((p - 0.5 + r).floor() + 1.0).clamp(0.0, size as Float) as u32;
+            start..end
+        };
+        for y in range(py, self.height) {
+            for x in range(px, self.width) {
+                let w = filter.weight(x as Float + 0.5 - px, y as Float + 0.5 - py);
+                let p = self.pixel_mut(x, y);
+                p.filtered += w * c;
+                p.weight += w;
+            }
+        }
+    }
+
+    pub fn add_splat(&mut self, x: u32, y: u32, c: Color) {
+        self.pixel_mut(x, y).splat += c;
+    }
+
+    /// Add the AOVs of the last sample added at (`x`, `y`).
+    pub fn add_aovs(&mut self, x: u32, y: u32, sample: &AovSample) {
+        let i = (y * self.width + x) as usize;
+        for &(layer, c) in &sample.values {
+            self.layer_sums_mut(layer)[i] += c;
+        }
+    }
+
+    /// The layers the film has AOV samples for, in order.
+    pub fn layers(&self) -> impl Iterator<Item = Layer> + '_ {
+        self.layers.iter().map(|(layer, _)| *layer)
+    }
+
+    pub fn layer_sums(&self, layer: Layer) -> Option<&[Color]> {
+        let i = self.layers.binary_search_by_key(&layer, |(l, _)| *l).ok()?;
+        Some(&self.layers[i].1)
+    }
+
+    /// The sums of `layer`, which is added if the film does not have it yet.
+    pub fn layer_sums_mut(&mut self, layer: Layer) -> &mut [Color] {
+        let i = match self.layers.binary_search_by_key(&layer, |(l, _)| *l) {
+            Ok(i) => i,
+            Err(i) => {
+                let sums = vec![BLACK; self.pixels.len()];
+                self.layers.insert(i, (layer, sums));
+                i
+            }
+        };
+        &mut self.layers[i].1
+    }
+
+    /// The mean of every pixel of `layer`.
+    pub fn layer_rgb(&self, layer: Layer) -> Option<Vec<Color>> {
+        let sums = self.layer_sums(layer)?;
+        let means = sums
+            .iter()
+            .zip(&self.pixels)
+            .map(|(&sum, p)| sum / p.count.max(1) as Float)
+            .collect();
+        Some(means)
+    }
+
+    /// Total number of samples in the film.
+    pub fn samples(&self) -> u64 {
+        self.pixels.iter().map(|p| p.count as u64).sum()
+    }
+
+    /// Add the samples of `other`, a film of the same size.
+    pub fn merge(&mut self, other: &Film) {
+        assert_eq!((self.width, self.height), (other.width, other.height));
+        for (p, q) in self.pixels.iter_mut().zip(&other.pixels) {
+            p.merge(q);
+        }
+        for (layer, sums) in &other.layers {
+            for (p, q) in self.layer_sums_mut(*layer).iter_mut().zip(sums) {
+                *p += *q;
+            }
+        }
+    }
+
+    /// Add the samples of `other` with its top left corner at (`x0`, `y0`),
+    /// which may be outside this film. Pixels falling outside are dropped.
+    pub fn merge_at(&mut self, other: &Film, x0: i32, y0: i32) {
+        // The pixels of `other` that land on this film.
+        let span = |offset: i32, size: u32, other_size: u32| {
+            let start = (-offset).max(0) as u32;
+            let end = (size as i64 - offset as i64).clamp(0, other_size as i64) as u32;
+            start..end.max(start)
+        };
+        let (xs, ys) = (
+            span(x0, self.width, other.width),
+            span(y0, self.height, other.height),
+        );
+        let to = |x: u32, y: u32, width: u32| {
+            ((y as i32 + y0) as u32 * width + (x as i32 + x0) as u32) as usize
+        };
+        let width = self.width;
+        for y in ys.clone() {
+            for x in xs.clone() {
+                self.pixels[to(x, y, width)].merge(other.pixel(x, y));
+            }
+        }
+        for (layer, sums) in &other.layers {
+            let sums_to = self.layer_sums_mut(*layer);
+            for y in ys.clone() {
+                for x in xs.clone() {
+                    sums_to[to(x, y, width)] += sums[(y * other.width + x) as usize];
+                }
+            }
+        }
+    }
+
+    /// The `width` by `height` region with its top left corner at (`x0`, `y0`).
+    /// Splats are rescaled to the samples in the region, so they keep their
+    /// brightness.
+    pub fn crop(&self, x0: u32, y0: u32, width: u32, height: u32) -> Film {
+        assert!(x0 + width <= self.width && y0 + height <= self.height);
+        let mut film = Film::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                *film.pixel_mut(x, y) = *self.pixel(x0 + x, y0 + y);
+            }
+        }
+        for (layer, sums) in &self.layers {
+            let to = film.layer_sums_mut(*layer);
+            for y in 0..height {
+                for x in 0..width {
+                    to[(y * width + x) as usize] = sums[((y0 + y) * self.width + x0 + x) as usize];
+                }
+            }
+        }
+        if self.samples() > 0 {
+            let scale = film.samples() as Float / self.samples() as Float;
+            for p in &mut film.pixels {
+                p.splat *= scale;
+            }
+        }
+        film
+    }
+
+    /// Resample to a new size. Every new pixel pools the samples of the
+    /// pixels it covers, or copies the nearest one when enlarging. Splats are
+    /// averaged and rescaled to the new number of samples.
+    pub fn resize(&self, width: u32, height: u32) -> Film {
+        let mut film = Film::new(width, height);
+        if self.width == 0 || self.height == 0 {
+            return film;
+        }
+        let span = |i: u32, from: u32, to: u32| {
+            let start = (i as u64 * from as u64 / to as u64) as u32;
+            let end = (((i + 1) as u64 * from as u64 / to as u64) as u32).max(start + 1);
+            start..end.min(from)
+        };
+        for y in 0..height {
+            for x in 0..width {
+                let mut p = FilmPixel::EMPTY;
+                let (ys, xs) = (span(y, self.height, height), span(x, self.width, width));
+                let pooled = ys.len() * xs.len();
+                for sy in ys.clone() {
+                    for sx in xs.clone() {
+                        p.merge(self.pixel(sx, sy));
+                    }
+                }
+                p.splat /= pooled as Float;
+                *film.pixel_mut(x, y) = p;
+                for (layer, sums) in &self.layers {
+                    let mut sum = BLACK;
+                    for sy in ys.clone() {
+                        for sx in xs.clone() {
+                            sum += sums[(sy * self.width + sx) as usize];
+                        }
+                    }
+                    film.layer_sums_mut(*layer)[(y * width + x) as usize] = sum;
+                }
+            }
+        }
+        if self.samples() > 0 {
+            let scale = film.samples() as Float / self.samples() as Float;
+            for p in &mut film.pixels {
+                p.splat *= scale;
+            }
+        }
+        film
+    }
+
+    /// The reconstructed radiance of every pixel, including splats.
+    pub fn to_rgb(&self) -> Vec<Color> {
+        let samples = self.samples().max(1) as Float;
+        self.pixels
+            .iter()
+            .map(|p| p.reconstruct() + p.splat / samples)
+            .collect()
+    }
+
+    pub fn to_srgb8(&self, tone_map: ToneMap, exposure: Float) -> Vec<u8> {
+        to_srgb8(&self.to_rgb(), tone_map, exposure)
+    }
+
+    /// The sample count of every pixel as 8 bit RGB, running from black
+    /// through blue, red and yellow to white at the largest count.
+    pub fn sample_heatmap(&self) -> Vec<u8> {
+        let most = self
+            .pixels
+            .iter()
+            .map(|p| p.count)
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let colors: Vec<Color> = self
+            .pixels
+            .iter()
+            .map(|p| heat_color(p.count as Float / most as Float))
+            .collect();
+        to_srgb8(&colors, ToneMap::Clamp, 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aov::Aov;
+    use crate::filter::FilterKind;
+
+    #[test]
+    fn test_pixel_statistics() {
+        let mut p = FilmPixel::EMPTY;
+        for v in [1.0, 2.0, 3.0, 6.0] {
+            p.add(color(v, 2.0 * v, 0.0));
+        }
+        assert_eq!(p.count, 4);
+        assert_eq!(p.mean(), color(3.0, 6.0, 0.0));
+        assert!((p.variance().x - 14.0 / 3.0).abs() < 1e-5);
+        assert!((p.variance().y - 56.0 / 3.0).abs() < 1e-4);
+        assert!((p.mean_variance().x - 14.0 / 12.0).abs() < 1e-5);
+        assert!((p.relative_error() - (56.0 / 12.0 as Float).sqrt() / 6.0).abs() < 1e-5);
+
+        let mut flat = FilmPixel::EMPTY;
+        assert_eq!(flat.relative_error(), INFINITY);
+        flat.add(BLACK);
+        flat.add(BLACK);
+        assert_eq!(flat.relative_error(), 0.0);
+    }
+
+    #[test]
+    fn test_merge_crop_resize() {
+        let mut a = Film::new(4, 2);
+        let mut b = Film::new(2, 2);
+        for y in 0..2 {
+            for x in 0..4 {
+                a.add_sample(x, y, color(x as Float, y as Float, 1.0));
+            }
+            for x in 0..2 {
+                b.add_sample(x, y, color(10.0, 10.0, 1.0));
+            }
+        }
+        a.merge_at(&b, 3, 1);
+        assert_eq!(a.pixel(3, 1).count, 2);
+        assert_eq!(a.pixel(3, 1).mean(), color(6.5, 5.5, 1.0));
+        assert_eq!(a.samples(), 9);
+
+        let c = a.crop(1, 0, 2, 2);
+        assert_eq!(c.pixel(0, 1), a.pixel(1, 1));
+
+        let half = a.resize(2, 1);
+        assert_eq!(half.pixel(0, 0).count, 4);
+        assert_eq!(half.pixel(0, 0).mean(), color(0.5, 0.5, 1.0));
+        let double = a.resize(8, 4);
+        assert_eq!(double.pixel(7, 3), a.pixel(3, 1));
+
+        let mut d = a.clone();
+        d.merge(&a);
+        assert_eq!(d.samples(), 18);
+        assert_eq!(d.to_rgb(), a.to_rgb());
+
+        // Splats are spread over all the samples in the film.
+        a.add_splat(0, 0, color(9.0, 0.0, 0.0));
+        assert_eq!(a.to_rgb()[0], color(1.0, 0.0, 1.0));
+        // The four pixels pooled had 1, 1, 0 and 1 in red.
+        assert_eq!(a.resize(2, 1).to_rgb()[0].x, 0.75);
+        // And a crop keeps them as bright.
+        let c = a.crop(0, 0, 2, 2);
+        assert!((c.to_rgb()[0] - a.to_rgb()[0]).length() < 1e-5);
+    }
+
+    #[test]
+    fn test_filtered_samples() {
+        let tent = Filter::new(FilterKind::Tent, 1.0);
+        let mut a = Film::new(3, 3);
+        a.add_sample(1, 1, WHITE);
+        a.add_filtered(1.5, 1.5, WHITE, &tent);
+        // Only the center reaches, the others are a pixel away.
+        assert_eq!(a.pixel(1, 1).weight, 1.0);
+        assert_eq!(a.pixel(0, 1).weight, 0.0);
+        a.add_sample(0, 0, color(3.0, 3.0, 3.0));
+        a.add_filtered(0.5, 1.0, color(3.0, 3.0, 3.0), &tent);
+        // It lands between two pixels of the first column.
+        assert_eq!(a.pixel(0, 1).weight, 0.5);
+        assert_eq!(a.pixel(1, 1).weight, 1.0);
+        assert_eq!(a.pixel(1, 0).weight, 0.0);
+        assert_eq!(a.to_rgb()[3], color(3.0, 3.0, 3.0));
+        assert_eq!(a.to_rgb()[4], WHITE);
+        // Pixels outside the film are dropped when merging.
+        let mut b = Film::new(2, 2);
+        b.merge_at(&a, -1, -1);
+        assert_eq!(b.pixel(0, 0), a.pixel(1, 1));
+        assert_eq!(b.samples(), 1);
+    }
+
+    #[test]
+    fn test_layers() {
+        let depth = Layer::new(Aov::Depth);
+        let mut sample = AovSample::new(&[Aov::Depth]);
+        let mut a = Film::new(2, 2);
+        for y in 0..2 {
+            for x in 0..2 {
+                let d = (y * 2 + x) as Float;
+                sample.clear();
+                sample.add(depth, color(d, d, d));
+                a.add_sample(x, y, BLACK);
+                a.add_aovs(x, y, &sample);
+            }
+        }
+        assert_eq!(a.layers().collect::<Vec<_>>(), vec![depth]);
+        let mut b = Film::new(2, 2);
+        b.add_sample(1, 1, BLACK);
+        b.add_aovs(1, 1, &sample);
+        a.merge(&b);
+        assert_eq!(a.layer_rgb(depth).unwrap()[3].x, 3.0);
+        let c = a.crop(1, 0, 1, 2);
+        assert_eq!(c.layer_rgb(depth).unwrap()[1].x, 3.0);
+        assert_eq!(a.resize(1, 1).layer_rgb(depth).unwrap()[0].x, 9.0 / 5.0);
+        let mut d = Film::new(3, 3);
+        d.merge_at(&a, 2, 2);
+        assert_eq!(d.layer_sums(depth).unwrap()[8].x, 0.0);
+        assert!(d.layer_rgb(Layer::light(0)).is_none());
+    }
+}
@@ -0,0 +1,149 @@
+use crate::geom::*;
+
+/// The reconstruction filters samples are weighted with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FilterKind {
+    /// Every sample counts in full towards the pixels it is in.
+    #[default]
+    Box,
+    /// Weight falling linearly to 0 at the radius.
+    Tent,
+    /// A Gaussian with deviation `radius / 3`, shifted to reach 0 at the
+    /// radius.
+    Gaussian,
+    /// The Mitchell-Netravali cubic with B = C = 1/3.
+    Mitchell,
+    /// A sinc windowed by a sinc as wide as the radius.
+    Lanczos,
+}
+
+impl FilterKind {
+    /// The radius in pixels when none is given.
+    pub fn default_radius(self) -> Float {
+        match self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+            FilterKind::Lanczos => 2.0,
+        }
+    }
+}
+
+/// A separable reconstruction filter. Every sample is splatted into the
+/// pixels whose centers are within `radius` of it in both directions,
+/// weighted by the filter at their offset, and pixels are the weighted mean
+/// of their samples.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Filter {
+    pub kind: FilterKind,
+    pub radius: Float,
+}
+
+impl Filter {
+    /// A filter of `kind`, with its default radius if `radius` is 0.
+    pub fn new(kind: FilterKind, radius: Float) -> Self {
+        let radius = if radius > 0.0 {
+            radius
+        } else {
+            kind.default_radius()
+        };
+        Self { kind, radius }
+    }
+
+    /// Whether every sample lands in its own pixel only, with weight 1.
+    pub fn is_pixel_box(&self) -> bool {
+        self.kind == FilterKind::Box && self.radius == 0.5
+    }
+
+    /// The pixels a sample can reach beyond its own on every side.
+    pub fn margin(&self) -> u32 {
+        (self.radius - 0.5).max(0.0).ceil() as u32
+    }
+
+    /// The weight of a sample `dx`, `dy` pixels from a pixel's center.
+    pub fn weight(&self, dx: Float, dy: Float) -> Float {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    fn weight_1d(&self, x: Float) -> Float {
+        let (x, r) = (x.abs(), self.radius);
+        if x > r {
+            return 0.0;
+        }
+        match self.kind {
+            FilterKind::Box => 1.0,
+            FilterKind::Tent => 1.0 - x / r,
+            FilterKind::Gaussian => {
+                let sigma = r / 3.0;
+                let g = |x: Float| (-x * x / (2.0 * sigma * sigma)).exp();
+                g(x) - g(r)
+            }
+            FilterKind::Mitchell => mitchell(2.0 * x / r, 1.0 / 3.0, 1.0 / 3.0),
+            FilterKind::Lanczos => sinc(x) * sinc(x / r),
+        }
+    }
+}
+
+fn sinc(x: Float) -> Float {
+    if x < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// The Mitchell-Netravali cubic at |x| in [0, 2].
+fn mitchell(x: Float, b: Float, c: Float) -> Float {
+    let (x2, x3) = (x * x, x * x * x);
+    let w = if x < 1.0 {
+        (12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b)
+    } else {
+        (-b - 6.0 * c) * x3
+            + (6.0 * b + 30.0 * c) * x2
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c)
+    };
+    w / 6.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_weights() {
+        let kinds = [
+            FilterKind::Box,
+            FilterKind::Tent,
+            FilterKind::Gaussian,
+            FilterKind::Mitchell,
+            FilterKind::Lanczos,
+        ];
+        for kind in kinds {
+            let filter = Filter::new(kind, 0.0);
+            let r = filter.radius;
+            assert!(filter.weight(0.0, 0.0) > 0.0);
+            assert_eq!(filter.weight(r + 0.01, 0.0), 0.0);
+            assert_eq!(filter.weight(0.3, -0.7), filter.weight(-0.3, 0.7));
+            if kind == FilterKind::Box {
+                continue;
+            }
+            // The others fall to 0 at the radius, and their weights at whole
+            // pixel steps from any position sum to about the same.
+            assert!(filter.weight(r * 0.999, 0.0).abs() < 0.05, "{kind:?}");
+            let sum = |offset: Float| -> Float {
+                (-4..=4)
+                    .map(|i| filter.weight_1d(i as Float + offset))
+                    .sum()
+            };
+            assert!((sum(0.0) - sum(0.5)).abs() < 0.1 * sum(0.0), "{kind:?}");
+        }
+        // Mitchell and Lanczos have negative lobes.
+        assert!(Filter::new(FilterKind::Mitchell, 0.0).weight(1.5, 0.0) < 0.0);
+        assert!(Filter::new(FilterKind::Lanczos, 0.0).weight(1.5, 0.0) < 0.0);
+        assert!(Filter::new(FilterKind::Box, 0.0).is_pixel_box());
+        assert_eq!(Filter::new(FilterKind::Mitchell, 0.0).margin(), 2);
+        assert_eq!(Filter::new(FilterKind::Tent, 1.0).margin(), 1);
+    }
+}
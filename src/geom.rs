@@ -4,7 +4,7 @@ use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssi
 
 pub type Float = f32;
 
-pub const INFINITY: Float = std::f32::MAX;
+pub const INFINITY: Float = Float::MAX;
 pub const PI: Float = std::f32::consts::PI;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -56,6 +56,11 @@ pub fn color(r: Float, g: Float, b: Float) -> Color {
     point3(r, g, b)
 }
 
+/// The luminance of a linear color with Rec. 709 primaries.
+pub fn luminance(c: Color) -> Float {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
 impl Vec3 {
     pub fn new(x: Float, y: Float, z: Float) -> Self {
         Self { x, y, z }
@@ -218,39 +223,44 @@ impl Distribution<Vec3> for Standard {
     }
 }
 
-pub fn rand_in_cube<R: Rng>(rng: &mut R) -> Vec3 {
+pub fn rand_in_cube<R: Rng + ?Sized>(rng: &mut R) -> Vec3 {
     let x: Float = rng.gen_range(-1.0..1.0);
     let y: Float = rng.gen_range(-1.0..1.0);
     let z: Float = rng.gen_range(-1.0..1.0);
     Vec3 { x, y, z }
 }
 
-pub fn random_in_unit_sphere<R: Rng>(rng: &mut R) -> Vec3 {
-    let mut p: Point3;
-    loop {
-        p = rand_in_cube(rng);
-        if p.length2() >= 1.0 {
-            continue;
-        }
-        break;
-    }
-    p
+// The warps below use a fixed number of random values, so that each value
+// comes from the same sampler dimension on every path.
+
+pub fn random_in_unit_sphere<R: Rng + ?Sized>(rng: &mut R) -> Vec3 {
+    let r: Float = rng.gen();
+    random_unit_vector(rng) * r.cbrt()
 }
 
-pub fn random_unit_vector<R: Rng>(rng: &mut R) -> Vec3 {
-    random_in_unit_sphere(rng).normalize()
+pub fn random_unit_vector<R: Rng + ?Sized>(rng: &mut R) -> Vec3 {
+    let z = 1.0 - 2.0 * rng.gen::<Float>();
+    let phi = 2.0 * PI * rng.gen::<Float>();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    vec3(r * phi.cos(), r * phi.sin(), z)
 }
 
-pub fn random_in_unit_disk<R: Rng>(rng: &mut R) -> Vec3 {
-    loop {
-        let p = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 0.0);
-        if p.length() < 1.0 {
-            return p;
-        }
+/// Shirley and Chiu's concentric map from the square to the disk.
+pub fn random_in_unit_disk<R: Rng + ?Sized>(rng: &mut R) -> Vec3 {
+    let a = 2.0 * rng.gen::<Float>() - 1.0;
+    let b = 2.0 * rng.gen::<Float>() - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return ZERO;
     }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+    vec3(r * theta.cos(), r * theta.sin(), 0.0)
 }
 
-pub fn random_cosine_direction<R: Rng>(rng: &mut R) -> Vec3 {
+pub fn random_cosine_direction<R: Rng + ?Sized>(rng: &mut R) -> Vec3 {
     let r1: f32 = rng.gen();
     let r2: f32 = rng.gen();
     let z = (1.0 - r2).sqrt();
@@ -262,14 +272,14 @@ pub fn random_cosine_direction<R: Rng>(rng: &mut R) -> Vec3 {
     vec3(x, y, z)
 }
 
-pub fn rand_color<R: Rng>(rng: &mut R, range: std::ops::Range<Float>) -> Color {
+pub fn rand_color<R: Rng + ?Sized>(rng: &mut R, range: std::ops::Range<Float>) -> Color {
     let x: Float = rng.gen_range(range.clone());
     let y: Float = rng.gen_range(range.clone());
     let z: Float = rng.gen_range(range);
     Color { x, y, z }
 }
 
-pub fn rand_point<R: Rng>(rng: &mut R, range: std::ops::Range<Float>) -> Color {
+pub fn rand_point<R: Rng + ?Sized>(rng: &mut R, range: std::ops::Range<Float>) -> Color {
     let x: Float = rng.gen_range(range.clone());
     let y: Float = rng.gen_range(range.clone());
     let z: Float = rng.gen_range(range);
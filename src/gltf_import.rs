@@ -0,0 +1,498 @@
+use crate::aabb::*;
+use crate::bvh::LinearBvh;
+use crate::camera::Camera;
+use crate::geom::*;
+use crate::material::*;
+use crate::object::*;
+use crate::scenes::{Environment, RenderParams};
+use crate::sphere::Sphere;
+use crate::texture::ImageTexture;
+use crate::triangle::*;
+use base64::Engine;
+use gltf::khr_lights_punctual::Kind;
+use gltf::mesh::Mode;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum GltfError {
+    Gltf(gltf::Error),
+    Io(PathBuf, std::io::Error),
+    Image(String, image::ImageError),
+    Uri(String),
+    MissingData(String),
+}
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GltfError::Gltf(e) => write!(f, "invalid glTF: {}", e),
+            GltfError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            GltfError::Image(name, e) => write!(f, "cannot decode image {}: {}", name, e),
+            GltfError::Uri(uri) => write!(f, "unsupported uri '{}'", uri),
+            GltfError::MissingData(what) => write!(f, "missing data: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for GltfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GltfError::Gltf(e) => Some(e),
+            GltfError::Io(_, e) => Some(e),
+            GltfError::Image(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Column major, `m[column][row]`, as glTF stores them.
+type Mat4 = [[Float; 4]; 4];
+
+const IDENTITY: Mat4 = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+fn mat_mul(a: &Mat4, b: &Mat4) -> Mat4 {
+    let mut m = [[0.0; 4]; 4];
+    for (c, column) in m.iter_mut().enumerate() {
+        for (r, x) in column.iter_mut().enumerate() {
+            *x = (0..4).map(|k| a[k][r] * b[c][k]).sum();
+        }
+    }
+    m
+}
+
+fn transform_point(m: &Mat4, p: Point3) -> Point3 {
+    point3(
+        m[0][0] * p.x + m[1][0] * p.y + m[2][0] * p.z + m[3][0],
+        m[0][1] * p.x + m[1][1] * p.y + m[2][1] * p.z + m[3][1],
+        m[0][2] * p.x + m[1][2] * p.y + m[2][2] * p.z + m[3][2],
+    )
+}
+
+fn transform_vector(m: &Mat4, v: Vec3) -> Vec3 {
+    vec3(
+        m[0][0] * v.x + m[1][0] * v.y + m[2][0] * v.z,
+        m[0][1] * v.x + m[1][1] * v.y + m[2][1] * v.z,
+        m[0][2] * v.x + m[1][2] * v.y + m[2][2] * v.z,
+    )
+}
+
+// The columns of the upper 3x3 block.
+fn columns(m: &Mat4) -> [Vec3; 3] {
+    [0, 1, 2].map(|c| vec3(m[c][0], m[c][1], m[c][2]))
+}
+
+fn determinant(m: &Mat4) -> Float {
+    let [a, b, c] = columns(m);
+    dot(a, cross(b, c))
+}
+
+// Normals transform with the inverse transpose, which is the cofactor
+// matrix up to a scale that the normalization removes.
+fn transform_normal(m: &Mat4, n: Vec3) -> Vec3 {
+    let [a, b, c] = columns(m);
+    let t = cross(b, c) * n.x + cross(c, a) * n.y + cross(a, b) * n.z;
+    let t = if determinant(m) < 0.0 { -t } else { t };
+    if t.near_zero() {
+        t
+    } else {
+        t.normalize()
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Data uris are decoded in place, anything else is a path relative to the
+// glTF file.
+fn read_uri(base_dir: &Path, uri: &str) -> Result<Vec<u8>, GltfError> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        let (_, encoded) = data
+            .split_once(";base64,")
+            .ok_or_else(|| GltfError::Uri(uri.to_string()))?;
+        return base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|_| GltfError::Uri(uri.to_string()));
+    }
+    if uri.contains("://") {
+        return Err(GltfError::Uri(uri.to_string()));
+    }
+    let path = base_dir.join(percent_decode(uri));
+    std::fs::read(&path).map_err(|e| GltfError::Io(path, e))
+}
+
+struct Loader<'a> {
+    buffers: Vec<Vec<u8>>,
+    base_dir: PathBuf,
+    textures: HashMap<usize, Arc<ImageTexture>>,
+    materials: HashMap<Option<usize>, (Arc<dyn Material>, bool)>,
+    objects: Objects,
+    lights: Objects,
+    punctual: Vec<(gltf::khr_lights_punctual::Light<'a>, Mat4)>,
+    camera: Option<(Camera, Float)>,
+    bbox: Aabb,
+}
+
+impl<'a> Loader<'a> {
+    fn texture(&mut self, texture: gltf::Texture) -> Result<Arc<ImageTexture>, GltfError> {
+        let image = texture.source();
+        if let Some(t) = self.textures.get(&image.index()) {
+            return Ok(t.clone());
+        }
+        let name = image
+            .name()
+            .map(String::from)
+            .unwrap_or_else(|| format!("#{}", image.index()));
+        let bytes = match image.source() {
+            gltf::image::Source::View { view, .. } => {
+                let buffer = &self.buffers[view.buffer().index()];
+                buffer[view.offset()..view.offset() + view.length()].to_vec()
+            }
+            gltf::image::Source::Uri { uri, .. } => read_uri(&self.base_dir, uri)?,
+        };
+        let img = image::load_from_memory(&bytes).map_err(|e| GltfError::Image(name, e))?;
+        let t = Arc::new(ImageTexture::from_image(&img));
+        self.textures.insert(image.index(), t.clone());
+        Ok(t)
+    }
+
+    // Returns the material and whether it emits light.
+    fn material(
+        &mut self,
+        material: gltf::Material,
+    ) -> Result<(Arc<dyn Material>, bool), GltfError> {
+        if let Some(m) = self.materials.get(&material.index()) {
+            return Ok(m.clone());
+        }
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, _] = pbr.base_color_factor();
+        let base = color(r, g, b);
+        let strength = material.emissive_strength().unwrap_or(1.0);
+        let [er, eg, eb] = material.emissive_factor();
+        let emit = color(er, eg, eb) * strength;
+        let transmission = material
+            .transmission()
+            .map_or(0.0, |t| t.transmission_factor());
+        let m: (Arc<dyn Material>, bool) = if emit.length2() > 0.0 {
+            (Arc::new(DiffuseLight::new(emit)), true)
+        } else if transmission > 0.5 {
+            (
+                Arc::new(Dielectric::new(material.ior().unwrap_or(1.5))),
+                false,
+            )
+        } else if pbr.metallic_factor() >= 0.5 {
+            (Arc::new(Metal::new(base, pbr.roughness_factor())), false)
+        } else if let Some(info) = pbr.base_color_texture() {
+            let texture = self.texture(info.texture())?;
+            (Arc::new(Lambertian::new(texture)), false)
+        } else {
+            (Arc::new(Lambertian::solid_color(base)), false)
+        };
+        self.materials.insert(material.index(), m.clone());
+        Ok(m)
+    }
+
+    fn primitive(&mut self, primitive: gltf::Primitive, m: &Mat4) -> Result<(), GltfError> {
+        let buffers = &self.buffers;
+        let reader = primitive.reader(|b| buffers.get(b.index()).map(|v| v.as_slice()));
+        let positions: Vec<Point3> = reader
+            .read_positions()
+            .ok_or_else(|| GltfError::MissingData("primitive without positions".to_string()))?
+            .map(|p| transform_point(m, point3(p[0], p[1], p[2])))
+            .collect();
+        let normals: Vec<Vec3> = reader
+            .read_normals()
+            .map(|ns| {
+                ns.map(|n| transform_normal(m, vec3(n[0], n[1], n[2])))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let uvs: Vec<(Float, Float)> = reader
+            .read_tex_coords(0)
+            .map(|uvs| uvs.into_f32().map(|[u, v]| (u, 1.0 - v)).collect())
+            .unwrap_or_default();
+        let vertices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+        if let Some(&i) = vertices.iter().find(|&&i| i as usize >= positions.len()) {
+            return Err(GltfError::MissingData(format!("vertex {} out of range", i)));
+        }
+        let mut indices: Vec<[u32; 3]> = match primitive.mode() {
+            Mode::Triangles => vertices
+                .chunks_exact(3)
+                .map(|t| [t[0], t[1], t[2]])
+                .collect(),
+            Mode::TriangleStrip => (0..vertices.len().saturating_sub(2))
+                .map(|i| {
+                    if i % 2 == 0 {
+                        [vertices[i], vertices[i + 1], vertices[i + 2]]
+                    } else {
+                        [vertices[i + 1], vertices[i], vertices[i + 2]]
+                    }
+                })
+                .collect(),
+            Mode::TriangleFan => (1..vertices.len().saturating_sub(1))
+                .map(|i| [vertices[0], vertices[i], vertices[i + 1]])
+                .collect(),
+            // Points and lines have no area.
+            _ => return Ok(()),
+        };
+        // A mirroring transform flips the winding order.
+        if determinant(m) < 0.0 {
+            for t in indices.iter_mut() {
+                t.swap(1, 2);
+            }
+        }
+        if indices.is_empty() {
+            return Ok(());
+        }
+        let (material, emissive) = self.material(primitive.material())?;
+        let mesh = Arc::new(TriangleMesh::new(
+            Arc::new(MeshData::new(positions, normals, uvs, indices)),
+            material,
+        ));
+        if let Some(b) = mesh.bounding_box(&(0.0..1.0)) {
+            self.bbox = surrounding_box(self.bbox, b);
+        }
+        if emissive {
+            self.lights.add(mesh.clone());
+        }
+        self.objects.add(mesh);
+        Ok(())
+    }
+
+    fn node(&mut self, node: gltf::Node<'a>, parent: &Mat4) -> Result<(), GltfError> {
+        let m = mat_mul(parent, &node.transform().matrix());
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                self.primitive(primitive, &m)?;
+            }
+        }
+        if let (Some(camera), None) = (node.camera(), &self.camera) {
+            if let gltf::camera::Projection::Perspective(p) = camera.projection() {
+                let origin = transform_point(&m, ZERO);
+                let forward = transform_vector(&m, vec3(0.0, 0.0, -1.0));
+                let vup = transform_vector(&m, vec3(0.0, 1.0, 0.0));
+                let aspect_ratio = p.aspect_ratio().unwrap_or(16.0 / 9.0);
+                let camera = Camera::new(
+                    origin,
+                    origin + forward,
+                    vup,
+                    p.yfov().to_degrees(),
+                    aspect_ratio,
+                    0.0,
+                    1.0,
+                    0.0..1.0,
+                );
+                self.camera = Some((camera, aspect_ratio));
+            }
+        }
+        if let Some(light) = node.light() {
+            self.punctual.push((light, m));
+        }
+        for child in node.children() {
+            self.node(child, &m)?;
+        }
+        Ok(())
+    }
+
+    // Punctual lights become small emissive spheres with the same intensity,
+    // directional lights a distant sphere giving the same illuminance.
+    fn punctual_lights(&mut self) {
+        let (center, radius) = if self.objects.objects.is_empty() {
+            (ZERO, 1.0)
+        } else {
+            let b = self.bbox;
+            (
+                0.5 * (b.box_min + b.box_max),
+                0.5 * dist(b.box_min, b.box_max),
+            )
+        };
+        for (light, m) in std::mem::take(&mut self.punctual) {
+            let [r, g, b] = light.color();
+            let c = color(r, g, b) * light.intensity();
+            let (position, size, radiance) = match light.kind() {
+                Kind::Directional => {
+                    let d = 10.0 * radius;
+                    let size = 0.05 * d;
+                    let direction = transform_vector(&m, vec3(0.0, 0.0, -1.0)).normalize();
+                    (center - d * direction, size, c * d * d / (PI * size * size))
+                }
+                Kind::Point | Kind::Spot { .. } => {
+                    let size = 0.01 * radius;
+                    (transform_point(&m, ZERO), size, c / (PI * size * size))
+                }
+            };
+            let sphere = Arc::new(Sphere::new(
+                position,
+                size,
+                Arc::new(DiffuseLight::new(radiance)),
+            ));
+            self.objects.add(sphere.clone());
+            self.lights.add(sphere);
+        }
+    }
+
+    fn default_camera(&self) -> (Camera, Float) {
+        let b = self.bbox;
+        let (center, radius) = if self.objects.objects.is_empty() {
+            (ZERO, 1.0)
+        } else {
+            (
+                0.5 * (b.box_min + b.box_max),
+                0.5 * dist(b.box_min, b.box_max),
+            )
+        };
+        let aspect_ratio = 16.0 / 9.0;
+        let origin = center + vec3(0.0, 0.0, 3.0 * radius);
+        let camera = Camera::basic(origin, center, 40.0, aspect_ratio, 0.0, 1.0);
+        (camera, aspect_ratio)
+    }
+}
+
+/// Load a .gltf or .glb file as a complete environment. The first perspective
+/// camera in the scene is used, or one looking at the whole scene if there is none.
+pub fn load_gltf<P: AsRef<Path>>(path: P) -> Result<Environment, GltfError> {
+    let path = path.as_ref();
+    let gltf::Gltf { document, blob } = gltf::Gltf::open(path).map_err(GltfError::Gltf)?;
+    let base_dir = path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .to_path_buf();
+    let mut buffers = Vec::new();
+    for buffer in document.buffers() {
+        let data = match buffer.source() {
+            gltf::buffer::Source::Bin => blob
+                .clone()
+                .ok_or_else(|| GltfError::MissingData("binary chunk".to_string()))?,
+            gltf::buffer::Source::Uri(uri) => read_uri(&base_dir, uri)?,
+        };
+        if data.len() < buffer.length() {
+            return Err(GltfError::MissingData(format!("buffer {}", buffer.index())));
+        }
+        buffers.push(data);
+    }
+
+    let mut loader = Loader {
+        buffers,
+        base_dir,
+        textures: HashMap::new(),
+        materials: HashMap::new(),
+        objects: Objects::new(Vec::new()),
+        lights: Objects::new(Vec::new()),
+        punctual: Vec::new(),
+        camera: None,
+        bbox: Aabb::EMPTY,
+    };
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| GltfError::MissingData("scene".to_string()))?;
+    for node in scene.nodes() {
+        loader.node(node, &IDENTITY)?;
+    }
+    loader.punctual_lights();
+    let (camera, aspect_ratio) = loader
+        .camera
+        .clone()
+        .unwrap_or_else(|| loader.default_camera());
+
+    let background = if loader.lights.objects.is_empty() {
+        color(0.7, 0.7, 0.7)
+    } else {
+        BLACK
+    };
+    let params = RenderParams::new(background, aspect_ratio, 800, 64, 50);
+    let lights: Arc<dyn Object> = if loader.lights.objects.is_empty() {
+        Arc::new(EmptyObject {})
+    } else {
+        Arc::new(loader.lights)
+    };
+    let n = loader.objects.objects.len();
+    let scene: Box<dyn Object> = if n == 0 {
+        Box::new(loader.objects)
+    } else {
+        Box::new(LinearBvh::new(&mut loader.objects, 0, n, 0.0..1.0))
+    };
+    Ok(Environment::new(scene, camera, lights, params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    // A red triangle at z = -3, a camera at z = 1 and a point light, all in one
+    // file with the vertex data in a data uri.
+    const TRIANGLE: &str = r#"{
+        "asset": {"version": "2.0"},
+        "scene": 0,
+        "scenes": [{"nodes": [0, 1, 2]}],
+        "nodes": [
+            {"mesh": 0, "translation": [0, 0, -3]},
+            {"camera": 0, "translation": [0, 0, 1]},
+            {"extensions": {"KHR_lights_punctual": {"light": 0}}, "translation": [0, 0, 2]}
+        ],
+        "extensionsUsed": ["KHR_lights_punctual"],
+        "extensions": {"KHR_lights_punctual": {"lights": [{"type": "point", "intensity": 20}]}},
+        "cameras": [{"type": "perspective",
+                     "perspective": {"yfov": 0.8, "znear": 0.1, "aspectRatio": 1.5}}],
+        "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "indices": 1, "material": 0}]}],
+        "materials": [{"pbrMetallicRoughness": {"baseColorFactor": [0.8, 0.2, 0.2, 1],
+                                                "metallicFactor": 0}}],
+        "buffers": [{"byteLength": 44, "uri": "data:application/octet-stream;base64,AACAvwAAgL8AAAAAAACAPwAAgL8AAAAAAAAAAAAAgD8AAAAAAAABAAIAAAA="}],
+        "bufferViews": [{"buffer": 0, "byteOffset": 0, "byteLength": 36},
+                        {"buffer": 0, "byteOffset": 36, "byteLength": 6}],
+        "accessors": [
+            {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+             "min": [-1, -1, 0], "max": [1, 1, 0]},
+            {"bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR"}
+        ]
+    }"#;
+
+    #[test]
+    fn test_load_gltf() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let path = std::env::temp_dir().join("ray_test_triangle.gltf");
+        std::fs::write(&path, TRIANGLE).unwrap();
+        let environment = load_gltf(&path).unwrap();
+        assert!((environment.params.apsect_ratio - 1.5).abs() < 1e-6);
+        assert_eq!(environment.params.background, BLACK);
+
+        let ray = Ray::new(point3(0.0, 0.0, 1.0), vec3(0.0, 0.0, -1.0), 0.0);
+        let rec = environment.scene.hit(&mut rng, &ray, 0.001, INFINITY);
+        assert!(rec.is_some_and(|rec| (rec.t - 4.0).abs() < 1e-4));
+        let lights = environment.lights.bounding_box(&(0.0..1.0)).unwrap();
+        assert!(lights.box_min.z > 1.9 && lights.box_max.z < 2.1);
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b.bin"), "a b.bin");
+    }
+}
@@ -0,0 +1,330 @@
+use crate::aov::{material_color, AovSample};
+use crate::bdpt::bdpt_color;
+use crate::bvh::TraversalCounter;
+use crate::film::heat_color;
+use crate::geom::*;
+use crate::material::Reflection;
+use crate::object::{HitRecord, Object, Ray};
+use crate::pdf::{CosinePdf, Pdf};
+use crate::render::{
+    power_heuristic, ray_color, ray_color_aovs, sample_light, PathStats, BOUNCE_DIMENSIONS,
+};
+use crate::sampler::SampleStream;
+use crate::scenes::Environment;
+
+/// Estimates the light arriving along camera rays, one sample at a time.
+pub trait Integrator: Send + Sync {
+    /// The radiance arriving along the camera ray `r`. Radiance the sample
+    /// carries to other pixels is passed to `splat` with the film position,
+    /// counted from the top row.
+    fn radiance(
+        &self,
+        rng: &mut SampleStream,
+        r: &Ray,
+        environment: &Environment,
+        stats: &mut PathStats,
+        splat: &mut dyn FnMut(u32, u32, Color),
+    ) -> Color;
+
+    /// `radiance`, also adding the AOVs `aovs` asks for. By default the
+    /// surface AOVs come from tracing `r` again and the lighting ones are
+    /// left out.
+    fn radiance_aovs(
+        &self,
+        rng: &mut SampleStream,
+        r: &Ray,
+        environment: &Environment,
+        stats: &mut PathStats,
+        splat: &mut dyn FnMut(u32, u32, Color),
+        aovs: &mut AovSample,
+    ) -> Color {
+        let radiance = self.radiance(rng, r, environment, stats, splat);
+        match environment.scene.hit(rng, r, 0.001, INFINITY) {
+            Some(rec) => aovs.add_surface(r, &rec, albedo(rng, r, &rec)),
+            None => aovs.add_miss(environment.background()),
+        }
+        radiance
+    }
+}
+
+/// The built in integrators.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IntegratorKind {
+    /// `ray_color`, unidirectional path tracing with light sampling.
+    #[default]
+    Path,
+    /// `bdpt_color`, bidirectional path tracing.
+    Bidirectional,
+    /// `render_photon_map`, stochastic progressive photon mapping.
+    PhotonMapping,
+    /// `render_mlt`, primary sample space Metropolis light transport.
+    Metropolis,
+    /// The normal at the first hit, facing the ray, mapped from [-1, 1] to
+    /// [0, 1].
+    Normals,
+    /// The distance to the first hit over the diagonal of the scene's
+    /// bounding box.
+    Depth,
+    /// The texture coordinates of the first hit in red and green.
+    Uv,
+    /// The color the first hit scatters or emits.
+    Albedo,
+    /// A color per material.
+    MaterialId,
+    /// The bounding volume hierarchy nodes and primitives the camera ray
+    /// visits, as a heatmap that is white at `BVH_COST_WHITE`.
+    BvhCost,
+    /// The share of the hemisphere above the first hit that is open within
+    /// `ao_distance`.
+    AmbientOcclusion,
+    /// Light reaching the first diffuse surface straight from the lights and
+    /// the background, following specular bounces.
+    Direct,
+}
+
+impl IntegratorKind {
+    /// An integrator of this kind, or None for photon mapping and Metropolis
+    /// light transport, which render the whole image at once.
+    pub fn build(self) -> Option<Box<dyn Integrator>> {
+        Some(match self {
+            IntegratorKind::Path => Box::new(PathTracer),
+            IntegratorKind::Bidirectional => Box::new(BidirectionalPathTracer),
+            IntegratorKind::PhotonMapping | IntegratorKind::Metropolis => return None,
+            IntegratorKind::Normals => Box::new(Normals),
+            IntegratorKind::Depth => Box::new(Depth),
+            IntegratorKind::Uv => Box::new(Uv),
+            IntegratorKind::Albedo => Box::new(Albedo),
+            IntegratorKind::MaterialId => Box::new(MaterialId),
+            IntegratorKind::BvhCost => Box::new(BvhCost),
+            IntegratorKind::AmbientOcclusion => Box::new(AmbientOcclusion),
+            IntegratorKind::Direct => Box::new(Direct),
+        })
+    }
+
+    /// Whether this kind renders data about the first hit rather than
+    /// radiance, which is saved without exposure, tone mapping or sRGB
+    /// encoding.
+    pub fn is_data(self) -> bool {
+        !matches!(
+            self,
+            IntegratorKind::Path
+                | IntegratorKind::Bidirectional
+                | IntegratorKind::PhotonMapping
+                | IntegratorKind::Metropolis
+                | IntegratorKind::Direct
+        )
+    }
+}
+
+/// Traversal steps shown in white by the BVH cost heatmap.
+pub const BVH_COST_WHITE: Float = 256.0;
+
+pub struct PathTracer;
+
+impl Integrator for PathTracer {
+    fn radiance(
+        &self,
+        rng: &mut SampleStream,
+        r: &Ray,
+        environment: &Environment,
+        stats: &mut PathStats,
+        _splat: &mut dyn FnMut(u32, u32, Color),
+    ) -> Color {
+        ray_color(rng, r, environment, stats)
+    }
+
+    fn radiance_aovs(
+        &self,
+        rng: &mut SampleStream,
+        r: &Ray,
+        environment: &Environment,
+        stats: &mut PathStats,
+        _splat: &mut dyn FnMut(u32, u32, Color),
+        aovs: &mut AovSample,
+    ) -> Color {
+        ray_color_aovs(rng, r, environment, stats, aovs)
+    }
+}
+
+pub struct BidirectionalPathTracer;
+
+impl Integrator for BidirectionalPathTracer {
+    fn radiance(
+        &self,
+        rng: &mut SampleStream,
+        r: &Ray,
+        environment: &Environment,
+        stats: &mut PathStats,
+        splat: &mut dyn FnMut(u32, u32, Color),
+    ) -> Color {
+        bdpt_color(rng, r, environment, stats, splat)
+    }
+}
+
+// The first hit along the camera ray `r`.
+fn first_hit(
+    rng: &mut SampleStream,
+    r: &Ray,
+    environment: &Environment,
+    stats: &mut PathStats,
+) -> Option<HitRecord> {
+    stats.rays += 1;
+    PathStats::count(&mut stats.depths, 0);
+    rng.next_block(BOUNCE_DIMENSIONS);
+    environment.scene.hit(rng, r, 0.001, INFINITY)
+}
+
+// The color `rec` scatters, or emits if it does not scatter.
+fn albedo(rng: &mut SampleStream, r: &Ray, rec: &HitRecord) -> Color {
+    match rec.material.scatter(rng, r, rec) {
+        Some(scatter) => scatter.attenuation,
+        None => rec.material.color_emitted(rec, rec.u, rec.v, rec.p),
+    }
+}
+
+// Implement `Integrator` for a unit struct from the color of the first hit,
+// black where the ray escapes.
+macro_rules! first_hit_integrator {
+    ($name:ident, |$rng:ident, $r:ident, $environment:ident, $stats:ident, $rec:ident| $body:expr) => {
+        pub struct $name;
+
+        impl Integrator for $name {
+            fn radiance(
+                &self,
+                $rng: &mut SampleStream,
+                $r: &Ray,
+                $environment: &Environment,
+                $stats: &mut PathStats,
+                _splat: &mut dyn FnMut(u32, u32, Color),
+            ) -> Color {
+                match first_hit($rng, $r, $environment, $stats) {
+                    Some($rec) => $body,
+                    None => BLACK,
+                }
+            }
+        }
+    };
+}
+
+first_hit_integrator!(Normals, |_rng, _r, _environment, _stats, rec| 0.5
+    * (rec.normal + WHITE));
+
+first_hit_integrator!(Depth, |_rng, r, environment, _stats, rec| {
+    let diagonal = environment
+        .scene
+        .bounding_box(&(0.0..1.0))
+        .map_or(1.0, |b| dist(b.box_min, b.box_max));
+    let d = rec.t * r.direction.length() / diagonal;
+    color(d, d, d)
+});
+
+first_hit_integrator!(Uv, |_rng, _r, _environment, _stats, rec| color(
+    rec.u, rec.v, 0.0
+));
+
+first_hit_integrator!(Albedo, |rng, r, _environment, _stats, rec| albedo(
+    rng, r, &rec
+));
+
+first_hit_integrator!(MaterialId, |_rng, _r, _environment, _stats, rec| {
+    material_color(&rec.material)
+});
+
+first_hit_integrator!(AmbientOcclusion, |rng, r, environment, stats, rec| {
+    let distance = match environment.params.ao_distance {
+        d if d > 0.0 => d,
+        _ => environment
+            .scene
+            .bounding_box(&(0.0..1.0))
+            .map_or(INFINITY, |b| 0.1 * dist(b.box_min, b.box_max)),
+    };
+    let direction = CosinePdf::with_w(rec.normal).generate(rng);
+    let ray = Ray::new(rec.p, direction, r.time);
+    stats.rays += 1;
+    let reach = distance / direction.length();
+    match environment.scene.hit(rng, &ray, 0.001, reach) {
+        Some(_) => BLACK,
+        None => WHITE,
+    }
+});
+
+pub struct BvhCost;
+
+impl Integrator for BvhCost {
+    fn radiance(
+        &self,
+        rng: &mut SampleStream,
+        r: &Ray,
+        environment: &Environment,
+        stats: &mut PathStats,
+        _splat: &mut dyn FnMut(u32, u32, Color),
+    ) -> Color {
+        let counter = TraversalCounter::start();
+        first_hit(rng, r, environment, stats);
+        heat_color(counter.steps() as Float / BVH_COST_WHITE)
+    }
+}
+
+pub struct Direct;
+
+impl Integrator for Direct {
+    fn radiance(
+        &self,
+        rng: &mut SampleStream,
+        r: &Ray,
+        environment: &Environment,
+        stats: &mut PathStats,
+        _splat: &mut dyn FnMut(u32, u32, Color),
+    ) -> Color {
+        let world = &*environment.scene;
+        let lights = &*environment.lights;
+        let mut radiance = BLACK;
+        let mut throughput = WHITE;
+        let mut ray = *r;
+        for depth in 0..environment.max_depth() {
+            stats.rays += 1;
+            PathStats::count(&mut stats.depths, depth);
+            rng.next_block(BOUNCE_DIMENSIONS);
+            let Some(rec) = world.hit(rng, &ray, 0.001, INFINITY) else {
+                radiance += throughput * environment.background();
+                break;
+            };
+            radiance += throughput * rec.material.color_emitted(&rec, rec.u, rec.v, rec.p);
+            let Some(scatter) = rec.material.scatter(rng, &ray, &rec) else {
+                break;
+            };
+            let pdf = match scatter.reflection {
+                Reflection::Specular(next) => {
+                    throughput = throughput * scatter.attenuation;
+                    ray = next;
+                    continue;
+                }
+                Reflection::Scatter(pdf) => pdf,
+            };
+            let attenuation = scatter.attenuation;
+            let (direct, _) =
+                sample_light(rng, &ray, &rec, attenuation, &*pdf, world, lights, stats);
+            radiance += throughput * direct;
+            // The BSDF sample, counting only what it reaches directly.
+            let scattered = Ray::new(rec.p, pdf.generate(rng), ray.time);
+            let pdf_val = pdf.value(scattered.direction);
+            if pdf_val > 0.0 {
+                let f = attenuation * rec.material.scattering_pdf(&ray, &rec, &scattered);
+                stats.rays += 1;
+                let le = match world.hit(rng, &scattered, 0.001, INFINITY) {
+                    Some(light) => {
+                        let le = light
+                            .material
+                            .color_emitted(&light, light.u, light.v, light.p);
+                        let light_pdf = lights.pdf_value(scattered.origin, scattered.direction);
+                        power_heuristic(pdf_val, light_pdf) * le
+                    }
+                    None => environment.background(),
+                };
+                radiance += throughput * f * le / pdf_val;
+            }
+            break;
+        }
+        radiance
+    }
+}
@@ -1,54 +1,496 @@
+use crate::aov::{Aov, Layer};
+use crate::film::Film;
+use crate::geom::*;
+use crate::sampler::SamplerKind;
+use crate::scenes::RenderParams;
+use crate::tonemap::{to_srgb8, ToneMap};
 use png::*;
 use std::fs::File;
-use std::io::BufWriter;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 
-pub fn write_ppm(data: &[u8], width: u32, height: u32, name: &'static str) {
+/// The first `images/{name}_{n}.{extension}` that does not exist yet.
+pub fn next_path(name: &str, extension: &str) -> PathBuf {
     let path = format!(r"images/{}", name);
     let mut num = 0;
     let mut sketch = PathBuf::from(format!(r"{}_{}", path, num));
-    sketch.set_extension("ppm");
+    sketch.set_extension(extension);
     while sketch.exists() {
         num += 1;
         sketch = PathBuf::from(format!(r"{}_{}", path, num));
-        sketch.set_extension("ppm");
+        sketch.set_extension(extension);
     }
-    let mut file = File::create(&sketch).unwrap();
-    writeln!(file, "P3").unwrap();
-    writeln!(file, "{} {}", width, height).unwrap();
-    writeln!(file, "255").unwrap();
+    sketch
+}
+
+pub fn save_ppm(data: &[u8], width: u32, height: u32, path: &Path) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "P3")?;
+    writeln!(file, "{} {}", width, height)?;
+    writeln!(file, "255")?;
     for y in 0..height {
         for x in 0..width {
             let offset = ((y * width * 3) + x * 3) as usize;
             write!(
                 &mut file,
                 "{} {} {} ",
-                data[offset + 0],
+                data[offset],
                 data[offset + 1],
                 data[offset + 2]
-            )
-            .unwrap()
+            )?
         }
-        write!(&mut file, "\n").unwrap()
+        writeln!(&mut file)?
     }
+    Ok(())
 }
 
-pub fn write_png(data: &[u8], width: u32, height: u32, name: &'static str) {
-    let path = format!(r"images/{}", name);
-    let mut num = 0;
-    let mut sketch = PathBuf::from(format!(r"{}_{}", path, num));
-    sketch.set_extension("png");
-    while sketch.exists() {
-        num += 1;
-        sketch = PathBuf::from(format!(r"{}_{}", path, num));
-        sketch.set_extension("png");
-    }
-    let file = File::create(&sketch).unwrap();
+pub fn save_png(data: &[u8], width: u32, height: u32, path: &Path) -> std::io::Result<()> {
+    let file = File::create(path)?;
     let w = &mut BufWriter::new(file);
     let mut encoder = Encoder::new(w, width, height);
     encoder.set_color(ColorType::Rgb);
     encoder.set_depth(BitDepth::Eight);
-    let mut writer = encoder.write_header().unwrap();
-    writer.write_image_data(data).unwrap();
-}
\ No newline at end of file
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(data)?;
+    Ok(())
+}
+
+// The following take linear radiance, `width * height` pixels from the top row down.
+
+pub fn save_exr(pixels: &[Color], width: u32, height: u32, path: &Path) -> std::io::Result<()> {
+    exr::prelude::write_rgb_file(path, width as usize, height as usize, |x, y| {
+        let c = pixels[y * width as usize + x];
+        (c.x, c.y, c.z)
+    })
+    .map_err(std::io::Error::other)
+}
+
+/// OpenEXR with the beauty image in R, G and B and every layer in
+/// `<name>.R`, `<name>.G` and `<name>.B`, as compositors read layers.
+pub fn save_exr_layers(
+    pixels: &[Color],
+    layers: &[(String, Vec<Color>)],
+    width: u32,
+    height: u32,
+    path: &Path,
+) -> std::io::Result<()> {
+    use exr::prelude::*;
+    let channel = |name: String, pixels: &[Color], i: u8| {
+        AnyChannel::new(
+            name.as_str(),
+            FlatSamples::F32(pixels.iter().map(|c| c[i]).collect()),
+        )
+    };
+    let mut channels = SmallVec::new();
+    for (i, rgb) in ["R", "G", "B"].into_iter().enumerate() {
+        channels.push(channel(rgb.to_string(), pixels, i as u8));
+        for (name, layer) in layers {
+            channels.push(channel(format!("{}.{}", name, rgb), layer, i as u8));
+        }
+    }
+    let size = (width as usize, height as usize);
+    Image::from_channels(size, AnyChannels::sort(channels))
+        .write()
+        .to_file(path)
+        .map_err(std::io::Error::other)
+}
+
+// Shared exponent encoding, see Greg Ward, "Real Pixels", Graphics Gems II.
+fn rgbe(c: Color) -> [u8; 4] {
+    let v = c.x.max(c.y).max(c.z);
+    if v < 1e-32 {
+        return [0; 4];
+    }
+    let e = v.log2().floor() as i32 + 1;
+    let scale = 256.0 / (2.0 as Float).powi(e);
+    [
+        (c.x.max(0.0) * scale).min(255.0) as u8,
+        (c.y.max(0.0) * scale).min(255.0) as u8,
+        (c.z.max(0.0) * scale).min(255.0) as u8,
+        (e + 128) as u8,
+    ]
+}
+
+/// Radiance RGBE with uncompressed scanlines.
+pub fn save_hdr(pixels: &[Color], width: u32, height: u32, path: &Path) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "#?RADIANCE")?;
+    writeln!(file, "FORMAT=32-bit_rle_rgbe")?;
+    writeln!(file)?;
+    writeln!(file, "-Y {} +X {}", height, width)?;
+    for &c in &pixels[..(width * height) as usize] {
+        file.write_all(&rgbe(c))?;
+    }
+    file.flush()
+}
+
+/// Portable float map, little endian with the bottom row first.
+pub fn save_pfm(pixels: &[Color], width: u32, height: u32, path: &Path) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "PF\n{} {}\n-1.0\n", width, height)?;
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let c = pixels[(y * width + x) as usize];
+            for v in [c.x, c.y, c.z] {
+                file.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+    file.flush()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Ppm,
+    Exr,
+    Hdr,
+    Pfm,
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Ppm => "ppm",
+            ImageFormat::Exr => "exr",
+            ImageFormat::Hdr => "hdr",
+            ImageFormat::Pfm => "pfm",
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<ImageFormat> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        [
+            ImageFormat::Png,
+            ImageFormat::Ppm,
+            ImageFormat::Exr,
+            ImageFormat::Hdr,
+            ImageFormat::Pfm,
+        ]
+        .into_iter()
+        .find(|f| f.extension() == ext)
+    }
+
+    /// Whether the format stores linear radiance rather than display values.
+    pub fn is_linear(self) -> bool {
+        matches!(self, ImageFormat::Exr | ImageFormat::Hdr | ImageFormat::Pfm)
+    }
+}
+
+/// Save the mean of every pixel of `film`. The 8 bit formats are encoded by
+/// `RenderParams::to_8bit`.
+///
+/// The film's AOV layers go into the same file as OpenEXR, and otherwise
+/// into files named after the layer next to `path`, see `layer_path`. The 8
+/// bit formats clamp them to [0, 1].
+pub fn save_film(
+    film: &Film,
+    format: ImageFormat,
+    params: &RenderParams,
+    path: &Path,
+) -> std::io::Result<()> {
+    let (w, h) = (film.width(), film.height());
+    let layers: Vec<(String, Vec<Color>)> = film
+        .layers()
+        .map(|layer| (layer.to_string(), film.layer_rgb(layer).unwrap()))
+        .collect();
+    if format == ImageFormat::Exr {
+        return save_exr_layers(&film.to_rgb(), &layers, w, h, path);
+    }
+    // Layers are clamped whatever the image's tone mapping.
+    let to_8bit = |pixels: &[Color], layer: bool| match layer {
+        true => to_srgb8(pixels, ToneMap::Clamp, 0.0),
+        false => params.to_8bit(pixels),
+    };
+    let save = |pixels: &[Color], layer, path: &Path| match format {
+        ImageFormat::Png => save_png(&to_8bit(pixels, layer), w, h, path),
+        ImageFormat::Ppm => save_ppm(&to_8bit(pixels, layer), w, h, path),
+        ImageFormat::Exr => save_exr(pixels, w, h, path),
+        ImageFormat::Hdr => save_hdr(pixels, w, h, path),
+        ImageFormat::Pfm => save_pfm(pixels, w, h, path),
+    };
+    save(&film.to_rgb(), false, path)?;
+    for (name, pixels) in &layers {
+        save(pixels, true, &layer_path(path, name))?;
+    }
+    Ok(())
+}
+
+/// Where `save_film` puts the layer `name` of an image saved to `path`:
+/// `image.png` becomes `image.name.png`.
+pub fn layer_path(path: &Path, name: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut file = format!("{}.{}", stem, name);
+    if let Some(extension) = path.extension() {
+        file = format!("{}.{}", file, extension.to_string_lossy());
+    }
+    path.with_file_name(file)
+}
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"RAYCKPT5";
+
+/// How the samples in a checkpoint were drawn, which the samples added when
+/// it is resumed have to match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sampling {
+    pub seed: u64,
+    pub sampler: SamplerKind,
+    /// The samples per pixel the sampler's pattern is laid out for.
+    pub pattern: u32,
+}
+
+/// Save the samples accumulated in `film` and how they were drawn. The file is written next to `path` and renamed over it, so a render
+/// killed while saving leaves the previous checkpoint intact.
+pub fn save_checkpoint(film: &Film, sampling: &Sampling, path: &Path) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    file.write_all(CHECKPOINT_MAGIC)?;
+    file.write_all(&film.width().to_le_bytes())?;
+    file.write_all(&film.height().to_le_bytes())?;
+    file.write_all(&sampling.seed.to_le_bytes())?;
+    let sampler = SamplerKind::ALL
+        .iter()
+        .position(|&s| s == sampling.sampler)
+        .unwrap() as u32;
+    file.write_all(&sampler.to_le_bytes())?;
+    file.write_all(&sampling.pattern.to_le_bytes())?;
+    for p in film.pixels() {
+        for v in [
+            p.sum.x, p.sum.y, p.sum.z, p.sum_sq.x, p.sum_sq.y, p.sum_sq.z,
+        ] {
+            file.write_all(&v.to_le_bytes())?;
+        }
+        file.write_all(&p.count.to_le_bytes())?;
+        for v in [
+            p.splat.x,
+            p.splat.y,
+            p.splat.z,
+            p.filtered.x,
+            p.filtered.y,
+            p.filtered.z,
+            p.weight,
+        ] {
+            file.write_all(&v.to_le_bytes())?;
+        }
+    }
+    let layers: Vec<Layer> = film.layers().collect();
+    file.write_all(&(layers.len() as u32).to_le_bytes())?;
+    for layer in layers {
+        let aov = Aov::ALL.iter().position(|&a| a == layer.aov).unwrap() as u32;
+        file.write_all(&aov.to_le_bytes())?;
+        file.write_all(&layer.light.to_le_bytes())?;
+        for c in film.layer_sums(layer).unwrap() {
+            for v in [c.x, c.y, c.z] {
+                file.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+    file.into_inner()?.sync_all()?;
+    std::fs::rename(tmp, path)
+}
+
+/// Read a film and its sampling written by `save_checkpoint`.
+pub fn load_checkpoint(path: &Path) -> std::io::Result<(Film, Sampling)> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    file.read_exact(&mut magic)?;
+    if &magic != CHECKPOINT_MAGIC {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "not a checkpoint file",
+        ));
+    }
+    let mut u32_bytes = [0; 4];
+    let mut read_u32 = |file: &mut BufReader<File>| -> std::io::Result<u32> {
+        file.read_exact(&mut u32_bytes)?;
+        Ok(u32::from_le_bytes(u32_bytes))
+    };
+    let width = read_u32(&mut file)?;
+    let height = read_u32(&mut file)?;
+    let mut seed = [0; 8];
+    file.read_exact(&mut seed)?;
+    let sampler = SamplerKind::ALL
+        .get(read_u32(&mut file)? as usize)
+        .copied()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "unknown sampler"))?;
+    let sampling = Sampling {
+        seed: u64::from_le_bytes(seed),
+        sampler,
+        pattern: read_u32(&mut file)?,
+    };
+    let mut film = Film::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let mut v = [0.0; 6];
+            for v in &mut v {
+                *v = f32::from_bits(read_u32(&mut file)?);
+            }
+            let p = film.pixel_mut(x, y);
+            p.sum = color(v[0], v[1], v[2]);
+            p.sum_sq = color(v[3], v[4], v[5]);
+            p.count = read_u32(&mut file)?;
+            let mut v = [0.0; 7];
+            for v in &mut v {
+                *v = f32::from_bits(read_u32(&mut file)?);
+            }
+            p.splat = color(v[0], v[1], v[2]);
+            p.filtered = color(v[3], v[4], v[5]);
+            p.weight = v[6];
+        }
+    }
+    for _ in 0..read_u32(&mut file)? {
+        let aov = Aov::ALL
+            .get(read_u32(&mut file)? as usize)
+            .copied()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "unknown AOV"))?;
+        let layer = Layer {
+            aov,
+            light: read_u32(&mut file)?,
+        };
+        let mut sums = Vec::with_capacity((width * height) as usize);
+        for _ in 0..width * height {
+            let mut v = [0.0; 3];
+            for v in &mut v {
+                *v = f32::from_bits(read_u32(&mut file)?);
+            }
+            sums.push(color(v[0], v[1], v[2]));
+        }
+        film.layer_sums_mut(layer).copy_from_slice(&sums);
+    }
+    Ok((film, sampling))
+}
+
+pub fn write_ppm(data: &[u8], width: u32, height: u32, name: &'static str) {
+    save_ppm(data, width, height, &next_path(name, "ppm")).unwrap();
+}
+
+pub fn write_png(data: &[u8], width: u32, height: u32, name: &'static str) {
+    save_png(data, width, height, &next_path(name, "png")).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{Filter, FilterKind};
+
+    // A path in the temporary directory no other test process writes to.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ray_test_{}_{}", std::process::id(), name))
+    }
+
+    fn gradient() -> Vec<Color> {
+        (0..12)
+            .map(|i| color(i as Float * 0.5, 0.25, 8.0 - i as Float * 0.5))
+            .collect()
+    }
+
+    fn read_rgb32f(path: &Path) -> Vec<Color> {
+        let image = image::open(path).unwrap().to_rgb32f();
+        assert_eq!(image.dimensions(), (4, 3));
+        image.pixels().map(|p| color(p[0], p[1], p[2])).collect()
+    }
+
+    #[test]
+    fn test_hdr_round_trip() {
+        let pixels = gradient();
+        let dir = std::env::temp_dir();
+
+        let path = dir.join("ray_test_gradient.exr");
+        save_exr(&pixels, 4, 3, &path).unwrap();
+        for (a, b) in read_rgb32f(&path).iter().zip(&pixels) {
+            assert!((*a - *b).length() < 1e-6);
+        }
+
+        let path = dir.join("ray_test_gradient.hdr");
+        save_hdr(&pixels, 4, 3, &path).unwrap();
+        let file = std::io::BufReader::new(File::open(&path).unwrap());
+        let decoder = image::codecs::hdr::HdrDecoder::new(file).unwrap();
+        let read = decoder.read_image_hdr().unwrap();
+        assert_eq!(read.len(), 12);
+        for (a, b) in read.iter().zip(&pixels) {
+            let a = color(a[0], a[1], a[2]);
+            assert!((a - *b).length() < 0.01 * b.length());
+        }
+
+        let path = dir.join("ray_test_gradient.pfm");
+        save_pfm(&pixels, 4, 3, &path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let header = b"PF\n4 3\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        assert_eq!(bytes.len(), header.len() + 4 * 3 * 12);
+        // The first pixel in the file is the bottom left one.
+        let r = f32::from_le_bytes(bytes[header.len()..header.len() + 4].try_into().unwrap());
+        assert_eq!(r, pixels[8].x);
+    }
+
+    #[test]
+    fn test_exr_layers() {
+        let pixels = gradient();
+        let mut film = Film::new(4, 3);
+        for (i, &c) in pixels.iter().enumerate() {
+            film.add_sample(i as u32 % 4, i as u32 / 4, c);
+            film.layer_sums_mut(Layer::new(Aov::Depth))[i] = 2.0 * c;
+        }
+        let params = RenderParams::new(BLACK, 4.0 / 3.0, 4, 1, 1);
+        let path = temp_path("layers.exr");
+        save_film(&film, ImageFormat::Exr, &params, &path).unwrap();
+        let image = exr::prelude::read_all_flat_layers_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let channels = &image.layer_data[0].channel_data.list;
+        let names: Vec<String> = channels.iter().map(|c| c.name.to_string()).collect();
+        assert_eq!(names, ["B", "G", "R", "depth.B", "depth.G", "depth.R"]);
+        let depth_r = channels[5].sample_data.value_by_flat_index(4).to_f32();
+        assert_eq!(depth_r, 2.0 * pixels[4].x);
+
+        // Other formats get a file per layer.
+        let path = temp_path("layers.pfm");
+        save_film(&film, ImageFormat::Pfm, &params, &path).unwrap();
+        let layer = layer_path(&path, "depth");
+        assert_eq!(layer, temp_path("layers.depth.pfm"));
+        assert!(layer.exists());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&layer).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let mut film = Film::new(4, 3);
+        for (i, &c) in gradient().iter().enumerate() {
+            for _ in 0..=i % 3 {
+                film.add_sample(i as u32 % 4, i as u32 / 4, c);
+            }
+        }
+        film.add_splat(1, 2, color(0.5, 0.25, 2.0));
+        let filter = Filter::new(FilterKind::Gaussian, 0.0);
+        film.add_filtered(2.3, 1.6, color(1.0, 0.5, 0.0), &filter);
+        film.layer_sums_mut(Layer::light(7))[5] = color(1.0, 2.0, 3.0);
+        let path = temp_path("checkpoint.ckpt");
+        let sampling = Sampling {
+            seed: 42,
+            sampler: SamplerKind::Stratified,
+            pattern: 9,
+        };
+        save_checkpoint(&film, &sampling, &path).unwrap();
+        let (read, saved) = load_checkpoint(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, sampling);
+        assert_eq!(read.pixels(), film.pixels());
+        assert_eq!(read.layers().collect::<Vec<_>>(), vec![Layer::light(7)]);
+        assert_eq!(
+            read.layer_sums(Layer::light(7)),
+            film.layer_sums(Layer::light(7))
+        );
+
+        // The temporary file is not the checkpoint itself, whatever its name.
+        let path = temp_path("checkpoint.tmp");
+        save_checkpoint(&film, &sampling, &path).unwrap();
+        assert_eq!(load_checkpoint(&path).unwrap().0.pixels(), film.pixels());
+        assert!(!temp_path("checkpoint.tmp.tmp").exists());
+
+        std::fs::write(&path, b"not a checkpoint").unwrap();
+        assert!(load_checkpoint(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
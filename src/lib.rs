@@ -1,13 +1,28 @@
 pub mod aabb;
+pub mod aov;
+pub mod bdpt;
 pub mod bvh;
 pub mod camera;
+pub mod denoise;
+pub mod film;
+pub mod filter;
 pub mod geom;
+pub mod gltf_import;
+pub mod integrator;
 pub mod material;
+pub mod mlt;
+pub mod obj;
 pub mod object;
+pub mod photon;
 pub mod rect;
 pub mod render;
+pub mod sampler;
 pub mod scenes;
 pub mod sphere;
 pub mod texture;
+pub mod tile;
+pub mod tonemap;
+pub mod triangle;
 pub mod io;
-pub mod pdf;
\ No newline at end of file
+pub mod pdf;
+pub mod scene_file;
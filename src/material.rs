@@ -2,8 +2,7 @@ use crate::geom::*;
 use crate::object::*;
 use crate::pdf::*;
 use crate::texture::*;
-use rand::rngs::SmallRng;
-use rand::{thread_rng, Rng, SeedableRng};
+use rand::{Rng, RngCore};
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -30,6 +29,7 @@ impl Scatter {
         Scatter::new(reflection, attenuation)
     }
 
+    #[allow(clippy::self_named_constructors)]
     pub fn scatter(pdf: Arc<dyn Pdf>, attenuation: Color) -> Self {
         let reflection = Reflection::Scatter(pdf);
         Scatter::new(reflection, attenuation)
@@ -37,7 +37,7 @@ impl Scatter {
 }
 
 pub trait Material: Send + Sync {
-    fn scatter(&self, _r_in: &Ray, _rec: &HitRecord) -> Option<Scatter> {
+    fn scatter(&self, _rng: &mut dyn RngCore, _r_in: &Ray, _rec: &HitRecord) -> Option<Scatter> {
         None
     }
     fn scattering_pdf(&self, _r_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> Float {
@@ -46,6 +46,11 @@ pub trait Material: Send + Sync {
     fn color_emitted(&self, _rec: &HitRecord, _u: Float, _v: Float, _p: Point3) -> Color {
         BLACK
     }
+    /// Whether this scatters inside a volume, where hits have no surface
+    /// normal to foreshorten by.
+    fn is_volume(&self) -> bool {
+        false
+    }
 }
 
 pub struct Lambertian<T> {
@@ -83,7 +88,7 @@ impl<T> Material for Lambertian<T>
 where
     T: Texture,
 {
-    fn scatter(&self, _r_in: &Ray, rec: &HitRecord) -> Option<Scatter> {
+    fn scatter(&self, _rng: &mut dyn RngCore, _r_in: &Ray, rec: &HitRecord) -> Option<Scatter> {
         Some(Scatter::scatter(
             Arc::new(CosinePdf::with_w(rec.normal)),
             self.albedo.value(rec.u, rec.v, rec.p),
@@ -109,12 +114,11 @@ impl Metal {
 }
 
 impl Material for Metal {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<Scatter> {
-        let mut rng = SmallRng::from_rng(thread_rng()).unwrap();
+    fn scatter(&self, rng: &mut dyn RngCore, r_in: &Ray, rec: &HitRecord) -> Option<Scatter> {
         let reflected = reflect(r_in.direction.normalize(), rec.normal);
         let scattered = Ray::new(
             rec.p,
-            reflected + self.fuzz * random_in_unit_sphere(&mut rng),
+            reflected + self.fuzz * random_in_unit_sphere(rng),
             r_in.time,
         );
         Some(Scatter::specular(scattered, self.albedo))
@@ -144,7 +148,7 @@ fn schlick(cosine: Float, ir: Float) -> Float {
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, r_in: &Ray, hit: &HitRecord) -> Option<Scatter> {
+    fn scatter(&self, rng: &mut dyn RngCore, r_in: &Ray, hit: &HitRecord) -> Option<Scatter> {
         let attenuation = WHITE;
         let refraction_ratio = if hit.front_face {
             1.0 / self.ir
@@ -155,7 +159,7 @@ impl Material for Dielectric {
         let cos_theta = dot(-unit_direction, hit.normal).min(1.0);
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
-        let rn: Float = SmallRng::from_rng(thread_rng()).unwrap().gen();
+        let rn: Float = rng.gen();
         let direction = if cannot_refract || schlick(cos_theta, refraction_ratio) > rn {
             reflect(unit_direction, hit.normal)
         } else {
@@ -215,16 +219,18 @@ impl<T> Material for Isotropic<T>
 where
     T: Texture,
 {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<Scatter> {
-        let mut rng = SmallRng::from_rng(thread_rng()).unwrap();
-        let scattered = Ray::new(rec.p, random_unit_vector(&mut rng), r_in.time);
+    fn scatter(&self, _rng: &mut dyn RngCore, _r_in: &Ray, rec: &HitRecord) -> Option<Scatter> {
         let attenuation = self.albedo.value(rec.u, rec.v, rec.p);
-        Some(Scatter::specular(scattered, attenuation))
+        Some(Scatter::scatter(Arc::new(SpherePdf), attenuation))
     }
 
     fn scattering_pdf(&self, _r_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> Float {
         1.0 / (4.0 * PI)
     }
+
+    fn is_volume(&self) -> bool {
+        true
+    }
 }
 
 pub fn isotropic(r: Float, g: Float, b: Float) -> Arc<Isotropic<Color>> {
@@ -0,0 +1,234 @@
+use crate::film::Film;
+use crate::geom::*;
+use crate::render::{
+    ray_color, sample_rng, target_samples, PathStats, Progress, BOUNCE_DIMENSIONS,
+    CAMERA_DIMENSIONS,
+};
+use crate::sampler::{splitmix64, SampleStream, Sampler};
+use crate::scenes::Environment;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// A point in primary sample space: every value a path draws from its
+/// `SampleStream` within the sampler's blocks. Dimensions past the end are
+/// independent on every evaluation.
+#[derive(Clone, Debug)]
+pub struct PrimarySamples {
+    values: Vec<Float>,
+    seed: u64,
+}
+
+impl PrimarySamples {
+    /// A uniformly random point with `dimensions` dimensions.
+    pub fn random(rng: &mut SmallRng, dimensions: usize) -> Self {
+        Self {
+            values: (0..dimensions).map(|_| rng.gen()).collect(),
+            seed: rng.gen(),
+        }
+    }
+
+    /// A large step, a new uniformly random point.
+    pub fn large_step(&self, rng: &mut SmallRng) -> Self {
+        Self::random(rng, self.values.len())
+    }
+
+    /// A small step, every value moved by a normal offset with deviation
+    /// `sigma` and wrapped around into [0, 1).
+    pub fn small_step(&self, rng: &mut SmallRng, sigma: Float) -> Self {
+        let values = self
+            .values
+            .iter()
+            .map(|&v| {
+                // Box-Muller.
+                let (a, b): (Float, Float) = (rng.gen(), rng.gen());
+                let offset = (-2.0 * (1.0 - a).ln()).sqrt() * (2.0 * PI * b).cos();
+                (v + sigma * offset)
+                    .rem_euclid(1.0)
+                    .min(1.0 - Float::EPSILON)
+            })
+            .collect();
+        Self {
+            values,
+            seed: rng.gen(),
+        }
+    }
+}
+
+impl Sampler for PrimarySamples {
+    fn get(&self, _x: u32, _y: u32, _index: u32, dimension: u32) -> Float {
+        match self.values.get(dimension as usize) {
+            Some(&v) => v,
+            None => {
+                let bits = splitmix64(self.seed ^ splitmix64(dimension as u64));
+                (bits >> 40) as Float / (1u64 << 24) as Float
+            }
+        }
+    }
+}
+
+// A path sampled by `ray_color` from `samples`, as its position on the film,
+// counted from the top left, and its radiance. The first two dimensions pick
+// the position over the whole image.
+struct PathSample {
+    x: u32,
+    y: u32,
+    radiance: Color,
+    contribution: Float,
+}
+
+fn evaluate(
+    environment: &Environment,
+    samples: &PrimarySamples,
+    stats: &mut PathStats,
+) -> PathSample {
+    let (w, h) = (environment.width(), environment.height());
+    let rng = SmallRng::seed_from_u64(samples.seed);
+    let mut rng = SampleStream::new(samples, 0, 0, 0, rng);
+    rng.next_block(CAMERA_DIMENSIONS);
+    let s = rng.gen::<Float>() * w as Float;
+    let t = rng.gen::<Float>() * h as Float;
+    let r = environment
+        .camera
+        .get_ray(&mut rng, s / (w - 1) as Float, t / (h - 1) as Float);
+    let mut radiance = ray_color(&mut rng, &r, environment, stats);
+    if !(radiance.x.is_finite() && radiance.y.is_finite() && radiance.z.is_finite()) {
+        radiance = BLACK;
+    }
+    PathSample {
+        x: (s as u32).min(w - 1),
+        y: h - 1 - (t as u32).min(h - 1),
+        radiance,
+        contribution: luminance(radiance).max(0.0),
+    }
+}
+
+/// Render `film` with primary sample space Metropolis light transport after
+/// Kelemen et al. Paths are traced by `ray_color` from `PrimarySamples`,
+/// which Markov chains mutate with large steps, a fresh random point, taken
+/// with `large_step_probability`, and small steps of `mutation_size`. A step
+/// is accepted in proportion to the luminance it reaches, and both the
+/// proposed and the current path are splatted weighted by the chance of
+/// being kept.
+///
+/// The image's brightness comes from `mlt_bootstrap` independent paths, which
+/// also pick where the `mlt_chains` chains start. The chains take as many
+/// steps as `target_samples` per pixel, and each counts as a tile for
+/// `progress`. A film part way through is rendered over from the start.
+///
+/// Returns the statistics of the paths traced.
+pub fn render_mlt(
+    environment: &Environment,
+    film: &mut Film,
+    progress: &(dyn Fn(&Progress) + Sync),
+    pass_done: &mut dyn FnMut(&Film),
+) -> PathStats {
+    let (w, h) = (environment.width(), environment.height());
+    assert_eq!((film.width(), film.height()), (w, h));
+    let params = &environment.params;
+    let samples_per_pixel = target_samples(environment);
+    if film.pixels().iter().all(|p| p.count >= samples_per_pixel) {
+        return PathStats::default();
+    }
+    let dimensions = (CAMERA_DIMENSIONS + environment.max_depth() * BOUNCE_DIMENSIONS) as usize;
+    let start = Instant::now();
+
+    // Bootstrap, the mean contribution normalizes the image.
+    let bootstrap_point = |i: usize| {
+        let mut rng = sample_rng(params.seed, i as u64, 0);
+        PrimarySamples::random(&mut rng, dimensions)
+    };
+    let bootstrap: Vec<(Float, PathStats)> = (0..params.mlt_bootstrap.max(1) as usize)
+        .into_par_iter()
+        .map(|i| {
+            let mut stats = PathStats::default();
+            let sample = evaluate(environment, &bootstrap_point(i), &mut stats);
+            (sample.contribution, stats)
+        })
+        .collect();
+    let mut stats = PathStats::default();
+    let mut cdf = Vec::with_capacity(bootstrap.len());
+    let mut total = 0.0f64;
+    for (contribution, bootstrap_stats) in &bootstrap {
+        stats.merge(bootstrap_stats);
+        total += *contribution as f64;
+        cdf.push(total);
+    }
+    *film = Film::new(w, h);
+    let mutations = samples_per_pixel as u64 * w as u64 * h as u64;
+    if total > 0.0 {
+        let b = (total / bootstrap.len() as f64) as Float;
+        let chains = (params.mlt_chains.max(1) as u64).min(mutations.max(1));
+        let next = AtomicUsize::new(0);
+        let state = Mutex::new((Film::new(w, h), 0, PathStats::default()));
+        let scale = b * (w * h) as Float;
+        (0..rayon::current_num_threads())
+            .into_par_iter()
+            .for_each(|_| {
+                loop {
+                    let chain = next.fetch_add(1, Ordering::Relaxed) as u64;
+                    if chain >= chains {
+                        break;
+                    }
+                    let mut splats = Film::new(w, h);
+                    let mut chain_stats = PathStats::default();
+                    let mut rng = sample_rng(!params.seed, chain, 0);
+                    // Start where a bootstrap path was, picked in proportion
+                    // to its contribution.
+                    let u = rng.gen::<f64>() * total;
+                    let i = cdf.partition_point(|&c| c <= u).min(cdf.len() - 1);
+                    let mut current = bootstrap_point(i);
+                    let mut sample = evaluate(environment, &current, &mut chain_stats);
+                    let steps = mutations / chains + (chain < mutations % chains) as u64;
+                    for _ in 0..steps {
+                        let proposed = if rng.gen::<Float>() < params.large_step_probability {
+                            current.large_step(&mut rng)
+                        } else {
+                            current.small_step(&mut rng, params.mutation_size)
+                        };
+                        let candidate = evaluate(environment, &proposed, &mut chain_stats);
+                        let accept = if sample.contribution > 0.0 {
+                            (candidate.contribution / sample.contribution).min(1.0)
+                        } else {
+                            1.0
+                        };
+                        for (s, weight) in [(&candidate, accept), (&sample, 1.0 - accept)] {
+                            if weight > 0.0 && s.contribution > 0.0 {
+                                let c = weight * scale / s.contribution * s.radiance;
+                                splats.add_splat(s.x, s.y, c);
+                            }
+                        }
+                        if rng.gen::<Float>() < accept {
+                            current = proposed;
+                            sample = candidate;
+                        }
+                    }
+                    let mut state = state.lock().unwrap();
+                    let (all, chains_done, all_stats) = &mut *state;
+                    all.merge(&splats);
+                    *chains_done += 1;
+                    all_stats.merge(&chain_stats);
+                    progress(&Progress {
+                        tiles_done: *chains_done,
+                        tiles_total: chains as usize,
+                        rays_traced: stats.rays + all_stats.rays,
+                        elapsed: start.elapsed(),
+                    });
+                }
+            });
+        let (splats, _, chain_stats) = state.into_inner().unwrap();
+        film.merge(&splats);
+        stats.merge(&chain_stats);
+    }
+    // Every step counts as a sample, spread evenly over the pixels.
+    for y in 0..h {
+        for x in 0..w {
+            film.pixel_mut(x, y).count = samples_per_pixel;
+        }
+    }
+    pass_done(film);
+    stats
+}
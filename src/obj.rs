@@ -0,0 +1,463 @@
+use crate::geom::*;
+use crate::material::*;
+use crate::texture::ImageTexture;
+use crate::triangle::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(PathBuf, std::io::Error),
+    Parse {
+        file: PathBuf,
+        line: usize,
+        message: String,
+    },
+    Image(PathBuf, image::ImageError),
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ObjError::Parse {
+                file,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", file.display(), line, message),
+            ObjError::Image(path, e) => write!(f, "cannot load image {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ObjError::Io(_, e) => Some(e),
+            ObjError::Image(_, e) => Some(e),
+            ObjError::Parse { .. } => None,
+        }
+    }
+}
+
+/// One mesh per group and material in the file.
+pub struct ObjMesh {
+    pub group: String,
+    pub material: String,
+    pub emissive: bool,
+    pub mesh: TriangleMesh,
+}
+
+// Tracks the current file and line for error messages.
+struct Location<'a> {
+    file: &'a Path,
+    line: usize,
+}
+
+impl Location<'_> {
+    fn error(&self, message: impl Into<String>) -> ObjError {
+        ObjError::Parse {
+            file: self.file.to_path_buf(),
+            line: self.line,
+            message: message.into(),
+        }
+    }
+
+    fn float(&self, token: Option<&str>, what: &str) -> Result<Float, ObjError> {
+        let token = token.ok_or_else(|| self.error(format!("missing {}", what)))?;
+        token
+            .parse()
+            .map_err(|_| self.error(format!("invalid {} '{}'", what, token)))
+    }
+
+    fn vec3<'b>(&self, tokens: &mut impl Iterator<Item = &'b str>) -> Result<Vec3, ObjError> {
+        Ok(vec3(
+            self.float(tokens.next(), "x coordinate")?,
+            self.float(tokens.next(), "y coordinate")?,
+            self.float(tokens.next(), "z coordinate")?,
+        ))
+    }
+
+    // OBJ indices start at 1, negative indices count back from the last element.
+    fn index(&self, token: &str, count: usize, what: &str) -> Result<usize, ObjError> {
+        let i: i64 = token
+            .parse()
+            .map_err(|_| self.error(format!("invalid {} index '{}'", what, token)))?;
+        let resolved = match i.cmp(&0) {
+            std::cmp::Ordering::Greater => i - 1,
+            std::cmp::Ordering::Less => count as i64 + i,
+            std::cmp::Ordering::Equal => -1,
+        };
+        if resolved < 0 || resolved >= count as i64 {
+            return Err(self.error(format!(
+                "{} index {} out of range, there are {}",
+                what, i, count
+            )));
+        }
+        Ok(resolved as usize)
+    }
+}
+
+#[derive(Default)]
+struct MtlDesc {
+    kd: Option<Color>,
+    ks: Option<Color>,
+    ke: Option<Color>,
+    ni: Option<Float>,
+    ns: Option<Float>,
+    d: Option<Float>,
+    illum: Option<u32>,
+    map_kd: Option<PathBuf>,
+}
+
+impl MtlDesc {
+    fn emissive(&self) -> bool {
+        self.ke
+            .is_some_and(|ke| ke.x > 0.0 || ke.y > 0.0 || ke.z > 0.0)
+    }
+
+    fn material(&self) -> Result<Arc<dyn Material>, ObjError> {
+        let kd = self.kd.unwrap_or(color(0.8, 0.8, 0.8));
+        let ks = self.ks.unwrap_or(BLACK);
+        if self.emissive() {
+            return Ok(Arc::new(DiffuseLight::new(self.ke.unwrap())));
+        }
+        let transparent = self.d.is_some_and(|d| d < 1.0);
+        if transparent || matches!(self.illum, Some(4 | 6 | 7)) {
+            return Ok(Arc::new(Dielectric::new(self.ni.unwrap_or(1.5))));
+        }
+        if self.illum == Some(3) || (ks.length2() > 0.0 && kd.length2() == 0.0) {
+            // Convert the Phong exponent to a rough equivalent fuzz.
+            let fuzz = (2.0 / (self.ns.unwrap_or(1000.0) + 2.0)).sqrt();
+            return Ok(Arc::new(Metal::new(ks, fuzz)));
+        }
+        if let Some(path) = &self.map_kd {
+            let texture = ImageTexture::open(path).map_err(|e| ObjError::Image(path.clone(), e))?;
+            return Ok(Arc::new(Lambertian::new(texture)));
+        }
+        Ok(Arc::new(Lambertian::solid_color(kd)))
+    }
+}
+
+fn parse_mtl(
+    path: &Path,
+    materials: &mut HashMap<String, (Arc<dyn Material>, bool)>,
+) -> Result<(), ObjError> {
+    let file = File::open(path).map_err(|e| ObjError::Io(path.to_path_buf(), e))?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut loc = Location {
+        file: path,
+        line: 0,
+    };
+    let mut current: Option<(String, MtlDesc)> = None;
+    let mut finish = |current: Option<(String, MtlDesc)>| -> Result<(), ObjError> {
+        if let Some((name, desc)) = current {
+            materials.insert(name, (desc.material()?, desc.emissive()));
+        }
+        Ok(())
+    };
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| ObjError::Io(path.to_path_buf(), e))?;
+        loc.line += 1;
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        if keyword == "newmtl" {
+            finish(current.take())?;
+            let name = tokens.collect::<Vec<_>>().join(" ");
+            current = Some((name, MtlDesc::default()));
+            continue;
+        }
+        let Some((_, desc)) = current.as_mut() else {
+            return Err(loc.error(format!("'{}' before newmtl", keyword)));
+        };
+        match keyword {
+            "Kd" => desc.kd = Some(loc.vec3(&mut tokens)?),
+            "Ks" => desc.ks = Some(loc.vec3(&mut tokens)?),
+            "Ke" => desc.ke = Some(loc.vec3(&mut tokens)?),
+            "Ni" => desc.ni = Some(loc.float(tokens.next(), "Ni")?),
+            "Ns" => desc.ns = Some(loc.float(tokens.next(), "Ns")?),
+            "d" => desc.d = Some(loc.float(tokens.next(), "d")?),
+            "Tr" => desc.d = Some(1.0 - loc.float(tokens.next(), "Tr")?),
+            "illum" => {
+                let illum = tokens.next().unwrap_or("");
+                desc.illum = Some(
+                    illum
+                        .parse()
+                        .map_err(|_| loc.error(format!("invalid illum '{}'", illum)))?,
+                );
+            }
+            // Texture options come before the file name.
+            "map_Kd" => {
+                let name = tokens
+                    .last()
+                    .ok_or_else(|| loc.error("map_Kd without a file name"))?;
+                desc.map_kd = Some(base_dir.join(name));
+            }
+            _ => {}
+        }
+    }
+    finish(current)
+}
+
+type VertexKey = (usize, Option<usize>, Option<usize>);
+
+struct MeshBuilder {
+    group: String,
+    material: String,
+    positions: Vec<Point3>,
+    normals: Vec<Option<Vec3>>,
+    uvs: Vec<Option<(Float, Float)>>,
+    indices: Vec<[u32; 3]>,
+    vertices: HashMap<VertexKey, u32>,
+}
+
+impl MeshBuilder {
+    fn new(group: &str, material: &str) -> Self {
+        Self {
+            group: group.to_string(),
+            material: material.to_string(),
+            positions: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            indices: Vec::new(),
+            vertices: HashMap::new(),
+        }
+    }
+
+    fn vertex(&mut self, key: VertexKey, obj: &ObjData) -> u32 {
+        *self.vertices.entry(key).or_insert_with(|| {
+            self.positions.push(obj.positions[key.0]);
+            self.uvs.push(key.1.map(|i| obj.uvs[i]));
+            self.normals.push(key.2.map(|i| obj.normals[i]));
+            self.positions.len() as u32 - 1
+        })
+    }
+
+    // Vertices without a normal get the area weighted average of the normals
+    // of the faces around them.
+    fn finish(self) -> MeshData {
+        let normals = if self.normals.iter().all(Option::is_none) {
+            Vec::new()
+        } else {
+            let mut smooth = vec![ZERO; self.positions.len()];
+            for &[a, b, c] in &self.indices {
+                let (a, b, c) = (a as usize, b as usize, c as usize);
+                let n = cross(
+                    self.positions[b] - self.positions[a],
+                    self.positions[c] - self.positions[a],
+                );
+                smooth[a] += n;
+                smooth[b] += n;
+                smooth[c] += n;
+            }
+            self.normals
+                .iter()
+                .zip(smooth)
+                .map(|(n, s)| match n {
+                    Some(n) => *n,
+                    None if s.near_zero() => s,
+                    None => s.normalize(),
+                })
+                .collect()
+        };
+        let uvs = if self.uvs.iter().all(Option::is_none) {
+            Vec::new()
+        } else {
+            self.uvs.iter().map(|uv| uv.unwrap_or((0.0, 0.0))).collect()
+        };
+        MeshData::new(self.positions, normals, uvs, self.indices)
+    }
+}
+
+#[derive(Default)]
+struct ObjData {
+    positions: Vec<Point3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(Float, Float)>,
+}
+
+/// Parse an OBJ file. Faces are triangulated as fans, material libraries are
+/// resolved against `base_dir` and faces without a material use `default_material`.
+pub fn parse_obj<R: BufRead>(
+    reader: R,
+    file: &Path,
+    base_dir: &Path,
+    default_material: Arc<dyn Material>,
+) -> Result<Vec<ObjMesh>, ObjError> {
+    let mut obj = ObjData::default();
+    let mut materials: HashMap<String, (Arc<dyn Material>, bool)> = HashMap::new();
+    let mut builders: Vec<MeshBuilder> = Vec::new();
+    let mut current: Option<usize> = None;
+    let mut group = String::from("default");
+    let mut material = String::new();
+    let mut loc = Location { file, line: 0 };
+    let mut face: Vec<u32> = Vec::new();
+
+    for line in reader.lines() {
+        let line = line.map_err(|e| ObjError::Io(file.to_path_buf(), e))?;
+        loc.line += 1;
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        match keyword {
+            "v" => obj.positions.push(loc.vec3(&mut tokens)?),
+            "vn" => {
+                let n = loc.vec3(&mut tokens)?;
+                obj.normals.push(if n.near_zero() { n } else { n.normalize() });
+            }
+            "vt" => {
+                let u = loc.float(tokens.next(), "u coordinate")?;
+                let v = match tokens.next() {
+                    Some(v) => loc.float(Some(v), "v coordinate")?,
+                    None => 0.0,
+                };
+                obj.uvs.push((u, v));
+            }
+            "g" | "o" => {
+                group = tokens.collect::<Vec<_>>().join(" ");
+                current = None;
+            }
+            "usemtl" => {
+                material = tokens.collect::<Vec<_>>().join(" ");
+                if !materials.contains_key(&material) {
+                    return Err(loc.error(format!("unknown material '{}'", material)));
+                }
+                current = None;
+            }
+            "mtllib" => {
+                for name in tokens {
+                    parse_mtl(&base_dir.join(name), &mut materials)?;
+                }
+            }
+            "f" => {
+                let b = match current {
+                    Some(b) => b,
+                    None => {
+                        let b = builders
+                            .iter()
+                            .position(|b| b.group == group && b.material == material)
+                            .unwrap_or_else(|| {
+                                builders.push(MeshBuilder::new(&group, &material));
+                                builders.len() - 1
+                            });
+                        current = Some(b);
+                        b
+                    }
+                };
+                face.clear();
+                for token in tokens {
+                    let mut parts = token.split('/');
+                    let v = loc.index(parts.next().unwrap(), obj.positions.len(), "vertex")?;
+                    let vt = match parts.next() {
+                        Some(t) if !t.is_empty() => {
+                            Some(loc.index(t, obj.uvs.len(), "texture coordinate")?)
+                        }
+                        _ => None,
+                    };
+                    let vn = match parts.next() {
+                        Some(n) if !n.is_empty() => {
+                            Some(loc.index(n, obj.normals.len(), "normal")?)
+                        }
+                        _ => None,
+                    };
+                    face.push(builders[b].vertex((v, vt, vn), &obj));
+                }
+                if face.len() < 3 {
+                    return Err(loc.error("face with fewer than 3 vertices"));
+                }
+                for k in 1..face.len() - 1 {
+                    builders[b].indices.push([face[0], face[k], face[k + 1]]);
+                }
+            }
+            // Smoothing groups, lines and points are ignored.
+            _ => {}
+        }
+    }
+
+    Ok(builders
+        .into_iter()
+        .filter(|b| !b.indices.is_empty())
+        .map(|b| {
+            let (mat, emissive) = materials
+                .get(&b.material)
+                .cloned()
+                .unwrap_or_else(|| (default_material.clone(), false));
+            ObjMesh {
+                group: b.group.clone(),
+                material: b.material.clone(),
+                emissive,
+                mesh: TriangleMesh::new(Arc::new(b.finish()), mat),
+            }
+        })
+        .collect())
+}
+
+pub fn load_obj<P: AsRef<Path>>(
+    path: P,
+    default_material: Arc<dyn Material>,
+) -> Result<Vec<ObjMesh>, ObjError> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| ObjError::Io(path.to_path_buf(), e))?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    parse_obj(BufReader::new(file), path, base_dir, default_material)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<Vec<ObjMesh>, ObjError> {
+        parse_obj(
+            source.as_bytes(),
+            Path::new("test.obj"),
+            Path::new("."),
+            lambertian(0.5, 0.5, 0.5),
+        )
+    }
+
+    #[test]
+    fn test_quads_and_groups() {
+        let meshes = parse(
+            "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+             g front\nf 1 2 3 4\n\
+             g back\nf -1 -2 -3\n",
+        )
+        .unwrap();
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes[0].group, "front");
+        assert_eq!(meshes[0].mesh.data.len(), 2);
+        assert_eq!(meshes[1].mesh.data.indices, vec![[0, 1, 2]]);
+        assert!(meshes[0].mesh.data.normals.is_empty());
+    }
+
+    #[test]
+    fn test_missing_normals_are_filled() {
+        let meshes = parse(
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 1 1 0\nvn 0 0 1\n\
+             f 1//1 2//1 3//1\nf 2 4 3\n",
+        )
+        .unwrap();
+        let data = &meshes[0].mesh.data;
+        assert_eq!(data.normals.len(), data.positions.len());
+        assert!(data.normals.iter().all(|n| (n.z - 1.0).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_errors() {
+        match parse("v 0 0 0\nv 1 0 0\nf 1 2 3\n") {
+            Err(ObjError::Parse { line, .. }) => assert_eq!(line, 3),
+            _ => panic!("expected an index error"),
+        }
+        assert!(parse("v 0 zero 0\n").is_err());
+        assert!(parse("usemtl steel\n").is_err());
+    }
+}
@@ -1,9 +1,8 @@
 use crate::aabb::*;
 use crate::geom::*;
 use crate::material::*;
-use rand::rngs::SmallRng;
 use rand::seq::SliceRandom;
-use rand::{Rng, SeedableRng};
+use rand::{Rng, RngCore};
 use std::ops::Range;
 use std::sync::Arc;
 
@@ -36,6 +35,9 @@ pub struct HitRecord {
     pub u: Float,
     pub v: Float,
     pub front_face: bool,
+    /// The index of the object hit in the outermost list or hierarchy
+    /// containing it.
+    pub object: u32,
 }
 
 impl HitRecord {
@@ -56,6 +58,7 @@ impl HitRecord {
             u,
             v,
             front_face,
+            object: 0,
         }
     }
 
@@ -82,6 +85,7 @@ impl HitRecord {
             u,
             v,
             front_face,
+            object: 0,
         }
     }
 
@@ -96,14 +100,20 @@ impl HitRecord {
 }
 
 pub trait Object: Send + Sync {
-    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord>;
+    fn hit(&self, rng: &mut dyn RngCore, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord>;
     fn bounding_box(&self, time_range: &Range<Float>) -> Option<Aabb>;
     fn pdf_value(&self, _o: Vec3, _v: Vec3) -> Float {
         panic!("The default implementaion of pdf_value should never be called.");
     }
-    fn random(&self, _rng: &mut SmallRng, _o: Vec3) -> Vec3 {
+    fn random(&self, _rng: &mut dyn RngCore, _o: Vec3) -> Vec3 {
         panic!("The default implementaion of random should never be called.");
     }
+    // A point on the surface at `time` picked independently of any viewpoint,
+    // a normal there and the density per area of picking it. Lights that
+    // cannot be sampled this way return `None`.
+    fn sample_area(&self, _rng: &mut dyn RngCore, _time: Float) -> Option<(Point3, Vec3, Float)> {
+        None
+    }
 }
 
 pub struct Objects {
@@ -125,8 +135,8 @@ impl Objects {
 }
 
 impl Object for Box<dyn Object> {
-    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
-        self.as_ref().hit(r, t_min, t_max)
+    fn hit(&self, rng: &mut dyn RngCore, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        self.as_ref().hit(rng, r, t_min, t_max)
     }
 
     fn bounding_box(&self, time_range: &Range<Float>) -> Option<Aabb> {
@@ -137,18 +147,48 @@ impl Object for Box<dyn Object> {
         (**self).pdf_value(o, v)
     }
 
-    fn random(&self, rng: &mut SmallRng, o: Vec3) -> Vec3 {
+    fn random(&self, rng: &mut dyn RngCore, o: Vec3) -> Vec3 {
         (**self).random(rng, o)
     }
+
+    fn sample_area(&self, rng: &mut dyn RngCore, time: Float) -> Option<(Point3, Vec3, Float)> {
+        (**self).sample_area(rng, time)
+    }
+}
+
+impl<T> Object for Arc<T>
+where
+    T: Object + ?Sized,
+{
+    fn hit(&self, rng: &mut dyn RngCore, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        (**self).hit(rng, r, t_min, t_max)
+    }
+
+    fn bounding_box(&self, time_range: &Range<Float>) -> Option<Aabb> {
+        (**self).bounding_box(time_range)
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> Float {
+        (**self).pdf_value(o, v)
+    }
+
+    fn random(&self, rng: &mut dyn RngCore, o: Vec3) -> Vec3 {
+        (**self).random(rng, o)
+    }
+
+    fn sample_area(&self, rng: &mut dyn RngCore, time: Float) -> Option<(Point3, Vec3, Float)> {
+        (**self).sample_area(rng, time)
+    }
 }
 
 impl Object for Objects {
-    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+    fn hit(&self, rng: &mut dyn RngCore, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
         let mut rec = None;
         let mut closest_so_far = t_max;
-        for object in &self.objects {
-            if let Some(new_rec) = object.hit(r, t_min, closest_so_far) {
+        for (i, object) in self.objects.iter().enumerate() {
+            if let Some(mut new_rec) = object.hit(rng, r, t_min, closest_so_far) {
                 closest_so_far = new_rec.t;
+                new_rec.object = i as u32;
                 rec = Some(new_rec);
             }
         }
@@ -156,15 +196,9 @@ impl Object for Objects {
     }
 
     fn bounding_box(&self, time_range: &Range<Float>) -> Option<Aabb> {
-        let aabb = self.objects.iter().fold(Some(Aabb::EMPTY), |mut acc, o| {
-            if let Some(b) = o.bounding_box(time_range) {
-                acc = Some(surrounding_box(acc.unwrap(), b));
-            } else {
-                acc = None;
-            };
-            acc
-        });
-        aabb
+        self.objects.iter().try_fold(Aabb::EMPTY, |acc, o| {
+            o.bounding_box(time_range).map(|b| surrounding_box(acc, b))
+        })
     }
 
     fn pdf_value(&self, o: Vec3, v: Vec3) -> Float {
@@ -176,15 +210,21 @@ impl Object for Objects {
             / self.objects.len() as f32
     }
 
-    fn random(&self, rng: &mut SmallRng, o: Vec3) -> Vec3 {
+    fn random(&self, rng: &mut dyn RngCore, o: Vec3) -> Vec3 {
         self.objects.choose(rng).unwrap().random(rng, o)
     }
+
+    // Objects are picked uniformly, as `random` does.
+    fn sample_area(&self, rng: &mut dyn RngCore, time: Float) -> Option<(Point3, Vec3, Float)> {
+        let (p, n, pdf) = self.objects.choose(rng)?.sample_area(rng, time)?;
+        Some((p, n, pdf / self.objects.len() as Float))
+    }
 }
 
 pub struct EmptyObject {}
 
 impl Object for EmptyObject {
-    fn hit(&self, _r: &Ray, _t_min: Float, _t_max: Float) -> Option<HitRecord> {
+    fn hit(&self, _rng: &mut dyn RngCore, _r: &Ray, _t_min: Float, _t_max: Float) -> Option<HitRecord> {
         None
     }
 
@@ -196,7 +236,7 @@ impl Object for EmptyObject {
         0.0
     }
 
-    fn random(&self, _rng: &mut SmallRng, _o: Vec3) -> Vec3 {
+    fn random(&self, _rng: &mut dyn RngCore, _o: Vec3) -> Vec3 {
         ZERO
     }
 }
@@ -221,8 +261,8 @@ impl<T> Object for FlipFace<T>
 where
     T: Object,
 {
-    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
-        if let Some(mut rec) = self.object.hit(r, t_min, t_max) {
+    fn hit(&self, rng: &mut dyn RngCore, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        if let Some(mut rec) = self.object.hit(rng, r, t_min, t_max) {
             rec.front_face = !rec.front_face;
             Some(rec)
         } else {
@@ -233,6 +273,19 @@ where
     fn bounding_box(&self, time_range: &Range<Float>) -> Option<Aabb> {
         self.object.bounding_box(time_range)
     }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> Float {
+        self.object.pdf_value(o, v)
+    }
+
+    fn random(&self, rng: &mut dyn RngCore, o: Vec3) -> Vec3 {
+        self.object.random(rng, o)
+    }
+
+    fn sample_area(&self, rng: &mut dyn RngCore, time: Float) -> Option<(Point3, Vec3, Float)> {
+        let (p, n, pdf) = self.object.sample_area(rng, time)?;
+        Some((p, -n, pdf))
+    }
 }
 
 impl<T> Translate<T> {
@@ -245,9 +298,9 @@ impl<T> Object for Translate<T>
 where
     T: Object,
 {
-    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+    fn hit(&self, rng: &mut dyn RngCore, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
         let moved_r = Ray::new(r.origin - self.offset, r.direction, r.time);
-        if let Some(mut rec) = self.object.hit(&moved_r, t_min, t_max) {
+        if let Some(mut rec) = self.object.hit(rng, &moved_r, t_min, t_max) {
             rec.p += self.offset;
             rec.set_face_normal(&moved_r, rec.normal);
             Some(rec)
@@ -257,14 +310,22 @@ where
     }
 
     fn bounding_box(&self, time_range: &Range<Float>) -> Option<Aabb> {
-        if let Some(bbox) = self.object.bounding_box(time_range) {
-            Some(Aabb::new(
-                bbox.box_min + self.offset,
-                bbox.box_max + self.offset,
-            ))
-        } else {
-            None
-        }
+        self.object
+            .bounding_box(time_range)
+            .map(|bbox| Aabb::new(bbox.box_min + self.offset, bbox.box_max + self.offset))
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> Float {
+        self.object.pdf_value(o - self.offset, v)
+    }
+
+    fn random(&self, rng: &mut dyn RngCore, o: Vec3) -> Vec3 {
+        self.object.random(rng, o - self.offset)
+    }
+
+    fn sample_area(&self, rng: &mut dyn RngCore, time: Float) -> Option<(Point3, Vec3, Float)> {
+        let (p, n, pdf) = self.object.sample_area(rng, time)?;
+        Some((p + self.offset, n, pdf))
     }
 }
 
@@ -302,7 +363,7 @@ where
                         tester[s] = coords[s];
                         for c in 0..3 {
                             rect.box_min[c] = rect.box_min[c].min(tester[c]);
-                            rect.box_max[c] = rect.box_max[c].min(tester[c]);
+                            rect.box_max[c] = rect.box_max[c].max(tester[c]);
                         }
                     }
                 }
@@ -319,36 +380,59 @@ where
     }
 }
 
+impl<T> Rotate<T> {
+    fn to_object(&self, v: Vec3) -> Vec3 {
+        let (p, q, _) = self.axis.order();
+        let mut r = v;
+        r[p] = self.cos * v[p] - self.sin * v[q];
+        r[q] = self.sin * v[p] + self.cos * v[q];
+        r
+    }
+
+    fn to_world(&self, v: Vec3) -> Vec3 {
+        let (p, q, _) = self.axis.order();
+        let mut r = v;
+        r[p] = self.cos * v[p] + self.sin * v[q];
+        r[q] = -self.sin * v[p] + self.cos * v[q];
+        r
+    }
+}
+
 impl<T> Object for Rotate<T>
 where
     T: Object,
 {
-    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
-        let mut origin = r.origin;
-        let mut direction = r.direction;
-        let (p, q, _) = self.axis.order();
-        origin[p] = self.cos * r.origin[p] - self.sin * r.origin[q];
-        origin[q] = self.sin * r.origin[p] + self.cos * r.origin[q];
-        direction[p] = self.cos * r.direction[p] - self.sin * r.direction[q];
-        direction[q] = self.sin * r.direction[p] + self.cos * r.direction[q];
-        let rotated_r = Ray::new(origin, direction, r.time);
-        let hr = self.object.hit(&rotated_r, t_min, t_max).map(|mut rec| {
-            let mut pt = rec.p;
-            let mut normal = rec.normal;
-            pt[p] = self.cos * rec.p[p] + self.sin * rec.p[q];
-            pt[q] = -self.sin * rec.p[p] + self.cos * rec.p[q];
-            normal[p] = self.cos * rec.normal[p] + self.sin * rec.normal[q];
-            normal[q] = -self.sin * rec.normal[p] + self.cos * rec.normal[q];
-            rec.p = pt;
-            rec.set_face_normal(&rotated_r, normal);
-            rec
-        });
-        hr
+    fn hit(&self, rng: &mut dyn RngCore, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let rotated_r = Ray::new(
+            self.to_object(r.origin),
+            self.to_object(r.direction),
+            r.time,
+        );
+        self.object
+            .hit(rng, &rotated_r, t_min, t_max)
+            .map(|mut rec| {
+                rec.p = self.to_world(rec.p);
+                rec.set_face_normal(&rotated_r, self.to_world(rec.normal));
+                rec
+            })
     }
 
     fn bounding_box(&self, _time_range: &Range<Float>) -> Option<Aabb> {
         self.bbox
     }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> Float {
+        self.object.pdf_value(self.to_object(o), self.to_object(v))
+    }
+
+    fn random(&self, rng: &mut dyn RngCore, o: Vec3) -> Vec3 {
+        self.to_world(self.object.random(rng, self.to_object(o)))
+    }
+
+    fn sample_area(&self, rng: &mut dyn RngCore, time: Float) -> Option<(Point3, Vec3, Float)> {
+        let (p, n, pdf) = self.object.sample_area(rng, time)?;
+        Some((self.to_world(p), self.to_world(n), pdf))
+    }
 }
 
 pub struct ConstantMedium<O> {
@@ -371,10 +455,9 @@ impl<O> Object for ConstantMedium<O>
 where
     O: Object,
 {
-    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
-        let mut rng = SmallRng::from_entropy();
-        let mut rec1 = self.boundary.hit(r, Float::MIN, Float::MAX)?;
-        let mut rec2 = self.boundary.hit(r, rec1.t + 0.0001, Float::MAX)?;
+    fn hit(&self, rng: &mut dyn RngCore, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let mut rec1 = self.boundary.hit(rng, r, Float::MIN, Float::MAX)?;
+        let mut rec2 = self.boundary.hit(rng, r, rec1.t + 0.0001, Float::MAX)?;
         rec1.t = rec1.t.max(t_min);
         rec2.t = rec2.t.min(t_max);
         if rec1.t >= rec2.t {
@@ -1,12 +1,11 @@
 use crate::geom::*;
 use crate::object::Object;
-use rand::Rng;
-use rand::rngs::SmallRng;
+use rand::{Rng, RngCore};
 use std::sync::Arc;
 
 pub trait Pdf {
     fn value(&self, direction: Vec3) -> Float;
-    fn generate(&self, rng: &mut SmallRng) -> Vec3;
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3;
 }
 
 pub struct CosinePdf {
@@ -35,11 +34,24 @@ impl Pdf for CosinePdf {
         }
     }
 
-    fn generate(&self, rng: &mut SmallRng) -> Vec3 {
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3 {
         self.uvw.local(random_cosine_direction(rng))
     }
 }
 
+/// Uniform over all directions.
+pub struct SpherePdf;
+
+impl Pdf for SpherePdf {
+    fn value(&self, _direction: Vec3) -> Float {
+        1.0 / (4.0 * PI)
+    }
+
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3 {
+        random_unit_vector(rng)
+    }
+}
+
 pub struct ObjectPdf<T>
 where
     T: ?Sized,
@@ -65,7 +77,7 @@ where
         self.object.pdf_value(self.o, direction)
     }
 
-    fn generate(&self, rng: &mut SmallRng) -> Vec3 {
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3 {
         self.object.random(rng, self.o)
     }
 }
@@ -86,7 +98,7 @@ impl Pdf for MixturePdf {
         0.5 * self.p0.value(direction) + 0.5 * self.p1.value(direction)
     }
 
-    fn generate(&self, rng: &mut SmallRng) -> Vec3 {
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3 {
         let rd = rng.gen_bool(0.5);
         if rd {
             self.p0.generate(rng)
@@ -94,4 +106,4 @@ impl Pdf for MixturePdf {
             self.p1.generate(rng)
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,554 @@
+use crate::bdpt::{emits, flipped};
+use crate::film::{Film, FilmPixel};
+use crate::geom::*;
+use crate::material::Reflection;
+use crate::object::{HitRecord, Object, Ray};
+use crate::pdf::{CosinePdf, Pdf};
+use crate::render::{
+    camera_ray, sample_rng, target_samples, PathStats, Progress, BOUNCE_DIMENSIONS,
+};
+use crate::sampler::SampleStream;
+use crate::scenes::Environment;
+use rand::{Rng, RngCore};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// The share of the photons newly found at a visible point that its estimate
+// keeps when the radius shrinks.
+const ALPHA: Float = 2.0 / 3.0;
+
+// Photons a thread traces each time it takes more.
+const PHOTON_BATCH: usize = 4096;
+
+// Where a camera path first reaches a diffuse surface.
+struct VisiblePoint {
+    rec: HitRecord,
+    r_in: Ray,
+    attenuation: Color,
+    // The throughput of the camera path up to the point.
+    beta: Color,
+}
+
+impl VisiblePoint {
+    // The BSDF for light arriving travelling along `direction`. The cosine the
+    // scattering pdf includes is left out, as the density of the photons
+    // landing already falls with it.
+    fn f(&self, direction: Vec3) -> Color {
+        let from = Ray::new(self.rec.p, -direction, self.r_in.time);
+        let cos = dot(self.rec.normal, from.direction.normalize());
+        if cos <= 0.0 {
+            return BLACK;
+        }
+        self.attenuation
+            * self
+                .rec
+                .material
+                .scattering_pdf(&self.r_in, &self.rec, &from)
+            / cos
+    }
+}
+
+// The estimate of one pixel, refined every pass.
+struct PixelEstimate {
+    // Radiance the camera paths picked up without photons, summed over the
+    // passes.
+    direct: Color,
+    radius: Float,
+    // The number of photons the estimate is made of, fractional as only a
+    // share of each pass's is kept.
+    photons: Float,
+    // The flux those photons carried.
+    flux: Color,
+    point: Option<VisiblePoint>,
+}
+
+// The visible points listed in every cell of a uniform grid that their search
+// disk overlaps. Cells are twice the largest radius wide, so a disk overlaps
+// at most eight.
+struct HashGrid {
+    cell: Float,
+    cells: HashMap<[i32; 3], Vec<usize>>,
+}
+
+impl HashGrid {
+    fn new(estimates: &[PixelEstimate]) -> Self {
+        let cell = 2.0
+            * estimates
+                .iter()
+                .filter(|e| e.point.is_some())
+                .map(|e| e.radius)
+                .fold(0.0, Float::max);
+        let mut grid = Self {
+            cell,
+            cells: HashMap::new(),
+        };
+        if cell <= 0.0 {
+            return grid;
+        }
+        for (index, e) in estimates.iter().enumerate() {
+            let Some(point) = &e.point else {
+                continue;
+            };
+            let r = vec3(e.radius, e.radius, e.radius);
+            let (lo, hi) = (grid.cell_of(point.rec.p - r), grid.cell_of(point.rec.p + r));
+            for x in lo[0]..=hi[0] {
+                for y in lo[1]..=hi[1] {
+                    for z in lo[2]..=hi[2] {
+                        grid.cells.entry([x, y, z]).or_default().push(index);
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    fn cell_of(&self, p: Point3) -> [i32; 3] {
+        [0u8, 1, 2].map(|axis| (p[axis] / self.cell).floor() as i32)
+    }
+
+    // The visible points whose disk may contain `p`.
+    fn near(&self, p: Point3) -> &[usize] {
+        if self.cells.is_empty() {
+            return &[];
+        }
+        self.cells.get(&self.cell_of(p)).map_or(&[], |v| v)
+    }
+}
+
+// The flux gathered at every visible point in one pass, and the photon count.
+struct Gathered {
+    flux: Vec<Color>,
+    photons: Vec<u32>,
+}
+
+impl Gathered {
+    fn new(n: usize) -> Self {
+        Self {
+            flux: vec![BLACK; n],
+            photons: vec![0; n],
+        }
+    }
+
+    fn merge(&mut self, other: &Gathered) {
+        for (a, b) in self.flux.iter_mut().zip(&other.flux) {
+            *a += *b;
+        }
+        for (a, b) in self.photons.iter_mut().zip(&other.photons) {
+            *a += b;
+        }
+    }
+}
+
+// Light reaching `rec` straight from the lights, by sampling them, and from
+// the background, by sampling the BSDF. Photons bring the rest.
+#[allow(clippy::too_many_arguments)]
+fn direct_light(
+    rng: &mut SampleStream,
+    r_in: &Ray,
+    rec: &HitRecord,
+    attenuation: Color,
+    bsdf: &dyn Pdf,
+    environment: &Environment,
+    stats: &mut PathStats,
+) -> Color {
+    let world = &*environment.scene;
+    let lights = &*environment.lights;
+    let mut radiance = BLACK;
+    let direction = lights.random(rng, rec.p);
+    let pdf = lights.pdf_value(rec.p, direction);
+    if pdf > 0.0 && pdf.is_finite() {
+        stats.rays += 1;
+        let shadow = Ray::new(rec.p, direction, r_in.time);
+        if let Some(light) = world.hit(rng, &shadow, 0.001, INFINITY) {
+            let f = attenuation * rec.material.scattering_pdf(r_in, rec, &shadow);
+            let le = light
+                .material
+                .color_emitted(&light, light.u, light.v, light.p);
+            radiance += f * le / pdf;
+        }
+    }
+    let background = environment.background();
+    if background != BLACK {
+        let ray = Ray::new(rec.p, bsdf.generate(rng), r_in.time);
+        let pdf = bsdf.value(ray.direction);
+        stats.rays += 1;
+        if pdf > 0.0 && world.hit(rng, &ray, 0.001, INFINITY).is_none() {
+            let f = attenuation * rec.material.scattering_pdf(r_in, rec, &ray);
+            radiance += f * background / pdf;
+        }
+    }
+    radiance
+}
+
+// Follow the camera ray `r` through specular bounces and volumes to the first
+// diffuse surface. Returns the radiance picked up on the way, with the direct
+// light at the surface, and the surface as a visible point.
+fn trace_camera(
+    rng: &mut SampleStream,
+    r: &Ray,
+    environment: &Environment,
+    stats: &mut PathStats,
+) -> (Color, Option<VisiblePoint>) {
+    let mut radiance = BLACK;
+    let mut beta = WHITE;
+    let mut ray = *r;
+    for depth in 0..environment.max_depth() {
+        stats.rays += 1;
+        PathStats::count(&mut stats.depths, depth);
+        rng.next_block(BOUNCE_DIMENSIONS);
+        let Some(rec) = environment.scene.hit(rng, &ray, 0.001, INFINITY) else {
+            radiance += beta * environment.background();
+            break;
+        };
+        radiance += beta * rec.material.color_emitted(&rec, rec.u, rec.v, rec.p);
+        let Some(scatter) = rec.material.scatter(rng, &ray, &rec) else {
+            break;
+        };
+        match scatter.reflection {
+            Reflection::Specular(next) => {
+                beta = beta * scatter.attenuation;
+                ray = next;
+            }
+            Reflection::Scatter(pdf) if rec.material.is_volume() => {
+                let next = Ray::new(rec.p, pdf.generate(rng), ray.time);
+                let pdf_val = pdf.value(next.direction);
+                if pdf_val <= 0.0 {
+                    break;
+                }
+                beta = beta * scatter.attenuation * rec.material.scattering_pdf(&ray, &rec, &next)
+                    / pdf_val;
+                ray = next;
+            }
+            Reflection::Scatter(pdf) => {
+                let attenuation = scatter.attenuation;
+                radiance +=
+                    beta * direct_light(rng, &ray, &rec, attenuation, &*pdf, environment, stats);
+                let point = VisiblePoint {
+                    rec,
+                    r_in: ray,
+                    attenuation,
+                    beta,
+                };
+                return (radiance, Some(point));
+            }
+        }
+    }
+    (radiance, None)
+}
+
+// Where photons start: on the lights, at the background or either, with the
+// chance of the background.
+struct Emitter {
+    has_lights: bool,
+    // The scene's bounding sphere, when the background lights it.
+    sky: Option<(Point3, Float)>,
+}
+
+impl Emitter {
+    fn new(environment: &Environment) -> Self {
+        let has_lights = environment
+            .lights
+            .bounding_box(&(0.0..1.0))
+            .is_some_and(|b| b.box_min.x <= b.box_max.x);
+        let sky = environment
+            .scene
+            .bounding_box(&(0.0..1.0))
+            .filter(|_| environment.background() != BLACK)
+            .map(|b| {
+                let center = 0.5 * (b.box_min + b.box_max);
+                (center, dist(center, b.box_max))
+            });
+        Self { has_lights, sky }
+    }
+
+    fn sky_chance(&self) -> Float {
+        match (self.has_lights, self.sky) {
+            (_, None) => 0.0,
+            (false, Some(_)) => 1.0,
+            (true, Some(_)) => 0.5,
+        }
+    }
+
+    // A photon's first ray and the power it carries. Photons from the lights
+    // leave a point sampled on them in a cosine weighted direction, and
+    // photons from the background enter the scene's bounding sphere from a
+    // uniformly random direction through a point on the disk facing it.
+    fn emit(
+        &self,
+        rng: &mut dyn RngCore,
+        environment: &Environment,
+        stats: &mut PathStats,
+    ) -> Option<(Ray, Color)> {
+        let time = environment.camera.sample_time(rng);
+        let sky_chance = self.sky_chance();
+        match self.sky {
+            Some((center, radius)) if rng.gen::<Float>() < sky_chance => {
+                let w = random_unit_vector(rng);
+                let disk = random_in_unit_disk(rng);
+                let offset = Onb::build_from_w(w).local(vec3(disk.x, disk.y, 0.0));
+                let origin = center + radius * (w + offset);
+                let power = environment.background() * (4.0 * PI * PI * radius * radius);
+                Some((Ray::new(origin, -w, time), power / sky_chance))
+            }
+            _ if self.has_lights => {
+                let (rec, pdf) = sample_light_point(rng, environment, time, stats)?;
+                let direction = CosinePdf::with_w(rec.normal).generate(rng);
+                let le = rec.material.color_emitted(&rec, rec.u, rec.v, rec.p);
+                let power = le * PI / (pdf * (1.0 - sky_chance));
+                Some((Ray::new(rec.p, direction, time), power))
+            }
+            _ => None,
+        }
+    }
+}
+
+// A point picked by area on `lights`, wherever the camera is, and its density
+// per area. The record at the point comes from the scene, found with a short
+// ray along the normal, on the side that emits.
+fn sample_light_point(
+    rng: &mut dyn RngCore,
+    environment: &Environment,
+    time: Float,
+    stats: &mut PathStats,
+) -> Option<(HitRecord, Float)> {
+    let (p, n, pdf) = environment.lights.sample_area(rng, time)?;
+    if pdf <= 0.0 || !pdf.is_finite() {
+        return None;
+    }
+    let eps = 1e-4 * (1.0 + p.length());
+    stats.rays += 1;
+    let ray = Ray::new(p + eps * n, -n, time);
+    let rec = environment.scene.hit(rng, &ray, 0.0, 2.0 * eps)?;
+    let rec = if emits(&rec) { rec } else { flipped(&rec) };
+    emits(&rec).then_some((rec, pdf))
+}
+
+// Follow a photon carrying `power` along `ray` and add it to the visible
+// points around every diffuse surface it reaches after the first, where the
+// camera paths already sampled the light directly.
+#[allow(clippy::too_many_arguments)]
+fn trace_photon(
+    rng: &mut dyn RngCore,
+    environment: &Environment,
+    mut ray: Ray,
+    mut power: Color,
+    grid: &HashGrid,
+    estimates: &[PixelEstimate],
+    gathered: &mut Gathered,
+    stats: &mut PathStats,
+) {
+    let brightest = |c: Color| c.x.max(c.y).max(c.z);
+    for depth in 0..environment.max_depth() {
+        stats.rays += 1;
+        let Some(rec) = environment.scene.hit(rng, &ray, 0.001, INFINITY) else {
+            break;
+        };
+        let Some(scatter) = rec.material.scatter(rng, &ray, &rec) else {
+            break;
+        };
+        let before = brightest(power);
+        ray = match scatter.reflection {
+            Reflection::Specular(next) => {
+                power = power * scatter.attenuation;
+                next
+            }
+            Reflection::Scatter(pdf) => {
+                if depth > 0 && !rec.material.is_volume() {
+                    for &index in grid.near(rec.p) {
+                        let e = &estimates[index];
+                        let point = e.point.as_ref().unwrap();
+                        if dist2(point.rec.p, rec.p) <= e.radius * e.radius
+                            && dot(point.rec.normal, rec.normal) > 0.0
+                        {
+                            gathered.flux[index] += power * point.f(ray.direction);
+                            gathered.photons[index] += 1;
+                        }
+                    }
+                }
+                let next = Ray::new(rec.p, pdf.generate(rng), ray.time);
+                let pdf_val = pdf.value(next.direction);
+                if pdf_val <= 0.0 {
+                    break;
+                }
+                power =
+                    power * scatter.attenuation * rec.material.scattering_pdf(&ray, &rec, &next)
+                        / pdf_val;
+                next
+            }
+        };
+        // Survive with the share of the power the bounce kept.
+        if depth + 1 >= environment.params.rr_depth {
+            let survival = if before > 0.0 {
+                (brightest(power) / before).min(1.0)
+            } else {
+                0.0
+            };
+            if rng.gen::<Float>() >= survival {
+                break;
+            }
+            power /= survival;
+        }
+    }
+}
+
+// The initial search radius: a few pixels' worth of the scene's size.
+fn initial_radius(environment: &Environment) -> Float {
+    let params = &environment.params;
+    if params.photon_radius > 0.0 {
+        return params.photon_radius;
+    }
+    let size = environment
+        .scene
+        .bounding_box(&(0.0..1.0))
+        .map_or(1.0, |b| dist(b.box_min, b.box_max));
+    2.0 * size / params.width.max(params.height) as Float
+}
+
+// The estimate of every pixel after `passes` passes of `photons` photons.
+fn write_film(film: &mut Film, estimates: &[PixelEstimate], passes: u32, photons: usize) {
+    let (n, total) = (passes as Float, passes as Float * photons as Float);
+    for (index, e) in estimates.iter().enumerate() {
+        let gathered = e.flux / (total * PI * e.radius * e.radius);
+        let c = (e.direct / n + gathered).map(|v| if v.is_nan() { 0.0 } else { v });
+        let (x, y) = (index as u32 % film.width(), index as u32 / film.width());
+        *film.pixel_mut(x, y) = FilmPixel {
+            sum: c * n,
+            sum_sq: c * c * n,
+            count: passes,
+            ..FilmPixel::EMPTY
+        };
+    }
+}
+
+/// Render `film` with stochastic progressive photon mapping. Every pass
+/// traces one camera path per pixel through specular bounces to a diffuse
+/// surface, where it samples the light arriving directly, and then
+/// `photons_per_pass` photons from `lights` and the background. The photons
+/// landing within a radius of a pixel's surface point add to its estimate of
+/// the indirect light, and the radius shrinks as they do so the estimate
+/// converges. Visible points are found through a hash grid.
+///
+/// A pass counts as one sample of every pixel, and passes run until every
+/// pixel has `target_samples`. The film is written after each and passed to
+/// `pass_done`. As the radii are not saved, a film part way through is
+/// rendered over from the start.
+///
+/// Returns the statistics of the camera paths and photons traced.
+pub fn render_photon_map(
+    environment: &Environment,
+    film: &mut Film,
+    progress: &(dyn Fn(&Progress) + Sync),
+    pass_done: &mut dyn FnMut(&Film),
+) -> PathStats {
+    let (w, h) = (environment.width(), environment.height());
+    assert_eq!((film.width(), film.height()), (w, h));
+    let params = &environment.params;
+    let passes = target_samples(environment);
+    let mut stats = PathStats::default();
+    if film.pixels().iter().all(|p| p.count >= passes) {
+        return stats;
+    }
+    let sampler = params.sampler.build(params.seed, passes);
+    let photons = match params.photons_per_pass {
+        0 => (w * h) as usize,
+        n => n as usize,
+    };
+    let emitter = Emitter::new(environment);
+    let radius = initial_radius(environment);
+    let mut estimates: Vec<PixelEstimate> = (0..w * h)
+        .map(|_| PixelEstimate {
+            direct: BLACK,
+            radius,
+            photons: 0.0,
+            flux: BLACK,
+            point: None,
+        })
+        .collect();
+    let start = Instant::now();
+    for pass in 0..passes {
+        let camera_stats = estimates
+            .par_iter_mut()
+            .enumerate()
+            .map(|(index, e)| {
+                let (i, j) = (index as u32 % w, h - 1 - index as u32 / w);
+                let rng = sample_rng(params.seed, j as u64 * w as u64 + i as u64, pass as u64);
+                let mut rng = SampleStream::new(&*sampler, i, j, pass, rng);
+                let mut stats = PathStats::default();
+                let r = camera_ray(&mut rng, environment, i, j);
+                let (radiance, point) = trace_camera(&mut rng, &r, environment, &mut stats);
+                e.direct += radiance;
+                e.point = point;
+                stats
+            })
+            .reduce(PathStats::default, |mut a, b| {
+                a.merge(&b);
+                a
+            });
+        stats.merge(&camera_stats);
+
+        let grid = HashGrid::new(&estimates);
+        let next = AtomicUsize::new(0);
+        let state = Mutex::new((Gathered::new(estimates.len()), PathStats::default()));
+        (0..rayon::current_num_threads())
+            .into_par_iter()
+            .for_each(|_| {
+                let mut gathered = Gathered::new(estimates.len());
+                let mut stats = PathStats::default();
+                loop {
+                    let first = next.fetch_add(PHOTON_BATCH, Ordering::Relaxed);
+                    if first >= photons {
+                        break;
+                    }
+                    for k in first..(first + PHOTON_BATCH).min(photons) {
+                        let mut rng = sample_rng(!params.seed, k as u64, pass as u64);
+                        if let Some((ray, power)) = emitter.emit(&mut rng, environment, &mut stats)
+                        {
+                            trace_photon(
+                                &mut rng,
+                                environment,
+                                ray,
+                                power,
+                                &grid,
+                                &estimates,
+                                &mut gathered,
+                                &mut stats,
+                            );
+                        }
+                    }
+                }
+                let mut state = state.lock().unwrap();
+                state.0.merge(&gathered);
+                state.1.merge(&stats);
+            });
+        let (gathered, photon_stats) = state.into_inner().unwrap();
+        stats.merge(&photon_stats);
+
+        estimates
+            .par_iter_mut()
+            .zip(gathered.flux.par_iter().zip(&gathered.photons))
+            .for_each(|(e, (&flux, &m))| {
+                if let (Some(point), true) = (e.point.take(), m > 0) {
+                    let m = m as Float;
+                    let photons = e.photons + ALPHA * m;
+                    let radius = e.radius * (photons / (e.photons + m)).sqrt();
+                    e.flux =
+                        (e.flux + point.beta * flux) * (radius * radius / (e.radius * e.radius));
+                    e.photons = photons;
+                    e.radius = radius;
+                }
+            });
+
+        write_film(film, &estimates, pass + 1, photons);
+        progress(&Progress {
+            tiles_done: pass as usize + 1,
+            tiles_total: passes as usize,
+            rays_traced: stats.rays,
+            elapsed: start.elapsed(),
+        });
+        pass_done(film);
+    }
+    stats
+}
@@ -1,5 +1,4 @@
-use rand::rngs::SmallRng;
-use rand::Rng;
+use rand::{Rng, RngCore};
 
 use crate::aabb::*;
 use crate::geom::*;
@@ -40,8 +39,9 @@ impl Rect {
     }
 }
 
-impl Object for Rect {
-    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+impl Rect {
+    // The distance and in plane coordinates where the ray crosses the rectangle.
+    fn intersect(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<(Float, Float, Float)> {
         let (p, q, s) = self.axis.order();
         let t = (self.k - r.origin[s]) / r.direction[s];
         if t < t_min || t > t_max {
@@ -52,6 +52,13 @@ impl Object for Rect {
         if x < self.p0 || x > self.p1 || y < self.q0 || y > self.q1 {
             return None;
         };
+        Some((t, x, y))
+    }
+}
+
+impl Object for Rect {
+    fn hit(&self, _rng: &mut dyn RngCore, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let (t, x, y) = self.intersect(r, t_min, t_max)?;
         let u = (x - self.p0) / (self.p1 - self.p0);
         let v = (y - self.q0) / (self.q1 - self.q0);
         let pt = r.at(t);
@@ -78,16 +85,17 @@ impl Object for Rect {
     }
 
     fn pdf_value(&self, o: Vec3, v: Vec3) -> Float {
-        if let Some(rec) = self.hit(&Ray::new(o, v, 0.0), 0.001, std::f32::MAX) {
+        if let Some((t, _, _)) = self.intersect(&Ray::new(o, v, 0.0), 0.001, Float::MAX) {
+            let (_, _, s) = self.axis.order();
             let area = (self.p1 - self.p0) * (self.q1 - self.q0);
-            let distance_squared = rec.t * rec.t * v.length2();
-            let cosine = (dot(v, rec.normal) / v.length()).abs();
-            return distance_squared / (cosine * area)
-        } 
+            let distance_squared = t * t * v.length2();
+            let cosine = (v[s] / v.length()).abs();
+            return distance_squared / (cosine * area);
+        }
         0.0
     }
 
-    fn random(&self, rng: &mut SmallRng, o: Vec3) -> Vec3 {
+    fn random(&self, rng: &mut dyn RngCore, o: Vec3) -> Vec3 {
         let (p, q, s) = self.axis.order();
         let pr = rng.gen_range(self.p0..self.p1);
         let qr = rng.gen_range(self.q0..self.q1);
@@ -97,6 +105,18 @@ impl Object for Rect {
         random_point[s] = self.k;
         random_point - o
     }
+
+    fn sample_area(&self, rng: &mut dyn RngCore, _time: Float) -> Option<(Point3, Vec3, Float)> {
+        let (p, q, s) = self.axis.order();
+        let mut point = ZERO;
+        point[p] = rng.gen_range(self.p0..self.p1);
+        point[q] = rng.gen_range(self.q0..self.q1);
+        point[s] = self.k;
+        let mut normal = ZERO;
+        normal[s] = 1.0;
+        let area = (self.p1 - self.p0) * (self.q1 - self.q0);
+        Some((point, normal, 1.0 / area))
+    }
 }
 
 pub struct Cuboid {
@@ -174,11 +194,23 @@ impl Cuboid {
 }
 
 impl Object for Cuboid {
-    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
-        self.sides.hit(r, t_min, t_max)
+    fn hit(&self, rng: &mut dyn RngCore, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        self.sides.hit(rng, r, t_min, t_max)
     }
 
     fn bounding_box(&self, _time_range: &std::ops::Range<Float>) -> Option<Aabb> {
         Some(Aabb::new(self.box_min, self.box_max))
     }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> Float {
+        self.sides.pdf_value(o, v)
+    }
+
+    fn random(&self, rng: &mut dyn RngCore, o: Vec3) -> Vec3 {
+        self.sides.random(rng, o)
+    }
+
+    fn sample_area(&self, rng: &mut dyn RngCore, time: Float) -> Option<(Point3, Vec3, Float)> {
+        self.sides.sample_area(rng, time)
+    }
 }
@@ -1,116 +1,1008 @@
+use crate::aov::AovSample;
+use crate::denoise::denoise;
+use crate::film::{Film, FilmPixel};
+use crate::filter::Filter;
 use crate::geom::*;
+use crate::integrator::{Integrator, IntegratorKind};
 use crate::material::Reflection;
-use crate::object::{Object, Ray, EmptyObject};
+use crate::mlt::render_mlt;
+use crate::object::{HitRecord, Object, Ray};
 use crate::pdf::*;
-use crate::scenes::Environment;
+use crate::photon::render_photon_map;
+use crate::sampler::{splitmix64, SampleStream, Sampler};
+use crate::scenes::{Environment, RenderParams};
+use crate::tile::{tiles, Tile};
 use rand::rngs::SmallRng;
-use rand::{thread_rng, Rng, SeedableRng};
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+/// The power heuristic with exponent 2, the weight of a sample taken with
+/// density `a` when it could also have been taken with density `b`.
+pub fn power_heuristic(a: Float, b: Float) -> Float {
+    let (a2, b2) = (a * a, b * b);
+    if a2 + b2 > 0.0 {
+        a2 / (a2 + b2)
+    } else {
+        0.0
+    }
+}
+
+/// What the paths of a render did, counted per depth with the camera ray at
+/// depth 0.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PathStats {
+    /// Camera, bounce and shadow rays traced.
+    pub rays: u64,
+    /// Paths that traced a ray at each depth.
+    pub depths: Vec<u64>,
+    /// Paths Russian roulette ended after each depth.
+    pub terminated: Vec<u64>,
+}
+
+impl PathStats {
+    pub(crate) fn count(counts: &mut Vec<u64>, depth: u32) {
+        let depth = depth as usize;
+        if counts.len() <= depth {
+            counts.resize(depth + 1, 0);
+        }
+        counts[depth] += 1;
+    }
+
+    pub fn merge(&mut self, other: &PathStats) {
+        self.rays += other.rays;
+        for (mine, theirs) in [
+            (&mut self.depths, &other.depths),
+            (&mut self.terminated, &other.terminated),
+        ] {
+            if mine.len() < theirs.len() {
+                mine.resize(theirs.len(), 0);
+            }
+            for (a, b) in mine.iter_mut().zip(theirs) {
+                *a += b;
+            }
+        }
+    }
+
+    /// The mean number of rays a path traced, not counting shadow rays.
+    pub fn mean_length(&self) -> Float {
+        match self.depths.first() {
+            Some(&paths) if paths > 0 => self.depths.iter().sum::<u64>() as Float / paths as Float,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Radiance arriving along `r`, following one path through the scene with its
+/// throughput, the product of the BSDF over the density of every bounce so
+/// far. Non-specular bounces sample a light for a shadow ray and the BSDF for
+/// the next ray, and weight both with the power heuristic. Hits on lights
+/// after camera rays and specular bounces count in full.
+///
+/// Paths end when they escape, at `max_depth` rays, or by Russian roulette
+/// after `rr_depth` bounces, which survives with probability the throughput's
+/// largest channel and scales the survivors up to stay unbiased.
 pub fn ray_color(
-    rng: &mut SmallRng,
+    rng: &mut SampleStream,
     r: &Ray,
-    background: Color,
-    world: &impl Object,
-    lights: Arc<dyn Object>,
-    depth: u32,
+    environment: &Environment,
+    stats: &mut PathStats,
 ) -> Color {
-    // let mut rng = SmallRng::from_rng(thread_rng()).unwrap();
-    if depth == 0 {
-        return BLACK;
-    }
-    if let Some(rec) = world.hit(r, 0.001, INFINITY) {
+    trace_path(rng, r, environment, stats, None)
+}
+
+/// `ray_color`, also adding the AOVs `aovs` asks for.
+pub fn ray_color_aovs(
+    rng: &mut SampleStream,
+    r: &Ray,
+    environment: &Environment,
+    stats: &mut PathStats,
+    aovs: &mut AovSample,
+) -> Color {
+    trace_path(rng, r, environment, stats, Some(aovs))
+}
+
+fn trace_path(
+    rng: &mut SampleStream,
+    r: &Ray,
+    environment: &Environment,
+    stats: &mut PathStats,
+    mut aovs: Option<&mut AovSample>,
+) -> Color {
+    let world = &*environment.scene;
+    let lights = &*environment.lights;
+    let mut radiance = BLACK;
+    let mut throughput = WHITE;
+    let mut ray = *r;
+    // The density with which the last bounce sampled `ray`, None when the
+    // bounce was specular or `ray` is the camera ray.
+    let mut bsdf_pdf: Option<Float> = None;
+    let max_depth = environment.max_depth();
+    for depth in 0..max_depth {
+        stats.rays += 1;
+        PathStats::count(&mut stats.depths, depth);
+        rng.next_block(BOUNCE_DIMENSIONS);
+        let Some(rec) = world.hit(rng, &ray, 0.001, INFINITY) else {
+            let background = environment.background();
+            radiance += throughput * background;
+            if let Some(aovs) = aovs.as_deref_mut() {
+                if depth == 0 {
+                    aovs.add_miss(background);
+                }
+                aovs.add_light(throughput * background, depth, None);
+            }
+            break;
+        };
         let emitted = rec.material.color_emitted(&rec, rec.u, rec.v, rec.p);
-        if let Some(scatter_rec) = rec.material.scatter(r, &rec) {
-            match scatter_rec.reflection {
-                Reflection::Scatter(pdf1) => {
-                    let pdf0 = Arc::new(ObjectPdf::new(lights.clone(), rec.p));
-                    let mixture_pdf = MixturePdf::new(pdf0, pdf1);
-                    let scattered = Ray::new(rec.p, mixture_pdf.generate(rng), r.time);
-                    let pdf_val = mixture_pdf.value(scattered.direction);
-                    emitted
-                        + scatter_rec.attenuation
-                            * rec.material.scattering_pdf(r, &rec, &scattered)
-                            * ray_color(rng, &scattered, background, world, lights, depth - 1)
-                            / pdf_val
+        let mut weighted = emitted;
+        if let Some(bsdf_pdf) = bsdf_pdf {
+            if emitted != BLACK {
+                let light_pdf = lights.pdf_value(ray.origin, ray.direction);
+                weighted = power_heuristic(bsdf_pdf, light_pdf) * emitted;
+            }
+        }
+        radiance += throughput * weighted;
+        let scatter = rec.material.scatter(rng, &ray, &rec);
+        if let Some(aovs) = aovs.as_deref_mut() {
+            if depth == 0 {
+                let albedo = scatter.as_ref().map_or(emitted, |s| s.attenuation);
+                aovs.add_surface(&ray, &rec, albedo);
+            }
+            if weighted != BLACK {
+                aovs.add_light(throughput * weighted, depth, Some(rec.object));
+            }
+        }
+        let Some(scatter_rec) = scatter else {
+            break;
+        };
+        match scatter_rec.reflection {
+            Reflection::Specular(next) => {
+                throughput = throughput * scatter_rec.attenuation;
+                ray = next;
+                bsdf_pdf = None;
+            }
+            Reflection::Scatter(pdf) => {
+                let (direct, light) = sample_light(
+                    rng,
+                    &ray,
+                    &rec,
+                    scatter_rec.attenuation,
+                    &*pdf,
+                    world,
+                    lights,
+                    stats,
+                );
+                radiance += throughput * direct;
+                if let Some(aovs) = aovs.as_deref_mut() {
+                    if direct != BLACK {
+                        aovs.add_light(throughput * direct, depth + 1, Some(light));
+                    }
                 }
-                Reflection::Specular(ray) => {
-                    scatter_rec.attenuation
-                        * ray_color(rng, &ray, background, world, lights, depth - 1)
+                let scattered = Ray::new(rec.p, pdf.generate(rng), ray.time);
+                let pdf_val = pdf.value(scattered.direction);
+                if pdf_val <= 0.0 {
+                    break;
                 }
+                let f =
+                    scatter_rec.attenuation * rec.material.scattering_pdf(&ray, &rec, &scattered);
+                throughput = throughput * f / pdf_val;
+                ray = scattered;
+                bsdf_pdf = Some(pdf_val);
             }
-        } else {
-            emitted
         }
-    } else {
-        background
+        let bounces = depth + 1;
+        if bounces >= environment.params.rr_depth && bounces < max_depth {
+            let survival = throughput.x.max(throughput.y).max(throughput.z).min(1.0);
+            if rng.gen::<Float>() >= survival {
+                PathStats::count(&mut stats.terminated, depth);
+                break;
+            }
+            throughput /= survival;
+        }
+    }
+    radiance
+}
+
+// Next event estimation: the light reflected at `rec` from a point sampled on
+// the lights, if the shadow ray reaches it, weighted against BSDF sampling,
+// and the object the shadow ray hit.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn sample_light(
+    rng: &mut SampleStream,
+    r_in: &Ray,
+    rec: &HitRecord,
+    attenuation: Color,
+    bsdf: &dyn Pdf,
+    world: &dyn Object,
+    lights: &dyn Object,
+    stats: &mut PathStats,
+) -> (Color, u32) {
+    let direction = lights.random(rng, rec.p);
+    let light_pdf = lights.pdf_value(rec.p, direction);
+    if light_pdf <= 0.0 || !light_pdf.is_finite() {
+        return (BLACK, 0);
+    }
+    let shadow = Ray::new(rec.p, direction, r_in.time);
+    let f = attenuation * rec.material.scattering_pdf(r_in, rec, &shadow);
+    if f == BLACK {
+        return (BLACK, 0);
+    }
+    stats.rays += 1;
+    let Some(light) = world.hit(rng, &shadow, 0.001, INFINITY) else {
+        return (BLACK, 0);
+    };
+    let le = light
+        .material
+        .color_emitted(&light, light.u, light.v, light.p);
+    let weight = power_heuristic(light_pdf, bsdf.value(direction));
+    (weight * f * le / light_pdf, light.object)
+}
+
+/// Render progress reported after every finished tile.
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    pub tiles_done: usize,
+    pub tiles_total: usize,
+    pub rays_traced: u64,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Estimated time remaining assuming the remaining tiles take as long as
+    /// the finished ones.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.tiles_done == 0 {
+            return None;
+        }
+        let remaining = (self.tiles_total - self.tiles_done) as f64 / self.tiles_done as f64;
+        Some(self.elapsed.mul_f64(remaining))
+    }
+}
+
+/// The generator for one sample of one pixel. It only depends on the seed and
+/// the indices, not on which thread renders the pixel or in what order.
+pub fn sample_rng(seed: u64, pixel: u64, sample: u64) -> SmallRng {
+    SmallRng::seed_from_u64(splitmix64(splitmix64(seed ^ splitmix64(pixel)) ^ sample))
+}
+
+/// The number of samples every pixel ends up with.
+pub fn target_samples(environment: &Environment) -> u32 {
+    environment.samples_per_pixel()
+}
+
+/// The samples per pixel the sampler's pattern is laid out for.
+pub fn sampler_pattern(environment: &Environment) -> u32 {
+    match environment.params.sampler_pattern {
+        0 => target_samples(environment),
+        n => n,
     }
 }
 
-fn write_color(data: &mut Vec<u8>, pixel_color: Color, samples_per_pixel: u32) {
-    let mut r = pixel_color.x;
-    let mut g = pixel_color.y;
-    let mut b = pixel_color.z;
+/// Sampler dimensions for the camera ray: pixel position, lens and time.
+pub const CAMERA_DIMENSIONS: u32 = 5;
+/// Sampler dimensions for each bounce. Values a bounce draws past these are
+/// independent.
+pub const BOUNCE_DIMENSIONS: u32 = 8;
 
-    // Divide the color by the number of samples.
-    let scale = 1.0 / samples_per_pixel as Float;
-    r = (scale * r).sqrt();
-    g = (scale * g).sqrt();
-    b = (scale * b).sqrt();
+// A ray through a random point of pixel (i, j), counted from the bottom left.
+pub(crate) fn camera_ray(rng: &mut SampleStream, environment: &Environment, i: u32, j: u32) -> Ray {
+    camera_sample(rng, environment, i, j).0
+}
+
+// `camera_ray` and where in pixel (i, j) it starts, from its bottom left.
+fn camera_sample(
+    rng: &mut SampleStream,
+    environment: &Environment,
+    i: u32,
+    j: u32,
+) -> (Ray, Float, Float) {
+    let (w, h) = (environment.width(), environment.height());
+    rng.next_block(CAMERA_DIMENSIONS);
+    let (dx, dy) = (rng.gen::<Float>(), rng.gen::<Float>());
+    let u = (i as Float + dx) / ((w - 1) as Float);
+    let v = (j as Float + dy) / ((h - 1) as Float);
+    (environment.camera.get_ray(rng, u, v), dx, dy)
+}
 
-    data.push((255.999 * r) as u8);
-    data.push((255.999 * g) as u8);
-    data.push((255.999 * b) as u8);
+// Trace the samples with indices `samples` of pixel (i, j), counted from the
+// bottom left, and add them and their AOVs to `film` at (x, y). Samples are
+// also spread over the pixels around by `filter`, unless it is the pixel box.
+// Radiance landing on other pixels is pushed to `splats` with their position
+// in the image.
+#[allow(clippy::too_many_arguments)]
+fn render_pixel(
+    environment: &Environment,
+    sampler: &dyn Sampler,
+    film: &mut Film,
+    x: u32,
+    y: u32,
+    i: u32,
+    j: u32,
+    samples: Range<u32>,
+    integrator: &dyn Integrator,
+    filter: &Filter,
+    stats: &mut PathStats,
+    splats: &mut Vec<(u32, u32, Color)>,
+) {
+    let pixel = j as u64 * environment.width() as u64 + i as u64;
+    let aovs = environment.params.rendered_aovs();
+    let mut aovs = (!aovs.is_empty()).then(|| AovSample::new(&aovs));
+    for k in samples {
+        let rng = sample_rng(environment.params.seed, pixel, k as u64);
+        let mut rng = SampleStream::new(sampler, i, j, k, rng);
+        let (r, dx, dy) = camera_sample(&mut rng, environment, i, j);
+        let mut splat = |x, y, c| splats.push((x, y, c));
+        let mut rc = match aovs.as_mut() {
+            Some(aovs) => {
+                aovs.clear();
+                integrator.radiance_aovs(&mut rng, &r, environment, stats, &mut splat, aovs)
+            }
+            None => integrator.radiance(&mut rng, &r, environment, stats, &mut splat),
+        };
+        if rc.x.is_nan() {
+            rc.x = 0.0
+        };
+        if rc.y.is_nan() {
+            rc.y = 0.0
+        };
+        if rc.z.is_nan() {
+            rc.z = 0.0
+        };
+        film.add_sample(x, y, rc);
+        if !filter.is_pixel_box() {
+            film.add_filtered(x as Float + dx, y as Float + 1.0 - dy, rc, filter);
+        }
+        if let Some(aovs) = &aovs {
+            film.add_aovs(x, y, aovs);
+        }
+    }
 }
 
 pub fn render(environment: &Environment) -> Vec<u8> {
-    let mut data: Vec<u8> = Vec::new();
-    let w = environment.width();
+    render_with_progress(environment, &|_| {})
+}
+
+pub fn render_with_progress(
+    environment: &Environment,
+    progress: &(dyn Fn(&Progress) + Sync),
+) -> Vec<u8> {
+    let params = &environment.params;
+    let film = render_film(environment, progress);
+    params.to_8bit(&output_film(&film, params).to_rgb())
+}
+
+/// Linear radiance of every pixel, from the top row down.
+pub fn render_hdr(environment: &Environment) -> Vec<Color> {
+    let film = render_film(environment, &|_| {});
+    output_film(&film, &environment.params).to_rgb()
+}
+
+/// `film` as it is shown and saved, denoised if `params.denoise`.
+pub fn output_film<'a>(film: &'a Film, params: &RenderParams) -> Cow<'a, Film> {
+    if params.denoise {
+        Cow::Owned(denoise(film, params))
+    } else {
+        Cow::Borrowed(film)
+    }
+}
+
+pub fn render_film(environment: &Environment, progress: &(dyn Fn(&Progress) + Sync)) -> Film {
+    let mut film = Film::new(environment.width(), environment.height());
+    render_progressive(environment, &mut film, progress, &mut |_| {});
+    film
+}
+
+/// The most samples adaptive sampling spends on one pixel, as a multiple of
+/// `target_samples`.
+pub const ADAPTIVE_MAX_FACTOR: u32 = 8;
+
+// The number of samples each pixel gets in one pass.
+#[derive(Clone, Copy, Debug)]
+struct PassPlan {
+    samples: u32,
+    cap: u32,
+    min_samples: u32,
+    threshold: Float,
+}
+
+impl PassPlan {
+    fn pixel_samples(&self, p: &FilmPixel) -> u32 {
+        if p.count >= self.cap {
+            0
+        } else if p.count < self.min_samples {
+            self.min_samples - p.count
+        } else if self.threshold > 0.0 && p.relative_error() < self.threshold {
+            0
+        } else {
+            self.samples.min(self.cap - p.count)
+        }
+    }
+}
+
+// Plan the next pass over `film` and guess how many passes are left including
+// it, or None when the film is done.
+fn plan_pass(environment: &Environment, film: &Film) -> Option<(PassPlan, usize)> {
+    let params = &environment.params;
+    let target = target_samples(environment);
+    let adaptive = params.noise_threshold > 0.0;
+    let min_samples = params.min_samples.min(target).max(2);
+    let mut plan = PassPlan {
+        samples: match params.samples_per_pass {
+            0 if adaptive => min_samples,
+            0 => target.max(1),
+            n => n,
+        },
+        cap: if adaptive {
+            target * ADAPTIVE_MAX_FACTOR
+        } else {
+            target
+        },
+        min_samples: if adaptive { min_samples } else { 0 },
+        threshold: params.noise_threshold,
+    };
+    let needed = |plan: &PassPlan| -> u64 {
+        film.pixels()
+            .iter()
+            .map(|p| plan.pixel_samples(p) as u64)
+            .sum()
+    };
+    let wanted = needed(&plan);
+    if wanted == 0 {
+        return None;
+    }
+    if !adaptive {
+        let fewest = film.pixels().iter().map(|p| p.count).min().unwrap_or(0);
+        return Some((plan, (target - fewest).div_ceil(plan.samples) as usize));
+    }
+    // Spend no more than uniform sampling would have in total.
+    let budget = target as u64 * film.pixels().len() as u64;
+    let left = budget
+        .checked_sub(film.samples())
+        .filter(|&left| left > 0)?;
+    if wanted > left {
+        let active = film
+            .pixels()
+            .iter()
+            .filter(|p| plan.pixel_samples(p) > 0)
+            .count() as u64;
+        plan.samples = (left / active) as u32;
+        if plan.samples == 0 {
+            return None;
+        }
+    }
+    Some((plan, left.div_ceil(needed(&plan)) as usize))
+}
+
+/// Add samples to `film` in passes over the whole image and call `pass_done`
+/// with the film after each. Each pass adds `samples_per_pass` samples per
+/// pixel, all of them when that is 0, until every pixel has
+/// `target_samples`.
+///
+/// With a `noise_threshold` sampling is adaptive: every pixel first gets
+/// `min_samples`, and after that only pixels whose relative error is above the
+/// threshold are sampled further, up to `ADAPTIVE_MAX_FACTOR` times the
+/// target, until the samples uniform sampling would have taken are spent.
+///
+/// Sample `k` of a pixel is the same whichever pass renders it, so a film
+/// saved part way through can be resumed, or topped up after raising
+/// `samples_per_pixel`. The stratified sampler's pattern depends on
+/// `sampler_pattern` though, which has to stay what it was for the samples
+/// already in the film; the samples topping it up start new patterns.
+///
+/// Samples come from `environment.integrator` if it is set, otherwise from
+/// `params.integrator`. Photon mapping and Metropolis light transport render
+/// on their own, see `render_photon_map` and `render_mlt`.
+///
+/// Returns the statistics of the paths traced.
+pub fn render_progressive(
+    environment: &Environment,
+    film: &mut Film,
+    progress: &(dyn Fn(&Progress) + Sync),
+    pass_done: &mut dyn FnMut(&Film),
+) -> PathStats {
+    let integrator = match (&environment.integrator, environment.params.integrator) {
+        (Some(integrator), _) => integrator.clone(),
+        (None, IntegratorKind::PhotonMapping) => {
+            return render_photon_map(environment, film, progress, pass_done)
+        }
+        (None, IntegratorKind::Metropolis) => {
+            return render_mlt(environment, film, progress, pass_done)
+        }
+        (None, kind) => Arc::from(kind.build().expect("integrator traces samples")),
+    };
+    let (w, h) = (environment.width(), environment.height());
+    assert_eq!((film.width(), film.height()), (w, h));
+    let params = &environment.params;
+    let tiles = tiles(w, h, params.tile_size, params.tile_order);
+    let sampler = params
+        .sampler
+        .build(params.seed, sampler_pattern(environment));
+    let start = Instant::now();
+    let mut stats = PathStats::default();
+    let mut pass = 0;
+    while let Some((plan, passes_left)) = plan_pass(environment, film) {
+        let report = |tiles_done, rays| {
+            progress(&Progress {
+                tiles_done: pass * tiles.len() + tiles_done,
+                tiles_total: (pass + passes_left) * tiles.len(),
+                rays_traced: stats.rays + rays,
+                elapsed: start.elapsed(),
+            })
+        };
+        let (samples, pass_stats) = render_pass(
+            environment,
+            &*integrator,
+            &*sampler,
+            film,
+            &tiles,
+            plan,
+            &report,
+        );
+        film.merge(&samples);
+        stats.merge(&pass_stats);
+        pass += 1;
+        pass_done(film);
+    }
+    stats
+}
+
+// Render the samples `plan` gives each pixel of `film` and return them with
+// the statistics of their paths. Every thread takes the next tile until none are
+// left, and `progress` is called with the tiles and rays done so far after
+// each tile.
+#[allow(clippy::too_many_arguments)]
+fn render_pass(
+    environment: &Environment,
+    integrator: &dyn Integrator,
+    sampler: &dyn Sampler,
+    film: &Film,
+    tiles: &[Tile],
+    plan: PassPlan,
+    progress: &(dyn Fn(usize, u64) + Sync),
+) -> (Film, PathStats) {
     let h = environment.height();
+    let params = &environment.params;
+    let filter = Filter::new(params.filter, params.filter_radius);
+    // Tiles render with room around them for the samples the filter spreads
+    // past their edges.
+    let margin = filter.margin();
+    let next = AtomicUsize::new(0);
+    let state = Mutex::new((
+        Film::new(film.width(), film.height()),
+        0,
+        PathStats::default(),
+    ));
 
-    for j in (0..h).rev() {
-        eprintln!("Scanlines remaining: {}", j + 1);
-        let scanline: Vec<Color> = (0..w)
-            .into_par_iter()
-            .map(|i| {
-                let mut pixel_color = BLACK;
-                let mut rng = SmallRng::from_rng(thread_rng()).unwrap();
-                let n = (environment.samples_per_pixel() as f32).sqrt() as u32;
-                for s in 0..n {
-                    for t in 0..n {
-                        let u = ((i as Float) + (s as f32 + rng.gen::<Float>()) / n as f32)
-                            / ((w - 1) as Float);
-                        let v = ((j as Float) + (t as f32 + rng.gen::<Float>()) / n as f32)
-                            / ((h - 1) as Float);
-                        let r = environment.camera.get_ray(u, v);
-                        let mut rc = ray_color(
-                            &mut rng,
-                            &r,
-                            environment.background(),
-                            &environment.scene,
-                            environment.lights.clone(),
-                            environment.max_depth(),
+    (0..rayon::current_num_threads())
+        .into_par_iter()
+        .for_each(|_| {
+            while let Some(tile) = tiles.get(next.fetch_add(1, Ordering::Relaxed)) {
+                let mut stats = PathStats::default();
+                let mut tile_film =
+                    Film::new(tile.width() + 2 * margin, tile.height() + 2 * margin);
+                let mut splats = Vec::new();
+                for y in tile.y0..tile.y1 {
+                    for x in tile.x0..tile.x1 {
+                        let p = film.pixel(x, y);
+                        let (done, end) = (p.count, p.count + plan.pixel_samples(p));
+                        let (fx, fy) = (x - tile.x0 + margin, y - tile.y0 + margin);
+                        render_pixel(
+                            environment,
+                            sampler,
+                            &mut tile_film,
+                            fx,
+                            fy,
+                            x,
+                            h - 1 - y,
+                            done..end,
+                            integrator,
+                            &filter,
+                            &mut stats,
+                            &mut splats,
                         );
-                        if rc.x.is_nan() {
-                            rc.x = 0.0
-                        };
-                        if rc.y.is_nan() {
-                            rc.y = 0.0
-                        };
-                        if rc.z.is_nan() {
-                            rc.z = 0.0
-                        };
-                        pixel_color += rc;
                     }
                 }
-                pixel_color
-            })
-            .collect();
+                let mut state = state.lock().unwrap();
+                let (pass, tiles_done, pass_stats) = &mut *state;
+                let m = margin as i32;
+                pass.merge_at(&tile_film, tile.x0 as i32 - m, tile.y0 as i32 - m);
+                for (x, y, c) in splats {
+                    pass.add_splat(x, y, c);
+                }
+                *tiles_done += 1;
+                pass_stats.merge(&stats);
+                progress(*tiles_done, pass_stats.rays);
+            }
+        });
+
+    let (pass, _, stats) = state.into_inner().unwrap();
+    (pass, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aov::{Aov, Layer};
+    use crate::camera::Camera;
+    use crate::filter::FilterKind;
+    use crate::material::{diffuse_light, lambertian};
+    use crate::object::{EmptyObject, FlipFace, Objects};
+    use crate::rect::Rect;
+    use crate::sampler::SamplerKind;
+    use crate::scenes::{cornell_box, RenderParams};
+    use crate::sphere::Sphere;
+    use crate::tile::TileOrder;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_render_is_deterministic() {
+        let mut environment = cornell_box(false);
+        environment.params.set_width(24);
+        environment.params.samples_per_pixel = 4;
+        environment.params.seed = 7;
+        let first = render(&environment);
+        environment.params.tile_size = 5;
+        environment.params.tile_order = TileOrder::Spiral;
+        assert_eq!(render(&environment), first);
+        environment.params.seed = 8;
+        assert_ne!(render(&environment), first);
+    }
+
+    #[test]
+    fn test_progressive_resume() {
+        let mut environment = cornell_box(false);
+        environment.params.set_width(16);
+        environment.params.samples_per_pixel = 9;
+        let full = render_film(&environment, &|_| {});
+
+        // Passes of 4, 4 and 1 samples give the same image.
+        environment.params.samples_per_pass = 4;
+        let mut film = Film::new(full.width(), full.height());
+        let mut passes = 0;
+        render_progressive(&environment, &mut film, &|_| {}, &mut |_| passes += 1);
+        assert_eq!((passes, film.samples()), (3, full.samples()));
+        for (a, b) in film.pixels().iter().zip(full.pixels()) {
+            assert_eq!(a.count, 9);
+            assert!((a.mean() - b.mean()).length() <= 1e-4 * (1.0 + b.mean().length()));
+        }
+
+        // Resuming a finished film does nothing, raising the target tops it up.
+        render_progressive(&environment, &mut film, &|_| {}, &mut |_| passes += 1);
+        assert_eq!(passes, 3);
+        environment.params.samples_per_pixel = 16;
+        render_progressive(&environment, &mut film, &|_| {}, &mut |_| passes += 1);
+        assert_eq!(
+            (passes, film.samples()),
+            (5, 16 * full.pixels().len() as u64)
+        );
+        // The samples added are the ones a 16 sample render takes.
+        let full = render_film(&environment, &|_| {});
+        for (a, b) in film.pixels().iter().zip(full.pixels()) {
+            assert!((a.mean() - b.mean()).length() <= 1e-4 * (1.0 + b.mean().length()));
+        }
+    }
+
+    #[test]
+    fn test_stratified_resume_keeps_its_pattern() {
+        let mut environment = cornell_box(false);
+        environment.params.set_width(16);
+        environment.params.sampler = SamplerKind::Stratified;
+        environment.params.samples_per_pixel = 9;
+        let mut film = render_film(&environment, &|_| {});
+        // Topping up with the first pattern size adds the samples a render
+        // with that pattern size would have taken.
+        environment.params.sampler_pattern = sampler_pattern(&environment);
+        environment.params.samples_per_pixel = 16;
+        render_progressive(&environment, &mut film, &|_| {}, &mut |_| {});
+        let full = render_film(&environment, &|_| {});
+        for (a, b) in film.pixels().iter().zip(full.pixels()) {
+            assert_eq!(a.count, 16);
+            assert!((a.mean() - b.mean()).length() <= 1e-4 * (1.0 + b.mean().length()));
+        }
+    }
 
-        for pixel_color in scanline {
-            write_color(&mut data, pixel_color, environment.samples_per_pixel());
+    #[test]
+    fn test_adaptive_sampling() {
+        let mut environment = cornell_box(false);
+        environment.params.set_width(16);
+        environment.params.samples_per_pixel = 16;
+        environment.params.noise_threshold = 0.02;
+        environment.params.min_samples = 4;
+        environment.params.samples_per_pass = 4;
+        let film = render_film(&environment, &|_| {});
+        let counts: Vec<u32> = film.pixels().iter().map(|p| p.count).collect();
+        let budget = 16 * counts.len() as u64;
+        // The budget is spent unevenly, black pixels stop at the minimum.
+        assert!(film.samples() <= budget && film.samples() > budget * 3 / 4);
+        assert!(counts
+            .iter()
+            .all(|&c| (4..=16 * ADAPTIVE_MAX_FACTOR).contains(&c)));
+        assert!(counts.contains(&4));
+        assert!(counts.iter().any(|&c| c > 16));
+    }
+
+    // A floor with a sphere under a square light, and the light to sample.
+    fn lit_floor() -> Environment {
+        let mut objects = Objects::new(Vec::new());
+        let light = Rect::new(
+            Axis::Y,
+            -1.0,
+            -1.0,
+            1.0,
+            1.0,
+            2.0,
+            diffuse_light(4.0, 4.0, 4.0),
+        );
+        objects.add(FlipFace::new(light.clone()));
+        objects.add(Rect::new(
+            Axis::Y,
+            -5.0,
+            -5.0,
+            5.0,
+            5.0,
+            0.0,
+            lambertian(0.5, 0.5, 0.5),
+        ));
+        objects.add(Sphere::new(
+            point3(0.5, 0.5, 0.0),
+            0.5,
+            lambertian(0.8, 0.3, 0.3),
+        ));
+        let camera = Camera::basic(point3(0.0, 1.0, 4.0), ZERO, 60.0, 1.0, 0.0, 1.0);
+        let params = RenderParams::new(BLACK, 1.0, 8, 512, 8);
+        Environment::new(Box::new(objects), camera, Arc::new(light), params)
+    }
+
+    // A floor lit by two spherical lights, the second behind the first as seen
+    // from the camera.
+    fn lit_by_spheres() -> Environment {
+        let mut objects = Objects::new(Vec::new());
+        objects.add(Rect::new(
+            Axis::Y,
+            -5.0,
+            -5.0,
+            5.0,
+            5.0,
+            0.0,
+            lambertian(0.5, 0.5, 0.5),
+        ));
+        let mut lights = Objects::new(Vec::new());
+        for center in [point3(0.0, 2.0, 0.0), point3(0.0, 2.25, -1.0)] {
+            let light = Arc::new(Sphere::new(center, 0.3, diffuse_light(4.0, 4.0, 4.0)));
+            objects.add(light.clone());
+            lights.add(light);
+        }
+        let camera = Camera::basic(point3(0.0, 1.0, 4.0), ZERO, 60.0, 1.0, 0.0, 1.0);
+        let params = RenderParams::new(BLACK, 1.0, 8, 512, 8);
+        Environment::new(Box::new(objects), camera, Arc::new(lights), params)
+    }
+
+    fn mean_luminance(environment: &Environment) -> Float {
+        let pixels = render_hdr(environment);
+        pixels.iter().map(|c| c.y).sum::<Float>() / pixels.len() as Float
+    }
+
+    #[test]
+    fn test_direct_lighting() {
+        // Nearly all of the light reaches the floor straight from the light.
+        let mut environment = lit_floor();
+        let path = mean_luminance(&environment);
+        environment.params.integrator = IntegratorKind::Direct;
+        let direct = mean_luminance(&environment);
+        assert!((direct - path).abs() < 0.02 * path, "{direct} {path}");
+    }
+
+    #[test]
+    fn test_debug_integrators() {
+        let mut environment = lit_floor();
+        environment.params.samples_per_pixel = 4;
+        // The bottom row sees the floor.
+        environment.params.integrator = IntegratorKind::Normals;
+        let normals = render_hdr(&environment);
+        for c in &normals[normals.len() - 8..] {
+            assert!((*c - color(0.5, 1.0, 0.5)).length() < 1e-4, "{c:?}");
+        }
+        // Data is saved linearly, without tone mapping.
+        let data = render(&environment);
+        for (&v, expected) in data[data.len() - 3..].iter().zip([128, 255, 128]) {
+            assert!(v.abs_diff(expected) <= 1, "{v}");
+        }
+        environment.params.integrator = IntegratorKind::Depth;
+        let depth = render_hdr(&environment);
+        assert!(depth.iter().all(|c| (0.0..=1.0).contains(&c.x)));
+        assert!(depth.iter().any(|c| c.x > 0.0));
+        environment.params.integrator = IntegratorKind::AmbientOcclusion;
+        let ao = render_hdr(&environment);
+        assert!(ao.iter().all(|c| (0.0..=1.0).contains(&c.x)));
+        assert!(ao.iter().any(|c| c.x < 1.0));
+
+        // An integrator of its own replaces `params.integrator`.
+        struct Constant;
+        impl Integrator for Constant {
+            fn radiance(
+                &self,
+                _rng: &mut SampleStream,
+                _r: &Ray,
+                _environment: &Environment,
+                _stats: &mut PathStats,
+                _splat: &mut dyn FnMut(u32, u32, Color),
+            ) -> Color {
+                color(0.25, 0.5, 0.75)
+            }
+        }
+        environment.integrator = Some(Arc::new(Constant));
+        assert!(render_hdr(&environment)
+            .iter()
+            .all(|&c| (c - color(0.25, 0.5, 0.75)).length() < 1e-5));
+    }
+
+    #[test]
+    fn test_aovs() {
+        let mut environment = lit_floor();
+        environment.params.samples_per_pixel = 16;
+        environment.params.aovs = Aov::ALL.to_vec();
+        let film = render_film(&environment, &|_| {});
+        let layer = |layer| film.layer_rgb(layer).unwrap();
+        let (beauty, n) = (film.to_rgb(), film.pixels().len());
+        // The lighting passes add up to the image, all light comes from the
+        // first object.
+        let (emission, direct, indirect) = (
+            layer(Layer::new(Aov::Emission)),
+            layer(Layer::new(Aov::Direct)),
+            layer(Layer::new(Aov::Indirect)),
+        );
+        let light = layer(Layer::light(0));
+        for i in 0..n {
+            let sum = emission[i] + direct[i] + indirect[i];
+            assert!((sum - beauty[i]).length() < 1e-4 * (1.0 + beauty[i].length()));
+            assert!((light[i] - beauty[i]).length() < 1e-4 * (1.0 + beauty[i].length()));
+        }
+        // The bottom row sees the floor, the second object.
+        let (normal, id) = (
+            layer(Layer::new(Aov::Normal)),
+            layer(Layer::new(Aov::ObjectId)),
+        );
+        for i in n - 8..n {
+            assert!((normal[i] - color(0.0, 1.0, 0.0)).length() < 1e-4);
+            assert_eq!(id[i], color(2.0, 2.0, 2.0));
         }
+        assert_eq!(film.layers().count(), Aov::ALL.len());
     }
-    data
-}
\ No newline at end of file
+
+    #[test]
+    fn test_reconstruction_filters() {
+        let mut environment = lit_floor();
+        environment.params.samples_per_pixel = 16;
+        environment.params.set_width(32);
+        let pixel_box = mean_luminance(&environment);
+        for filter in [
+            FilterKind::Tent,
+            FilterKind::Gaussian,
+            FilterKind::Mitchell,
+            FilterKind::Lanczos,
+        ] {
+            // Spreading samples out leaves the image as bright, away from
+            // small images' edges, which take in light from one side only.
+            environment.params.filter = filter;
+            environment.params.tile_size = 32;
+            let filtered = render_hdr(&environment);
+            let mean = filtered.iter().map(|c| c.y).sum::<Float>() / filtered.len() as Float;
+            assert!(
+                (mean - pixel_box).abs() < 0.02 * pixel_box,
+                "{filter:?} {mean} {pixel_box}"
+            );
+            // Samples spread across tile edges the same.
+            environment.params.tile_size = 3;
+            for (a, b) in render_hdr(&environment).iter().zip(&filtered) {
+                assert!(
+                    (*a - *b).length() <= 1e-4 * (1.0 + b.length()),
+                    "{filter:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_light_sampling_is_unbiased() {
+        // Rendered with and without a light to sample.
+        let mut environment = lit_floor();
+        let sampled = mean_luminance(&environment);
+        environment.lights = Arc::new(EmptyObject {});
+        let unsampled = mean_luminance(&environment);
+        assert!(
+            (sampled - unsampled).abs() < 0.02 * unsampled,
+            "{} {}",
+            sampled,
+            unsampled
+        );
+
+        assert_eq!(power_heuristic(1.0, 1.0), 0.5);
+        assert_eq!(power_heuristic(2.0, 0.0) + power_heuristic(0.0, 2.0), 1.0);
+    }
+
+    #[test]
+    fn test_russian_roulette() {
+        let mut environment = lit_floor();
+        let run = |environment: &Environment| {
+            let mut film = Film::new(environment.width(), environment.height());
+            let stats = render_progressive(environment, &mut film, &|_| {}, &mut |_| {});
+            (mean_luminance(environment), stats)
+        };
+        environment.params.rr_depth = environment.params.max_depth;
+        let (full, full_stats) = run(&environment);
+        environment.params.rr_depth = 1;
+        let (roulette, stats) = run(&environment);
+        assert!(
+            (roulette - full).abs() < 0.02 * full,
+            "{} {}",
+            roulette,
+            full
+        );
+
+        let paths = 512 * 8 * 8;
+        assert_eq!(stats.depths[0], paths);
+        assert!(stats.depths.windows(2).all(|d| d[0] >= d[1]));
+        assert!(stats.terminated.iter().sum::<u64>() > 0);
+        assert!(full_stats.terminated.iter().all(|&n| n == 0));
+        assert!(stats.mean_length() < full_stats.mean_length());
+        assert!(stats.rays < full_stats.rays);
+    }
+
+    #[test]
+    fn test_photon_mapping_matches_path_tracing() {
+        let mut environment = lit_floor();
+        let path = mean_luminance(&environment);
+        environment.params.integrator = IntegratorKind::PhotonMapping;
+        environment.params.samples_per_pixel = 64;
+        environment.params.photons_per_pass = 10_000;
+        environment.params.photon_radius = 0.1;
+        let photons = mean_luminance(&environment);
+        assert!((photons - path).abs() < 0.02 * path, "{} {}", photons, path);
+    }
+
+    #[test]
+    fn test_photon_mapping_with_sphere_lights() {
+        let mut environment = lit_by_spheres();
+        let path = mean_luminance(&environment);
+        environment.params.integrator = IntegratorKind::PhotonMapping;
+        environment.params.samples_per_pixel = 64;
+        environment.params.photons_per_pass = 10_000;
+        environment.params.photon_radius = 0.1;
+        let photons = mean_luminance(&environment);
+        assert!((photons - path).abs() < 0.02 * path, "{} {}", photons, path);
+    }
+
+    #[test]
+    fn test_metropolis_matches_path_tracing() {
+        let mut environment = lit_floor();
+        let path = mean_luminance(&environment);
+        environment.params.integrator = IntegratorKind::Metropolis;
+        environment.params.mlt_bootstrap = 100_000;
+        environment.params.mlt_chains = 64;
+        let metropolis = mean_luminance(&environment);
+        assert!(
+            (metropolis - path).abs() < 0.02 * path,
+            "{} {}",
+            metropolis,
+            path
+        );
+    }
+
+    #[test]
+    fn test_bidirectional_matches_path_tracing() {
+        let mut environment = lit_floor();
+        let path = mean_luminance(&environment);
+        environment.params.integrator = IntegratorKind::Bidirectional;
+        let bidirectional = mean_luminance(&environment);
+        assert!(
+            (bidirectional - path).abs() < 0.02 * path,
+            "{} {}",
+            bidirectional,
+            path
+        );
+    }
+}
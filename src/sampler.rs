@@ -0,0 +1,557 @@
+use crate::geom::*;
+use rand::rngs::SmallRng;
+use rand::{Rng, RngCore, SeedableRng};
+use std::sync::OnceLock;
+
+/// Supplies every dimension of every sample of every pixel. A value depends
+/// only on its coordinates, so pixels and samples can be rendered in any order.
+pub trait Sampler: Send + Sync {
+    /// Dimension `dimension` of sample `index` of pixel (x, y), in [0, 1).
+    fn get(&self, x: u32, y: u32, index: u32, dimension: u32) -> Float;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SamplerKind {
+    /// Uniform random values.
+    Independent,
+    /// Kensler's correlated multi-jittered pattern over pairs of dimensions.
+    Stratified,
+    /// The Halton sequence with random digit permutations per pixel.
+    Halton,
+    /// Owen scrambled Sobol points, padded in pairs of dimensions.
+    #[default]
+    Sobol,
+    /// The R2 sequence rotated per pixel by blue noise masks.
+    BlueNoise,
+}
+
+impl SamplerKind {
+    pub const ALL: [SamplerKind; 5] = [
+        SamplerKind::Independent,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+        SamplerKind::BlueNoise,
+    ];
+
+    /// A sampler for `samples` samples per pixel. Samples past that many are
+    /// still valid but only the first `samples` are stratified together.
+    pub fn build(self, seed: u64, samples: u32) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Independent => Box::new(Independent { seed }),
+            SamplerKind::Stratified => Box::new(Stratified {
+                seed,
+                samples: samples.max(1),
+            }),
+            SamplerKind::Halton => Box::new(Halton { seed }),
+            SamplerKind::Sobol => Box::new(Sobol { seed }),
+            SamplerKind::BlueNoise => Box::new(BlueNoise { seed }),
+        }
+    }
+}
+
+pub(crate) fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0, |h, &v| splitmix64(h ^ v))
+}
+
+// The top 24 bits as a float in [0, 1).
+fn to_float(bits: u32) -> Float {
+    (bits >> 8) as Float / (1 << 24) as Float
+}
+
+pub struct Independent {
+    seed: u64,
+}
+
+impl Sampler for Independent {
+    fn get(&self, x: u32, y: u32, index: u32, dimension: u32) -> Float {
+        let h = hash(&[
+            self.seed,
+            x as u64,
+            y as u64,
+            index as u64,
+            dimension as u64,
+        ]);
+        to_float((h >> 32) as u32)
+    }
+}
+
+pub struct Stratified {
+    seed: u64,
+    samples: u32,
+}
+
+// Kensler's hashed permutation of 0..len, "Correlated Multi-Jittered
+// Sampling", 2013.
+fn permute(mut i: u32, len: u32, p: u32) -> u32 {
+    let mut w = len - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < len {
+            return i.wrapping_add(p) % len;
+        }
+    }
+}
+
+fn random_float(mut i: u32, p: u32) -> Float {
+    i ^= p;
+    i ^= i >> 17;
+    i ^= i >> 10;
+    i = i.wrapping_mul(0xb36534e5);
+    i ^= i >> 12;
+    i ^= i >> 21;
+    i = i.wrapping_mul(0x93fc4795);
+    i ^= 0xdf6e307f;
+    i ^= i >> 17;
+    i = i.wrapping_mul(1 | p >> 18);
+    to_float(i)
+}
+
+impl Sampler for Stratified {
+    fn get(&self, x: u32, y: u32, index: u32, dimension: u32) -> Float {
+        // Every `samples` samples form a new pattern, over a grid of m by n
+        // cells holding at least that many.
+        let count = self.samples;
+        let round = index / count;
+        let pair = dimension / 2;
+        let p = hash(&[self.seed, x as u64, y as u64, round as u64, pair as u64]) as u32;
+        let m = ((count as Float).sqrt() as u32).max(1);
+        let n = count.div_ceil(m);
+        let s = permute(index % count, count, p.wrapping_mul(0x51633e2d));
+        let v = if dimension.is_multiple_of(2) {
+            let sy = permute(s / m, n, p.wrapping_mul(0x63d83595));
+            let jx = random_float(s, p.wrapping_mul(0xa399d265));
+            ((s % m) as Float + (sy as Float + jx) / n as Float) / m as Float
+        } else {
+            let sx = permute(s % m, m, p.wrapping_mul(0xa511e9b3));
+            let jy = random_float(s, p.wrapping_mul(0x711ad6a5));
+            ((s / m) as Float + (sx as Float + jy) / m as Float) / n as Float
+        };
+        v.min(ONE_MINUS_EPSILON)
+    }
+}
+
+const ONE_MINUS_EPSILON: Float = 1.0 - Float::EPSILON / 2.0;
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+pub struct Halton {
+    seed: u64,
+}
+
+impl Sampler for Halton {
+    // Dimensions past the 64th fall back to independent values.
+    fn get(&self, x: u32, y: u32, index: u32, dimension: u32) -> Float {
+        let Some(&base) = PRIMES.get(dimension as usize) else {
+            return Independent { seed: self.seed }.get(x, y, index, dimension);
+        };
+        let h = hash(&[self.seed, x as u64, y as u64, dimension as u64]);
+        // Permute the k-th digit at random, including the leading zeros,
+        // until the digits fall below float precision.
+        let inv_base = 1.0 / base as f64;
+        let (mut a, mut scale, mut v) = (index, inv_base, 0.0);
+        let mut k = 0;
+        while scale > 1e-8 {
+            let p = splitmix64(h ^ k) as u32;
+            v += permute(a % base, base, p) as f64 * scale;
+            a /= base;
+            scale *= inv_base;
+            k += 1;
+        }
+        (v as Float).min(ONE_MINUS_EPSILON)
+    }
+}
+
+pub struct Sobol {
+    seed: u64,
+}
+
+// The hash of Laine and Karras, in which every bit only depends on the same
+// and lower bits of the input.
+fn laine_karras(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+// An Owen scramble of the bits of x read as a binary fraction, Burley,
+// "Practical Hash-based Owen Scrambling", 2020.
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras(x.reverse_bits(), seed).reverse_bits()
+}
+
+// The second dimension of the Sobol sequence, built from the direction
+// numbers of the polynomial x + 1.
+fn sobol_second(mut i: u32) -> u32 {
+    let (mut v, mut r) = (1u32 << 31, 0);
+    while i != 0 {
+        if i & 1 != 0 {
+            r ^= v;
+        }
+        i >>= 1;
+        v ^= v >> 1;
+    }
+    r
+}
+
+impl Sampler for Sobol {
+    fn get(&self, x: u32, y: u32, index: u32, dimension: u32) -> Float {
+        // Each pair of dimensions uses the first two Sobol dimensions with its
+        // own shuffle of the samples and scramble of the values, which keeps
+        // every power of two prefix of the samples a (0, 2)-net.
+        let pair = dimension / 2;
+        let h = hash(&[self.seed, x as u64, y as u64, pair as u64]);
+        let i = nested_uniform_scramble(index, h as u32);
+        let v = if dimension.is_multiple_of(2) {
+            i.reverse_bits()
+        } else {
+            sobol_second(i)
+        };
+        let scramble = splitmix64(h ^ (dimension % 2) as u64);
+        to_float(nested_uniform_scramble(v, (scramble >> 32) as u32))
+    }
+}
+
+const MASK_SIZE: usize = 64;
+
+// A 64 by 64 tileable blue noise mask, values in [0, 1).
+fn blue_noise_mask() -> &'static [Float] {
+    static MASK: OnceLock<Vec<Float>> = OnceLock::new();
+    MASK.get_or_init(|| {
+        let n = MASK_SIZE * MASK_SIZE;
+        void_and_cluster(MASK_SIZE, 1)
+            .into_iter()
+            .map(|rank| (rank as Float + 0.5) / n as Float)
+            .collect()
+    })
+}
+
+// Rank the pixels of a `size` by `size` torus with Ulichney's void and
+// cluster method, "The void-and-cluster method for dither array generation",
+// 1993. Every prefix of the ranks is evenly spread out.
+fn void_and_cluster(size: usize, seed: u64) -> Vec<u32> {
+    let n = size * size;
+    let sigma: f64 = 1.5;
+    let kernel: Vec<f64> = (0..n)
+        .map(|i| {
+            let (dx, dy) = (i % size, i / size);
+            let dx = dx.min(size - dx) as f64;
+            let dy = dy.min(size - dy) as f64;
+            (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let toggle = |energy: &mut [f64], on: &mut [bool], p: usize| {
+        on[p] = !on[p];
+        let sign = if on[p] { 1.0 } else { -1.0 };
+        let (px, py) = (p % size, p / size);
+        for (q, e) in energy.iter_mut().enumerate() {
+            let dx = (q % size + size - px) % size;
+            let dy = (q / size + size - py) % size;
+            *e += sign * kernel[dy * size + dx];
+        }
+    };
+    // The tightest cluster is the set pixel with the most energy, the largest
+    // void the unset one with the least.
+    let tightest = |energy: &[f64], on: &[bool]| {
+        (0..n)
+            .filter(|&p| on[p])
+            .max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+    let largest_void = |energy: &[f64], on: &[bool]| {
+        (0..n)
+            .filter(|&p| !on[p])
+            .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+
+    let mut energy = vec![0.0; n];
+    let mut on = vec![false; n];
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let ones = n / 10;
+    while on.iter().filter(|&&b| b).count() < ones {
+        let p = rng.gen_range(0..n);
+        if !on[p] {
+            toggle(&mut energy, &mut on, p);
+        }
+    }
+    // Move points from clusters to voids until the pattern is even.
+    loop {
+        let cluster = tightest(&energy, &on);
+        toggle(&mut energy, &mut on, cluster);
+        let void = largest_void(&energy, &on);
+        toggle(&mut energy, &mut on, void);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut rank = vec![0; n];
+    let (mut e, mut o) = (energy.clone(), on.clone());
+    for r in (0..ones).rev() {
+        let p = tightest(&e, &o);
+        toggle(&mut e, &mut o, p);
+        rank[p] = r as u32;
+    }
+    for r in ones..n {
+        let p = largest_void(&energy, &on);
+        toggle(&mut energy, &mut on, p);
+        rank[p] = r as u32;
+    }
+    rank
+}
+
+pub struct BlueNoise {
+    seed: u64,
+}
+
+// The generators of the R2 sequence, powers of the inverse plastic number.
+const R2: [f64; 2] = [0.7548776662466927, 0.5698402909980532];
+
+impl Sampler for BlueNoise {
+    fn get(&self, x: u32, y: u32, index: u32, dimension: u32) -> Float {
+        // Every dimension reads the mask at its own toroidal offset, so the
+        // offsets of neighbouring pixels differ in every dimension.
+        let h = hash(&[self.seed, dimension as u64]);
+        let ox = (x as usize + (h as usize % MASK_SIZE)) % MASK_SIZE;
+        let oy = (y as usize + ((h >> 32) as usize % MASK_SIZE)) % MASK_SIZE;
+        let offset = blue_noise_mask()[oy * MASK_SIZE + ox] as f64;
+        // Each pair of dimensions walks the sequence in its own order, or
+        // every pair would be the first shifted by a constant. The shuffle
+        // keeps aligned power of two blocks of samples together, and any run
+        // of the sequence is as even as its start.
+        let pair = hash(&[self.seed, (dimension / 2) as u64]);
+        let index = nested_uniform_scramble(index, pair as u32);
+        let step = (index as f64 * R2[dimension as usize % 2]).fract();
+        ((offset + step).fract() as Float).min(ONE_MINUS_EPSILON)
+    }
+}
+
+/// The values one sample draws, in order. Within the current block they come
+/// from consecutive dimensions of a `Sampler`, past its end from `rng`.
+/// Blocks give each part of a path, such as the camera ray or one bounce,
+/// fixed dimensions however many values the parts before it used.
+pub struct SampleStream<'a> {
+    sampler: &'a dyn Sampler,
+    x: u32,
+    y: u32,
+    index: u32,
+    dimension: u32,
+    block_end: u32,
+    rng: SmallRng,
+}
+
+impl<'a> SampleStream<'a> {
+    pub fn new(sampler: &'a dyn Sampler, x: u32, y: u32, index: u32, rng: SmallRng) -> Self {
+        Self {
+            sampler,
+            x,
+            y,
+            index,
+            dimension: 0,
+            block_end: 0,
+            rng,
+        }
+    }
+
+    /// Start a block of `dimensions` dimensions following the current one.
+    pub fn next_block(&mut self, dimensions: u32) {
+        self.dimension = self.block_end;
+        self.block_end += dimensions;
+    }
+}
+
+impl RngCore for SampleStream<'_> {
+    fn next_u32(&mut self) -> u32 {
+        if self.dimension < self.block_end {
+            let v = self.sampler.get(self.x, self.y, self.index, self.dimension);
+            self.dimension += 1;
+            (v as f64 * 4294967296.0) as u32
+        } else {
+            self.rng.next_u32()
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.next_u32() as u64) << 32 | self.rng.next_u32() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 5] = SamplerKind::ALL;
+
+    // Whether no two values fall in the same of `count` equal intervals.
+    fn stratified(values: impl Iterator<Item = Float>, count: usize) -> bool {
+        let mut hits = vec![0; count];
+        for v in values {
+            hits[(v * count as Float) as usize] += 1;
+        }
+        hits.iter().all(|&h| h <= 1)
+    }
+
+    #[test]
+    fn test_samplers_are_deterministic_and_in_range() {
+        for kind in KINDS {
+            let a = kind.build(3, 16);
+            let b = kind.build(3, 16);
+            for dimension in 0..80 {
+                for index in 0..20 {
+                    let v = a.get(5, 9, index, dimension);
+                    assert!((0.0..1.0).contains(&v), "{:?}", kind);
+                    assert_eq!(v, b.get(5, 9, index, dimension));
+                }
+            }
+            let other = kind.build(4, 16);
+            assert_ne!(a.get(5, 9, 0, 0), other.get(5, 9, 0, 0), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn test_dimension_pairs_are_independent() {
+        // Later pairs of dimensions are not the first pair shifted by a
+        // constant, or every bounce would repeat the camera jitter.
+        for kind in KINDS {
+            let sampler = kind.build(3, 16);
+            for (x, y) in [(3, 7), (10, 20)] {
+                for (a, b) in [(0, 2), (1, 3), (0, 4)] {
+                    let shifts: Vec<Float> = (0..16)
+                        .map(|i| {
+                            (sampler.get(x, y, i, b) - sampler.get(x, y, i, a)).rem_euclid(1.0)
+                        })
+                        .collect();
+                    let spread = shifts
+                        .iter()
+                        .fold(0.0, |m: Float, s| m.max((s - shifts[0]).abs()));
+                    assert!(spread > 0.05, "{:?} {} {}", kind, a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_stratification() {
+        // Any count, not just a perfect square, is used in full. 7 samples
+        // fill 7 of the 2 by 4 cells, and 8 strata in each dimension.
+        let multi_jittered = SamplerKind::Stratified.build(1, 7);
+        for dimension in (0..6).step_by(2) {
+            let u = |i| multi_jittered.get(2, 3, i, dimension);
+            let v = |i| multi_jittered.get(2, 3, i, dimension + 1);
+            assert!(stratified((0..7).map(u), 8));
+            assert!(stratified((0..7).map(v), 8));
+            let cells = (0..7).map(|i| ((v(i) * 4.0).floor() * 2.0 + u(i) * 2.0) / 8.0);
+            assert!(stratified(cells, 8));
+        }
+        let values = (0..16).map(|i| SamplerKind::Stratified.build(1, 16).get(2, 3, i, 4));
+        assert!(stratified(values, 16));
+        // 16 Sobol points fill every elementary interval of area 1/16.
+        let sobol = SamplerKind::Sobol.build(1, 16);
+        for dimension in [0, 2, 10] {
+            for (cols, rows) in [(16, 1), (8, 2), (4, 4), (2, 8), (1, 16)] {
+                let mut cells = [0; 16];
+                for i in 0..16 {
+                    let u = sobol.get(2, 3, i, dimension);
+                    let v = sobol.get(2, 3, i, dimension + 1);
+                    let cell = (v * rows as Float) as usize * cols + (u * cols as Float) as usize;
+                    cells[cell] += 1;
+                }
+                assert!(
+                    cells.iter().all(|&c| c == 1),
+                    "{} {}x{}",
+                    dimension,
+                    cols,
+                    rows
+                );
+            }
+        }
+        let halton = SamplerKind::Halton.build(1, 9);
+        let values = (0..9).map(|i| halton.get(2, 3, i, 1));
+        assert!(stratified(values, 9));
+    }
+
+    #[test]
+    fn test_blue_noise_mask() {
+        let mask = blue_noise_mask();
+        let n = mask.len();
+        let mut ranks: Vec<usize> = mask.iter().map(|&v| (v * n as Float) as usize).collect();
+        ranks.sort();
+        assert!(ranks.iter().enumerate().all(|(i, &r)| i == r));
+        // Averages over 4 by 4 blocks vary far less than for white noise,
+        // which has a variance of 1 / 192, and neighbours differ more.
+        let size = MASK_SIZE;
+        let mut variance = 0.0;
+        for y in (0..size).step_by(4) {
+            for x in (0..size).step_by(4) {
+                let block = (0..16).map(|i| mask[(y + i / 4) * size + x + i % 4]);
+                let mean = block.sum::<Float>() / 16.0;
+                variance += (mean - 0.5) * (mean - 0.5) / (size * size / 16) as Float;
+            }
+        }
+        assert!(variance < 1.0 / 192.0 / 4.0, "{}", variance);
+        let difference = (0..size * size)
+            .map(|i| (mask[i] - mask[i / size * size + (i + 1) % size]).abs())
+            .sum::<Float>()
+            / (size * size) as Float;
+        assert!(difference > 0.37, "{}", difference);
+    }
+
+    #[test]
+    fn test_sample_stream_blocks() {
+        let sampler = SamplerKind::Sobol.build(0, 4);
+        let mut stream = SampleStream::new(&*sampler, 1, 2, 3, SmallRng::seed_from_u64(0));
+        stream.next_block(2);
+        let first: Float = stream.gen();
+        assert_eq!(first, sampler.get(1, 2, 3, 0));
+        stream.next_block(3);
+        let _: Float = stream.gen();
+        stream.next_block(1);
+        assert_eq!(stream.gen::<Float>(), sampler.get(1, 2, 3, 5));
+    }
+}
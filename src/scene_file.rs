@@ -0,0 +1,909 @@
+use crate::bvh::*;
+use crate::camera::Camera;
+use crate::geom::*;
+use crate::material::*;
+use crate::obj::*;
+use crate::object::*;
+use crate::rect::*;
+use crate::scenes::{Environment, RenderParams};
+use crate::sphere::*;
+use crate::texture::*;
+use crate::triangle::*;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// A scene file is a TOML document describing the render parameters, camera,
+// named textures and materials, and a list of objects. Textures and materials
+// are referenced by name so they can be shared between objects.
+//
+// [params]
+// background = [0.0, 0.0, 0.0]
+// aspect_ratio = 1.0
+// width = 600
+// samples_per_pixel = 100
+// max_depth = 50
+//
+// [camera]
+// origin = [278.0, 278.0, -800.0]
+// lookat = [278.0, 278.0, 0.0]
+// vfov = 40.0
+//
+// [materials.red]
+// type = "lambertian"
+// albedo = [0.65, 0.05, 0.05]
+//
+// [[objects]]
+// type = "sphere"
+// center = [190.0, 90.0, 190.0]
+// radius = 90.0
+// material = "red"
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(PathBuf, std::io::Error),
+    Parse(toml::de::Error),
+    Image(PathBuf, image::ImageError),
+    Obj(ObjError),
+    UnknownTexture(String),
+    UnknownMaterial(String),
+    TextureCycle(String),
+    MissingMaterial(String),
+    Unbounded(String),
+    UnknownField(String, String),
+    MediumLight(String),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            SceneError::Parse(e) => write!(f, "invalid scene file: {}", e),
+            SceneError::Image(path, e) => write!(f, "cannot load image {}: {}", path.display(), e),
+            SceneError::Obj(e) => write!(f, "cannot load mesh: {}", e),
+            SceneError::UnknownTexture(name) => write!(f, "unknown texture '{}'", name),
+            SceneError::UnknownMaterial(name) => write!(f, "unknown material '{}'", name),
+            SceneError::TextureCycle(name) => {
+                write!(f, "texture '{}' refers to itself", name)
+            }
+            SceneError::MissingMaterial(shape) => write!(f, "{} has no material", shape),
+            SceneError::Unbounded(shape) => {
+                write!(
+                    f,
+                    "{} cannot be placed in a bvh, it has no bounding box",
+                    shape
+                )
+            }
+            SceneError::UnknownField(shape, field) => {
+                write!(f, "unknown field '{}' in {}", field, shape)
+            }
+            SceneError::MediumLight(shape) => {
+                write!(f, "{} with a medium cannot be sampled as a light", shape)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SceneError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SceneError::Io(_, e) => Some(e),
+            SceneError::Parse(e) => Some(e),
+            SceneError::Image(_, e) => Some(e),
+            SceneError::Obj(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+type V3 = [Float; 3];
+
+fn v3(a: V3) -> Vec3 {
+    vec3(a[0], a[1], a[2])
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AxisDesc {
+    X,
+    Y,
+    Z,
+}
+
+impl From<AxisDesc> for Axis {
+    fn from(a: AxisDesc) -> Self {
+        match a {
+            AxisDesc::X => Axis::X,
+            AxisDesc::Y => Axis::Y,
+            AxisDesc::Z => Axis::Z,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneDesc {
+    pub params: ParamsDesc,
+    pub camera: CameraDesc,
+    #[serde(default)]
+    pub textures: HashMap<String, TextureDesc>,
+    #[serde(default)]
+    pub materials: HashMap<String, MaterialDesc>,
+    #[serde(default)]
+    pub objects: Vec<ObjectDesc>,
+    #[serde(default)]
+    pub bvh: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParamsDesc {
+    #[serde(default)]
+    pub background: V3,
+    pub aspect_ratio: Float,
+    pub width: u32,
+    pub samples_per_pixel: u32,
+    pub max_depth: u32,
+    #[serde(default)]
+    pub seed: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraDesc {
+    pub origin: V3,
+    pub lookat: V3,
+    #[serde(default = "default_vup")]
+    pub vup: V3,
+    pub vfov: Float,
+    #[serde(default)]
+    pub aperture: Float,
+    #[serde(default = "default_focus_dist")]
+    pub focus_dist: Float,
+    #[serde(default = "default_exposure")]
+    pub exposure: [Float; 2],
+}
+
+fn default_vup() -> V3 {
+    [0.0, 1.0, 0.0]
+}
+
+fn default_focus_dist() -> Float {
+    10.0
+}
+
+fn default_exposure() -> [Float; 2] {
+    [0.0, 1.0]
+}
+
+/// Either an inline color or the name of a texture.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum TextureRef {
+    Color(V3),
+    Named(String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TextureDesc {
+    Solid { color: V3 },
+    Checkered { even: TextureRef, odd: TextureRef },
+    Perlin { scale: Float },
+    Image { path: PathBuf },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum MaterialDesc {
+    Lambertian { albedo: TextureRef },
+    Metal { albedo: V3, fuzz: Float },
+    Dielectric { ir: Float },
+    DiffuseLight { emit: TextureRef },
+    Isotropic { albedo: TextureRef },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum TransformDesc {
+    Translate(V3),
+    Rotate { axis: AxisDesc, degrees: Float },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MediumDesc {
+    pub color: V3,
+    pub density: Float,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ShapeDesc {
+    Sphere {
+        center: V3,
+        radius: Float,
+        center1: Option<V3>,
+        #[serde(default)]
+        time: [Float; 2],
+    },
+    Rect {
+        axis: AxisDesc,
+        p0: Float,
+        q0: Float,
+        p1: Float,
+        q1: Float,
+        k: Float,
+    },
+    Cuboid {
+        min: V3,
+        max: V3,
+    },
+    Triangle {
+        vertices: [V3; 3],
+        normals: Option<[V3; 3]>,
+    },
+    // A Wavefront OBJ file, `material` is used for faces without one.
+    Obj {
+        path: PathBuf,
+        #[serde(default)]
+        bvh: bool,
+    },
+    Group {
+        objects: Vec<ObjectDesc>,
+        #[serde(default)]
+        bvh: bool,
+    },
+}
+
+impl ShapeDesc {
+    fn name(&self) -> &'static str {
+        match self {
+            ShapeDesc::Sphere { .. } => "sphere",
+            ShapeDesc::Rect { .. } => "rect",
+            ShapeDesc::Cuboid { .. } => "cuboid",
+            ShapeDesc::Triangle { .. } => "triangle",
+            ShapeDesc::Obj { .. } => "obj",
+            ShapeDesc::Group { .. } => "group",
+        }
+    }
+
+    fn fields(&self) -> &'static [&'static str] {
+        match self {
+            ShapeDesc::Sphere { .. } => &["center", "radius", "center1", "time"],
+            ShapeDesc::Rect { .. } => &["axis", "p0", "q0", "p1", "q1", "k"],
+            ShapeDesc::Cuboid { .. } => &["min", "max"],
+            ShapeDesc::Triangle { .. } => &["vertices", "normals"],
+            ShapeDesc::Obj { .. } => &["path", "bvh"],
+            ShapeDesc::Group { .. } => &["objects", "bvh"],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ObjectDesc {
+    #[serde(flatten)]
+    pub shape: ShapeDesc,
+    pub material: Option<String>,
+    #[serde(default)]
+    pub transforms: Vec<TransformDesc>,
+    pub medium: Option<MediumDesc>,
+    // Flip the face of the object in the scene, the copy in `lights` is not flipped.
+    #[serde(default)]
+    pub flip: bool,
+    // Also add the object to the lights sampled by the renderer, and for a
+    // group everything in it. The emissive meshes of an OBJ file and objects
+    // marked as lights inside groups are added either way.
+    #[serde(default)]
+    pub light: bool,
+    // The flattened shape cannot deny unknown fields, every key left over
+    // lands here and is checked against the fields of the shape.
+    #[serde(flatten)]
+    extra: HashMap<String, toml::Value>,
+}
+
+impl ObjectDesc {
+    fn check_fields(&self) -> Result<(), SceneError> {
+        let fields = self.shape.fields();
+        match self
+            .extra
+            .keys()
+            .filter(|k| *k != "type" && !fields.contains(&k.as_str()))
+            .min()
+        {
+            Some(field) => Err(SceneError::UnknownField(
+                self.shape.name().to_string(),
+                field.clone(),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+// The meshes of an OBJ object in the scene, kept for its copy in `lights`,
+// with whether an MTL material makes them emissive. `material` is the one
+// faces without a material of their own use.
+struct ObjMeshes {
+    material: Arc<dyn Material>,
+    meshes: Vec<(Arc<TriangleMesh>, bool)>,
+}
+
+struct Builder<'a> {
+    desc: &'a SceneDesc,
+    base_dir: PathBuf,
+    textures: HashMap<String, Arc<dyn Texture>>,
+    materials: HashMap<String, Arc<dyn Material>>,
+    objs: HashMap<*const ObjectDesc, ObjMeshes>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(desc: &'a SceneDesc, base_dir: &Path) -> Self {
+        Self {
+            desc,
+            base_dir: base_dir.to_path_buf(),
+            textures: HashMap::new(),
+            materials: HashMap::new(),
+            objs: HashMap::new(),
+        }
+    }
+
+    fn texture(
+        &mut self,
+        name: &str,
+        visiting: &mut HashSet<String>,
+    ) -> Result<Arc<dyn Texture>, SceneError> {
+        if let Some(t) = self.textures.get(name) {
+            return Ok(t.clone());
+        }
+        let desc = self
+            .desc
+            .textures
+            .get(name)
+            .ok_or_else(|| SceneError::UnknownTexture(name.to_string()))?;
+        if !visiting.insert(name.to_string()) {
+            return Err(SceneError::TextureCycle(name.to_string()));
+        }
+        let texture: Arc<dyn Texture> = match desc {
+            TextureDesc::Solid { color } => Arc::new(v3(*color)),
+            TextureDesc::Checkered { even, odd } => {
+                let even = self.texture_ref(even, visiting)?;
+                let odd = self.texture_ref(odd, visiting)?;
+                Arc::new(CheckeredTexture::new(Arc::new(even), Arc::new(odd)))
+            }
+            TextureDesc::Perlin { scale } => Arc::new(PerlinTexture::new(*scale)),
+            TextureDesc::Image { path } => {
+                let path = self.base_dir.join(path);
+                let img = ImageTexture::open(&path).map_err(|e| SceneError::Image(path, e))?;
+                Arc::new(img)
+            }
+        };
+        visiting.remove(name);
+        self.textures.insert(name.to_string(), texture.clone());
+        Ok(texture)
+    }
+
+    fn texture_ref(
+        &mut self,
+        r: &TextureRef,
+        visiting: &mut HashSet<String>,
+    ) -> Result<Arc<dyn Texture>, SceneError> {
+        match r {
+            TextureRef::Color(c) => Ok(Arc::new(v3(*c))),
+            TextureRef::Named(name) => self.texture(name, visiting),
+        }
+    }
+
+    fn material(&mut self, name: &str) -> Result<Arc<dyn Material>, SceneError> {
+        if let Some(m) = self.materials.get(name) {
+            return Ok(m.clone());
+        }
+        let desc = self
+            .desc
+            .materials
+            .get(name)
+            .ok_or_else(|| SceneError::UnknownMaterial(name.to_string()))?;
+        let mut visiting = HashSet::new();
+        let material: Arc<dyn Material> = match desc {
+            MaterialDesc::Lambertian { albedo } => {
+                Arc::new(Lambertian::new(self.texture_ref(albedo, &mut visiting)?))
+            }
+            MaterialDesc::Metal { albedo, fuzz } => Arc::new(Metal::new(v3(*albedo), *fuzz)),
+            MaterialDesc::Dielectric { ir } => Arc::new(Dielectric::new(*ir)),
+            MaterialDesc::DiffuseLight { emit } => {
+                Arc::new(DiffuseLight::new(self.texture_ref(emit, &mut visiting)?))
+            }
+            MaterialDesc::Isotropic { albedo } => {
+                Arc::new(Isotropic::new(self.texture_ref(albedo, &mut visiting)?))
+            }
+        };
+        self.materials.insert(name.to_string(), material.clone());
+        Ok(material)
+    }
+
+    fn object_material(&mut self, obj: &ObjectDesc) -> Result<Arc<dyn Material>, SceneError> {
+        let name = obj
+            .material
+            .as_ref()
+            .ok_or_else(|| SceneError::MissingMaterial(obj.shape.name().to_string()))?;
+        self.material(name)
+    }
+
+    fn shape(&mut self, obj: &ObjectDesc) -> Result<Box<dyn Object>, SceneError> {
+        obj.check_fields()?;
+        Ok(match &obj.shape {
+            ShapeDesc::Sphere {
+                center,
+                radius,
+                center1,
+                time,
+            } => {
+                let material = self.object_material(obj)?;
+                match center1 {
+                    Some(c1) => Box::new(Sphere::new_moving(
+                        v3(*center),
+                        v3(*c1),
+                        *radius,
+                        material,
+                        time[0]..time[1],
+                    )),
+                    None => Box::new(Sphere::new(v3(*center), *radius, material)),
+                }
+            }
+            ShapeDesc::Rect {
+                axis,
+                p0,
+                q0,
+                p1,
+                q1,
+                k,
+            } => {
+                let material = self.object_material(obj)?;
+                Box::new(Rect::new((*axis).into(), *p0, *q0, *p1, *q1, *k, material))
+            }
+            ShapeDesc::Cuboid { min, max } => {
+                let material = self.object_material(obj)?;
+                Box::new(Cuboid::new(v3(*min), v3(*max), material))
+            }
+            ShapeDesc::Triangle { vertices, normals } => {
+                let material = self.object_material(obj)?;
+                let [p0, p1, p2] = vertices.map(v3);
+                let triangle = Triangle::new(p0, p1, p2, material);
+                match normals {
+                    Some(n) => Box::new(triangle.with_normals(n.map(v3))),
+                    None => Box::new(triangle),
+                }
+            }
+            ShapeDesc::Obj { path, bvh } => {
+                let meshes = self.load_meshes(obj, path)?;
+                mesh_group(meshes, *bvh)
+            }
+            ShapeDesc::Group { objects, bvh } => self.group(objects, *bvh)?,
+        })
+    }
+
+    // Load the meshes of an OBJ file and keep them for `obj_lights`.
+    fn load_meshes(
+        &mut self,
+        obj: &ObjectDesc,
+        path: &Path,
+    ) -> Result<Vec<Arc<TriangleMesh>>, SceneError> {
+        let material = match &obj.material {
+            Some(name) => self.material(name)?,
+            None => lambertian(0.73, 0.73, 0.73),
+        };
+        let meshes: Vec<(Arc<TriangleMesh>, bool)> =
+            load_obj(self.base_dir.join(path), material.clone())
+                .map_err(SceneError::Obj)?
+                .into_iter()
+                .map(|m| (Arc::new(m.mesh), m.emissive))
+                .collect();
+        let shared = meshes.iter().map(|(m, _)| m.clone()).collect();
+        self.objs.insert(obj, ObjMeshes { material, meshes });
+        Ok(shared)
+    }
+
+    // The meshes of the OBJ object `obj` to sample as lights: the ones an MTL
+    // material makes emissive, and if it is `marked` as a light the ones using
+    // its `material`.
+    fn obj_lights(&self, obj: &ObjectDesc, marked: bool) -> Objects {
+        let mut lights = Objects::new(Vec::new());
+        if let Some(obj) = self.objs.get(&(obj as *const ObjectDesc)) {
+            for (mesh, emissive) in &obj.meshes {
+                if *emissive || (marked && Arc::ptr_eq(&mesh.material, &obj.material)) {
+                    lights.add(mesh.clone());
+                }
+            }
+        }
+        lights
+    }
+
+    // Apply the transforms of `obj` to its shape.
+    fn transform(&self, obj: &ObjectDesc, mut object: Box<dyn Object>) -> Box<dyn Object> {
+        for t in &obj.transforms {
+            object = match t {
+                TransformDesc::Translate(offset) => Box::new(Translate::new(object, v3(*offset))),
+                TransformDesc::Rotate { axis, degrees } => {
+                    Box::new(Rotate::new((*axis).into(), object, *degrees))
+                }
+            };
+        }
+        object
+    }
+
+    // Apply the transforms and medium of `obj` to its shape.
+    fn place(&self, obj: &ObjectDesc, object: Box<dyn Object>) -> Box<dyn Object> {
+        let mut object = self.transform(obj, object);
+        if let Some(medium) = &obj.medium {
+            object = Box::new(ConstantMedium::new(
+                object,
+                v3(medium.color),
+                medium.density,
+            ));
+        }
+        object
+    }
+
+    fn object(&mut self, obj: &ObjectDesc, flip: bool) -> Result<Box<dyn Object>, SceneError> {
+        let shape = self.shape(obj)?;
+        let object = self.place(obj, shape);
+        if flip {
+            Ok(Box::new(FlipFace::new(object)))
+        } else {
+            Ok(object)
+        }
+    }
+
+    // The copy of `obj` sampled as a light, if any of it is one. Objects in
+    // a group marked as a light are lights too. Groups and OBJ files are
+    // copied without a hierarchy, as only plain lists of objects can be
+    // sampled, and OBJ files share their meshes with the scene.
+    fn light(
+        &mut self,
+        obj: &ObjectDesc,
+        in_light: bool,
+    ) -> Result<Option<Box<dyn Object>>, SceneError> {
+        let marked = in_light || obj.light;
+        let shape = match &obj.shape {
+            ShapeDesc::Obj { .. } => {
+                let lights = self.obj_lights(obj, marked);
+                if lights.objects.is_empty() {
+                    return Ok(None);
+                }
+                Box::new(lights)
+            }
+            ShapeDesc::Group { objects, .. } => {
+                let mut lights = Objects::new(Vec::new());
+                for child in objects {
+                    if let Some(light) = self.light(child, marked)? {
+                        lights.objects.push(light);
+                    }
+                }
+                if lights.objects.is_empty() {
+                    return Ok(None);
+                }
+                Box::new(lights)
+            }
+            _ if marked => self.shape(obj)?,
+            _ => return Ok(None),
+        };
+        if obj.medium.is_some() {
+            return Err(SceneError::MediumLight(obj.shape.name().to_string()));
+        }
+        Ok(Some(self.transform(obj, shape)))
+    }
+
+    fn group(&mut self, descs: &[ObjectDesc], bvh: bool) -> Result<Box<dyn Object>, SceneError> {
+        let mut objects = Objects::new(Vec::new());
+        for obj in descs {
+            let object = self.object(obj, obj.flip)?;
+            if bvh && object.bounding_box(&(0.0..1.0)).is_none() {
+                return Err(SceneError::Unbounded(obj.shape.name().to_string()));
+            }
+            objects.objects.push(object);
+        }
+        if bvh && !objects.objects.is_empty() {
+            let n = objects.objects.len();
+            Ok(Box::new(LinearBvh::new(&mut objects, 0, n, 0.0..1.0)))
+        } else {
+            Ok(Box::new(objects))
+        }
+    }
+
+    fn lights(&mut self) -> Result<Arc<dyn Object>, SceneError> {
+        let mut lights = Objects::new(Vec::new());
+        for obj in &self.desc.objects {
+            if let Some(light) = self.light(obj, false)? {
+                lights.objects.push(light);
+            }
+        }
+        if lights.objects.is_empty() {
+            Ok(Arc::new(EmptyObject {}))
+        } else {
+            Ok(Arc::new(lights))
+        }
+    }
+
+    fn build(mut self) -> Result<Environment, SceneError> {
+        let desc = self.desc;
+        let p = &desc.params;
+        let mut params = RenderParams::new(
+            v3(p.background),
+            p.aspect_ratio,
+            p.width,
+            p.samples_per_pixel,
+            p.max_depth,
+        );
+        params.seed = p.seed;
+        let c = &desc.camera;
+        let camera = Camera::new(
+            v3(c.origin),
+            v3(c.lookat),
+            v3(c.vup),
+            c.vfov,
+            p.aspect_ratio,
+            c.aperture,
+            c.focus_dist,
+            c.exposure[0]..c.exposure[1],
+        );
+        let scene = self.group(&desc.objects, desc.bvh)?;
+        let lights = self.lights()?;
+        Ok(Environment::new(scene, camera, lights, params))
+    }
+}
+
+fn mesh_group(meshes: Vec<Arc<TriangleMesh>>, bvh: bool) -> Box<dyn Object> {
+    let mut objects = Objects::new(Vec::new());
+    for mesh in meshes {
+        objects.add(mesh);
+    }
+    if bvh && !objects.objects.is_empty() {
+        let n = objects.objects.len();
+        Box::new(LinearBvh::new(&mut objects, 0, n, 0.0..1.0))
+    } else {
+        Box::new(objects)
+    }
+}
+
+impl SceneDesc {
+    /// Relative image paths are resolved against `base_dir`.
+    pub fn build(&self, base_dir: &Path) -> Result<Environment, SceneError> {
+        Builder::new(self, base_dir).build()
+    }
+}
+
+pub fn parse_scene(source: &str, base_dir: &Path) -> Result<Environment, SceneError> {
+    let desc: SceneDesc = toml::from_str(source).map_err(SceneError::Parse)?;
+    desc.build(base_dir)
+}
+
+pub fn load_scene<P: AsRef<Path>>(path: P) -> Result<Environment, SceneError> {
+    let path = path.as_ref();
+    let source =
+        std::fs::read_to_string(path).map_err(|e| SceneError::Io(path.to_path_buf(), e))?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    parse_scene(&source, base_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    const SCENE: &str = r#"
+        [params]
+        aspect_ratio = 2.0
+        width = 200
+        samples_per_pixel = 4
+        max_depth = 5
+
+        [camera]
+        origin = [0, 0, 5]
+        lookat = [0, 0, 0]
+        vfov = 40
+
+        [textures.checker]
+        type = "checkered"
+        even = [0.2, 0.2, 0.2]
+        odd = "white"
+
+        [textures.white]
+        type = "solid"
+        color = [0.9, 0.9, 0.9]
+
+        [materials.ground]
+        type = "lambertian"
+        albedo = "checker"
+
+        [materials.light]
+        type = "diffuse_light"
+        emit = [4, 4, 4]
+
+        [[objects]]
+        type = "sphere"
+        center = [0, -100, 0]
+        radius = 100
+        material = "ground"
+
+        [[objects]]
+        type = "rect"
+        axis = "y"
+        p0 = -1
+        q0 = -1
+        p1 = 1
+        q1 = 1
+        k = 3
+        material = "light"
+        flip = true
+        light = true
+    "#;
+
+    #[test]
+    fn test_parse_scene() {
+        let env = parse_scene(SCENE, Path::new(".")).unwrap();
+        assert_eq!(env.width(), 200);
+        assert_eq!(env.height(), 100);
+        assert!(env.scene.bounding_box(&(0.0..1.0)).is_some());
+    }
+
+    #[test]
+    fn test_default_exposure() {
+        // Without an exposure the shutter is open from 0 to 1, the time span
+        // moving objects are built for.
+        let desc: SceneDesc = toml::from_str(SCENE).unwrap();
+        assert_eq!(desc.camera.exposure, [0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_unknown_material() {
+        let source = SCENE.replace("material = \"ground\"", "material = \"stone\"");
+        match parse_scene(&source, Path::new(".")) {
+            Err(SceneError::UnknownMaterial(name)) => assert_eq!(name, "stone"),
+            _ => panic!("expected an unknown material error"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_object_field() {
+        let source = SCENE.replace("material = \"ground\"", "materail = \"ground\"");
+        match parse_scene(&source, Path::new(".")) {
+            Err(SceneError::UnknownField(shape, field)) => {
+                assert_eq!((shape.as_str(), field.as_str()), ("sphere", "materail"))
+            }
+            _ => panic!("expected an unknown field error"),
+        }
+    }
+
+    // The lights of `objects` added to the head of `SCENE`, and a random
+    // direction towards them from the origin with its density.
+    fn sample_lights(objects: &str) -> (Environment, Vec3, Float) {
+        let (head, _) = SCENE.split_once("[[objects]]").unwrap();
+        let env = parse_scene(&format!("{}{}", head, objects), Path::new(".")).unwrap();
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(0);
+        let v = env.lights.random(&mut rng, ZERO);
+        let pdf = env.lights.pdf_value(ZERO, v);
+        (env, v, pdf)
+    }
+
+    #[test]
+    fn test_transformed_lights() {
+        // A unit square at y = 3.5 after the translation, and a box.
+        let (env, v, pdf) = sample_lights(
+            r#"
+            [[objects]]
+            type = "rect"
+            axis = "y"
+            p0 = -0.5
+            q0 = -0.5
+            p1 = 0.5
+            q1 = 0.5
+            k = 3
+            material = "light"
+            light = true
+            transforms = [{ translate = [0, 0.5, 0] }, { rotate = { axis = "y", degrees = 30 } }]
+            "#,
+        );
+        assert!(pdf > 0.0);
+        assert!((v.y / v.length() - 1.0).abs() < 0.1 && (v.y - 3.5).abs() < 1e-4);
+        assert_eq!(env.lights.pdf_value(ZERO, vec3(0.0, -1.0, 0.0)), 0.0);
+        let (_, _, pdf) = sample_lights(
+            r#"
+            [[objects]]
+            type = "cuboid"
+            min = [2, 2, 2]
+            max = [3, 3, 3]
+            material = "light"
+            light = true
+            "#,
+        );
+        assert!(pdf > 0.0);
+    }
+
+    #[test]
+    fn test_lights_in_groups() {
+        // A light inside a group that moves it, and a group of lights.
+        for (inner, outer) in [("light = true", ""), ("", "light = true")] {
+            let objects = format!(
+                r#"
+                [[objects]]
+                type = "group"
+                bvh = true
+                transforms = [{{ translate = [0, 1, 0] }}]
+                {outer}
+
+                [[objects.objects]]
+                type = "sphere"
+                center = [0, 3, 0]
+                radius = 0.5
+                material = "light"
+                {inner}
+                "#
+            );
+            let (env, v, pdf) = sample_lights(&objects);
+            assert!(pdf > 0.0);
+            assert!(v.y / v.length() > 0.99, "{:?}", v);
+            assert!(env.lights.pdf_value(ZERO, vec3(0.0, 1.0, 0.0)) > 0.0);
+        }
+        // A light in a medium cannot be sampled.
+        let (head, _) = SCENE.split_once("[[objects]]").unwrap();
+        let source = format!(
+            r#"{}
+            [[objects]]
+            type = "sphere"
+            center = [0, 3, 0]
+            radius = 0.5
+            material = "light"
+            light = true
+            medium = {{ color = [1, 1, 1], density = 1 }}
+            "#,
+            head
+        );
+        assert!(matches!(
+            parse_scene(&source, Path::new(".")),
+            Err(SceneError::MediumLight(_))
+        ));
+    }
+
+    #[test]
+    fn test_emissive_obj_meshes_are_lights() {
+        let dir = std::env::temp_dir().join(format!("ray_test_obj_lights_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("lamp.mtl"),
+            "newmtl lamp\nKe 4 4 4\nnewmtl wall\nKd 0.5 0.5 0.5\n",
+        )
+        .unwrap();
+        // A lamp at z = 1 and a wall at z = -1.
+        std::fs::write(
+            dir.join("lamp.obj"),
+            "mtllib lamp.mtl\n\
+             v -1 -1 1\nv 1 -1 1\nv 1 1 1\nv -1 1 1\n\
+             v -1 -1 -1\nv 1 -1 -1\nv 1 1 -1\nv -1 1 -1\n\
+             usemtl lamp\nf 1 2 3 4\n\
+             usemtl wall\nf 5 6 7 8\n",
+        )
+        .unwrap();
+        let (head, _) = SCENE.split_once("[[objects]]").unwrap();
+        let o = point3(0.0, 0.0, 0.0);
+        for (light, bvh) in [("false", "false"), ("true", "false"), ("false", "true")] {
+            let source = format!(
+                "{}[[objects]]\ntype = \"obj\"\npath = \"lamp.obj\"\nbvh = {}\nlight = {}\n",
+                head, bvh, light
+            );
+            let env = parse_scene(&source, &dir).unwrap();
+            assert!(env.lights.pdf_value(o, vec3(0.0, 0.0, 1.0)) > 0.0);
+            assert_eq!(env.lights.pdf_value(o, vec3(0.0, 0.0, -1.0)), 0.0);
+            let mut rng = rand::rngs::SmallRng::seed_from_u64(1);
+            assert!(env.lights.random(&mut rng, o).z > 0.0);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_cornell() {
+        let env = load_scene(concat!(env!("CARGO_MANIFEST_DIR"), "/scenes/cornell.toml")).unwrap();
+        assert_eq!(env.width(), 600);
+        assert_eq!(env.samples_per_pixel(), 1000);
+    }
+}
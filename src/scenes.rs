@@ -1,12 +1,19 @@
+use crate::aov::Aov;
 use crate::bvh::*;
 use crate::camera::Camera;
+use crate::filter::FilterKind;
 use crate::geom::*;
+use crate::integrator::{Integrator, IntegratorKind};
 use crate::material::*;
 use crate::object::*;
 use crate::rect::*;
+use crate::sampler::SamplerKind;
 use crate::sphere::*;
 use crate::texture::*;
+use crate::tile::TileOrder;
+use crate::tonemap::{to_linear8, to_srgb8, ToneMap};
 use rand::prelude::*;
+use std::ops::Range;
 use std::sync::Arc;
 
 pub struct RenderParams {
@@ -16,6 +23,64 @@ pub struct RenderParams {
     pub height: u32,
     pub samples_per_pixel: u32,
     pub max_depth: u32,
+    /// Bounces after which Russian roulette may end a path.
+    pub rr_depth: u32,
+    pub integrator: IntegratorKind,
+    /// Photons traced in each pass of photon mapping, 0 for one per pixel.
+    pub photons_per_pass: u32,
+    /// The radius photons are first gathered in, 0 to size it from the scene.
+    pub photon_radius: Float,
+    /// Independent paths Metropolis light transport starts from.
+    pub mlt_bootstrap: u32,
+    /// Markov chains Metropolis light transport runs.
+    pub mlt_chains: u32,
+    /// Chance of a Metropolis mutation being a large step.
+    pub large_step_probability: Float,
+    /// Standard deviation of small Metropolis mutations in primary sample
+    /// space.
+    pub mutation_size: Float,
+    /// How far ambient occlusion looks for occluders, 0 for a tenth of the
+    /// scene's diagonal.
+    pub ao_distance: Float,
+    pub seed: u64,
+    pub sampler: SamplerKind,
+    /// Samples per pixel the stratified sampler lays its pattern out for, 0
+    /// for `samples_per_pixel`. A resumed film keeps the size it started with.
+    pub sampler_pattern: u32,
+    /// Samples per pixel added in each progressive pass, 0 for a single pass.
+    pub samples_per_pass: u32,
+    /// Relative error below which a pixel stops being sampled, 0 to sample
+    /// every pixel equally.
+    pub noise_threshold: Float,
+    /// Samples every pixel gets before adaptive sampling measures its error.
+    pub min_samples: u32,
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+    /// Passes rendered into the film alongside the beauty image. Only path
+    /// tracing splits the light into the lighting passes, and photon mapping
+    /// and Metropolis light transport render none.
+    pub aovs: Vec<Aov>,
+    /// Whether to filter the noise out of the image before it is shown or
+    /// saved, see `denoise`.
+    pub denoise: bool,
+    /// À-trous passes of the denoiser, each reaching twice as far.
+    pub denoise_passes: u32,
+    /// How many standard errors of luminance apart pixels may be and still
+    /// be averaged by the denoiser.
+    pub denoise_sigma_color: Float,
+    /// How far apart normals may be and still be averaged by the denoiser.
+    pub denoise_sigma_normal: Float,
+    /// How far apart albedos may be and still be averaged by the denoiser.
+    pub denoise_sigma_albedo: Float,
+    /// The filter samples are spread over neighboring pixels with. Photon
+    /// mapping, Metropolis light transport and light paths of bidirectional
+    /// path tracing are box filtered whatever it is.
+    pub filter: FilterKind,
+    /// The filter's radius in pixels, 0 for its default.
+    pub filter_radius: Float,
+    pub tone_map: ToneMap,
+    /// Exposure compensation in stops.
+    pub exposure: Float,
 }
 
 impl RenderParams {
@@ -34,6 +99,61 @@ impl RenderParams {
             height,
             samples_per_pixel,
             max_depth,
+            rr_depth: 3,
+            integrator: IntegratorKind::default(),
+            photons_per_pass: 0,
+            photon_radius: 0.0,
+            mlt_bootstrap: 100_000,
+            mlt_chains: 1000,
+            large_step_probability: 0.3,
+            mutation_size: 0.01,
+            ao_distance: 0.0,
+            seed: 0,
+            sampler: SamplerKind::default(),
+            sampler_pattern: 0,
+            samples_per_pass: 0,
+            noise_threshold: 0.0,
+            min_samples: 16,
+            tile_size: 32,
+            tile_order: TileOrder::default(),
+            aovs: Vec::new(),
+            denoise: false,
+            denoise_passes: 5,
+            denoise_sigma_color: 4.0,
+            denoise_sigma_normal: 0.5,
+            denoise_sigma_albedo: 0.1,
+            filter: FilterKind::default(),
+            filter_radius: 0.0,
+            tone_map: ToneMap::default(),
+            exposure: 0.0,
+        }
+    }
+
+    pub fn set_width(&mut self, width: u32) {
+        self.width = width;
+        self.height = (width as Float / self.apsect_ratio) as u32;
+    }
+
+    /// The AOVs to render: those asked for, and the guides of the denoiser.
+    pub fn rendered_aovs(&self) -> Vec<Aov> {
+        let mut aovs = self.aovs.clone();
+        if self.denoise {
+            for aov in [Aov::Albedo, Aov::Normal] {
+                if !aovs.contains(&aov) {
+                    aovs.push(aov);
+                }
+            }
+        }
+        aovs
+    }
+
+    /// `pixels` as 8 bit RGB: exposed, tone mapped and sRGB encoded, or
+    /// linear if `integrator` renders data rather than radiance.
+    pub fn to_8bit(&self, pixels: &[Color]) -> Vec<u8> {
+        if self.integrator.is_data() {
+            to_linear8(pixels)
+        } else {
+            to_srgb8(pixels, self.tone_map, self.exposure)
         }
     }
 }
@@ -43,6 +163,8 @@ pub struct Environment {
     pub camera: Camera,
     pub lights: Arc<dyn Object>,
     pub params: RenderParams,
+    /// An integrator used instead of `params.integrator`.
+    pub integrator: Option<Arc<dyn Integrator>>,
 }
 
 impl Environment {
@@ -57,6 +179,7 @@ impl Environment {
             camera,
             lights,
             params,
+            integrator: None,
         }
     }
 
@@ -83,6 +206,37 @@ impl Environment {
     pub fn max_depth(&self) -> u32 {
         self.params.max_depth
     }
+
+    pub fn set_aspect_ratio(&mut self, aspect_ratio: Float) {
+        self.params.apsect_ratio = aspect_ratio;
+        self.params.set_width(self.params.width);
+        self.camera.set_aspect_ratio(aspect_ratio);
+    }
+}
+
+pub type SceneFn = fn() -> Environment;
+
+/// The built in scenes as (name, description, constructor).
+pub const SCENES: &[(&str, &str, SceneFn)] = &[
+    ("cornell", "Cornell box with metal, glass and diffuse objects", || {
+        cornell_box(false)
+    }),
+    ("cornell_smoke", "Cornell box with a box of black smoke", || {
+        cornell_box(true)
+    }),
+    (
+        "book2_final",
+        "Final scene of Ray Tracing: The Next Week",
+        book2_final_scene,
+    ),
+    ("marbles", "Random marbles around three large spheres", marbles_scene),
+];
+
+pub fn builtin_scene(name: &str) -> Option<Environment> {
+    SCENES
+        .iter()
+        .find(|(n, _, _)| *n == name)
+        .map(|(_, _, scene)| scene())
 }
 
 pub fn cornell_box(smoke: bool) -> Environment {
@@ -156,8 +310,16 @@ pub fn cornell_box(smoke: bool) -> Environment {
 }
 
 pub fn book2_final_scene() -> Environment {
+    book2_final_scene_with(LinearBvh::new)
+}
+
+pub type BvhFn<B> = fn(&mut Objects, usize, usize, Range<Float>) -> B;
+
+/// The book 2 final scene with its hierarchies built by `bvh`, for comparing
+/// implementations.
+pub fn book2_final_scene_with<B: Object + 'static>(bvh: BvhFn<B>) -> Environment {
     let mut objects = Objects::new(Vec::new());
-    let mut rng = thread_rng();
+    let mut rng = SmallRng::seed_from_u64(2);
     let mut boxes1 = Objects::new(Vec::new());
     let ground = lambertian(0.48, 0.83, 0.53);
 
@@ -179,7 +341,7 @@ pub fn book2_final_scene() -> Environment {
         }
     }
     let n = boxes1.objects.len();
-    objects.add(BvhNode::new(&mut boxes1, 0, n, 0.0..1.0));
+    objects.add(bvh(&mut boxes1, 0, n, 0.0..1.0));
     let light = diffuse_light(7.0, 7.0, 7.0);
     let light_rect = Rect::new(Axis::Y, 123.0, 147.0, 423.0, 412.0, 554.0, light);
     objects.add(FlipFace::new(light_rect.clone()));
@@ -212,7 +374,7 @@ pub fn book2_final_scene() -> Environment {
     let boundary = Sphere::new(ZERO, 5000.0, dielectric(1.5));
     objects.add(ConstantMedium::new(boundary, WHITE, 0.0001));
 
-    let earth_texture = ImageTexture::new("/Users/jeffreyrosenbluth/Rust/ray/assets/earthmap.jpeg");
+    let earth_texture = ImageTexture::new("assets/earthmap.jpeg");
     let earth = lambertian_texture(earth_texture);
     objects.add(Sphere::new(point3(400.0, 200.0, 400.0), 100.0, earth));
     let perlin_texture = PerlinTexture::new(0.08);
@@ -231,7 +393,7 @@ pub fn book2_final_scene() -> Environment {
     }
 
     objects.add(Translate::new(
-        Rotate::new(Axis::Y, BvhNode::new(&mut boxes2, 0, ns, 0.0..1.0), 15.0),
+        Rotate::new(Axis::Y, bvh(&mut boxes2, 0, ns, 0.0..1.0), 15.0),
         vec3(-100.0, 270.0, 395.0),
     ));
 
@@ -250,7 +412,7 @@ pub fn book2_final_scene() -> Environment {
 }
 
 pub fn marbles_scene() -> Environment {
-    let mut rng = rand::thread_rng();
+    let mut rng = SmallRng::seed_from_u64(1);
     let mut world = Objects::new(Vec::new());
     let checker = lambertian_texture(CheckeredTexture::with_color(
         vec3(0.3, 0.3, 0.3),
@@ -299,7 +461,7 @@ pub fn marbles_scene() -> Environment {
         }
     }
     let n = marbles.objects.len();
-    world.add(BvhNode::new(&mut marbles, 0, n, 0.0..1.0));
+    world.add(LinearBvh::new(&mut marbles, 0, n, 0.0..1.0));
 
     let mat1 = dielectric(1.5);
     let mat2 = lambertian(0.4, 0.2, 0.1);
@@ -317,7 +479,7 @@ pub fn marbles_scene() -> Environment {
     let camera = Camera::basic(point3(13.0, 2.0, 3.0), ZERO, 20.0, 1.5, 0.1, 10.0);
     let rparams = RenderParams::new(color(0.73, 0.73, 0.73), 1.5, 1200, 10, 50);
     Environment::new(
-        Box::new(BvhNode::new(&mut world, 0, n, 0.0..1.0)),
+        Box::new(LinearBvh::new(&mut world, 0, n, 0.0..1.0)),
         camera,
         Arc::new(EmptyObject {}),
         rparams,
@@ -2,11 +2,10 @@ use crate::aabb::*;
 use crate::geom::*;
 use crate::material::Material;
 use crate::object::*;
+use rand::{Rng, RngCore};
 use std::f32::consts::PI;
 use std::ops::Range;
 use std::sync::Arc;
-use rand::rngs::SmallRng;
-use rand::Rng;
 
 pub struct Sphere {
     pub center0: Point3,
@@ -60,8 +59,9 @@ pub fn sphere_uv(p: Point3) -> (Float, Float) {
     (phi / (2.0 * PI), theta / PI)
 }
 
-impl Object for Sphere {
-    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+impl Sphere {
+    // The nearest root of the ray equation in `t_min..t_max`.
+    fn root(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<Float> {
         let oc = r.origin - self.center(r.time);
         let a = r.direction.length2();
         let half_b = dot(oc, r.direction);
@@ -81,6 +81,13 @@ impl Object for Sphere {
                 return None;
             };
         }
+        Some(root)
+    }
+}
+
+impl Object for Sphere {
+    fn hit(&self, _rng: &mut dyn RngCore, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let root = self.root(r, t_min, t_max)?;
         let p = r.at(root);
         let outward_normal = (p - self.center(r.time)) / self.radius;
         let (u, v) = sphere_uv(outward_normal);
@@ -102,24 +109,31 @@ impl Object for Sphere {
     }
 
     fn pdf_value(&self, o: Vec3, v: Vec3) -> Float {
-        if let Some(_hit) = self.hit(&Ray::new(o, v, 0.0), 0.001, f32::MAX) {
-            let cos_theta_max = (1.0 - self.radius * self.radius / (self.center0 - o).length2()).sqrt();
+        if self.root(&Ray::new(o, v, 0.0), 0.001, f32::MAX).is_some() {
+            let cos_theta_max =
+                (1.0 - self.radius * self.radius / (self.center0 - o).length2()).sqrt();
             let solid_angle = 2.0 * PI * (1.0 - cos_theta_max);
             1.0 / solid_angle
         } else {
-            1.0
+            0.0
         }
     }
 
-    fn random(&self, rng: &mut SmallRng, o: Vec3) -> Vec3 {
+    fn random(&self, rng: &mut dyn RngCore, o: Vec3) -> Vec3 {
         let direction = self.center0 - o;
         let distance_squared = direction.length2();
         let uvw = Onb::build_from_w(direction);
         uvw.local(random_to_sphere(rng, self.radius, distance_squared))
     }
+
+    fn sample_area(&self, rng: &mut dyn RngCore, time: Float) -> Option<(Point3, Vec3, Float)> {
+        let n = random_unit_vector(rng);
+        let pdf = 1.0 / (4.0 * PI * self.radius * self.radius);
+        Some((self.center(time) + self.radius * n, n, pdf))
+    }
 }
 
-fn random_to_sphere(rng: &mut SmallRng, radius: f32, distance_squared: f32) -> Vec3 {
+fn random_to_sphere(rng: &mut dyn RngCore, radius: f32, distance_squared: f32) -> Vec3 {
     let r1: Float = rng.gen();
     let r2: Float = rng.gen();
     let z = 1.0 + r2 * ((1.0 - radius.powi(2) / distance_squared).sqrt() - 1.0);
@@ -127,4 +141,4 @@ fn random_to_sphere(rng: &mut SmallRng, radius: f32, distance_squared: f32) -> V
     let x = phi.cos() * (1.0 - z * z).sqrt();
     let y = phi.sin() * (1.0 - z * z).sqrt();
     Vec3::new(x, y, z)
-}
\ No newline at end of file
+}
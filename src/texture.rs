@@ -1,6 +1,7 @@
 use crate::geom::*;
 use image::*;
 use noise::*;
+use std::path::Path;
 use std::sync::Arc;
 
 pub trait Texture: Sync + Send {
@@ -13,6 +14,15 @@ impl Texture for Color {
     }
 }
 
+impl<T> Texture for Arc<T>
+where
+    T: Texture + ?Sized,
+{
+    fn value(&self, u: Float, v: Float, p: Point3) -> Color {
+        (**self).value(u, v, p)
+    }
+}
+
 #[derive(Clone)]
 pub struct CheckeredTexture<T, U>
 where
@@ -49,9 +59,9 @@ where
     fn value(&self, u: Float, v: Float, p: Point3) -> Color {
         let sines = (10.0 * p.x).sin() * (10.0 * p.y).sin() * (10.0 * p.z).sin();
         if sines < 0.0 {
-            return self.odd.value(u, v, p);
+            self.odd.value(u, v, p)
         } else {
-            return self.even.value(u, v, p);
+            self.even.value(u, v, p)
         }
     }
 }
@@ -89,7 +99,14 @@ pub struct ImageTexture {
 
 impl ImageTexture {
     pub fn new(path: &'static str) -> Self {
-        let img = open(path).unwrap();
+        Self::open(path).unwrap()
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> ImageResult<Self> {
+        Ok(Self::from_image(&open(path)?))
+    }
+
+    pub fn from_image(img: &DynamicImage) -> Self {
         let rgb8 = img.to_rgb8();
         let data = rgb8.to_vec();
         let width = rgb8.width() as usize;
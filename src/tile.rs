@@ -0,0 +1,155 @@
+/// A rectangle of pixels, `x0..x1` by `y0..y1` with y = 0 the top row.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tile {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl Tile {
+    pub fn width(&self) -> u32 {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> u32 {
+        self.y1 - self.y0
+    }
+
+    pub fn pixels(&self) -> u32 {
+        self.width() * self.height()
+    }
+}
+
+/// The order in which tiles are handed out to render threads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TileOrder {
+    /// Rows of tiles from the top left.
+    Scanline,
+    /// Outwards from the center of the image.
+    Spiral,
+    /// Along a Hilbert curve, which keeps consecutive tiles next to each other.
+    #[default]
+    Hilbert,
+}
+
+/// Cover a `width` by `height` image with tiles of at most `size` pixels square.
+pub fn tiles(width: u32, height: u32, size: u32, order: TileOrder) -> Vec<Tile> {
+    let size = size.max(1);
+    let nx = width.div_ceil(size);
+    let ny = height.div_ceil(size);
+    let tile = |(tx, ty): (u32, u32)| Tile {
+        x0: tx * size,
+        y0: ty * size,
+        x1: ((tx + 1) * size).min(width),
+        y1: ((ty + 1) * size).min(height),
+    };
+    let cells: Vec<(u32, u32)> = match order {
+        TileOrder::Scanline => (0..ny)
+            .flat_map(|ty| (0..nx).map(move |tx| (tx, ty)))
+            .collect(),
+        TileOrder::Spiral => spiral(nx, ny),
+        TileOrder::Hilbert => hilbert(nx, ny),
+    };
+    cells.into_iter().map(tile).collect()
+}
+
+// Walk right 1, down 1, left 2, up 2, right 3, ... from the center cell and
+// keep the cells inside the grid.
+fn spiral(nx: u32, ny: u32) -> Vec<(u32, u32)> {
+    let total = (nx * ny) as usize;
+    let mut cells = Vec::with_capacity(total);
+    let (mut x, mut y) = (((nx as i64) - 1) / 2, ((ny as i64) - 1) / 2);
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let inside = |x: i64, y: i64| x >= 0 && y >= 0 && x < nx as i64 && y < ny as i64;
+    if inside(x, y) {
+        cells.push((x as u32, y as u32));
+    }
+    let mut run = 1;
+    let mut d = 0;
+    while cells.len() < total {
+        for _ in 0..2 {
+            let (dx, dy) = directions[d % 4];
+            for _ in 0..run {
+                x += dx;
+                y += dy;
+                if inside(x, y) {
+                    cells.push((x as u32, y as u32));
+                }
+            }
+            d += 1;
+        }
+        run += 1;
+    }
+    cells
+}
+
+// Map distances along a Hilbert curve over the smallest power of two square
+// holding the grid and keep the cells inside it.
+fn hilbert(nx: u32, ny: u32) -> Vec<(u32, u32)> {
+    let n = nx.max(ny).max(1).next_power_of_two();
+    (0..n as u64 * n as u64)
+        .map(|d| hilbert_cell(n, d))
+        .filter(|&(x, y)| x < nx && y < ny)
+        .collect()
+}
+
+fn hilbert_cell(n: u32, d: u64) -> (u32, u32) {
+    let (mut x, mut y) = (0u64, 0u64);
+    let mut t = d;
+    let mut s = 1u64;
+    while s < n as u64 {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x as u32, y as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiles_cover_image() {
+        for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert] {
+            let (w, h) = (103, 61);
+            let mut covered = vec![0; (w * h) as usize];
+            for t in tiles(w, h, 16, order) {
+                for y in t.y0..t.y1 {
+                    for x in t.x0..t.x1 {
+                        covered[(y * w + x) as usize] += 1;
+                    }
+                }
+            }
+            assert!(covered.iter().all(|&c| c == 1), "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn test_tile_orders() {
+        let scanline = tiles(64, 64, 16, TileOrder::Scanline);
+        assert_eq!(scanline[1].x0, 16);
+        assert_eq!(scanline[4].y0, 16);
+
+        let spiral = tiles(80, 80, 16, TileOrder::Spiral);
+        assert_eq!((spiral[0].x0, spiral[0].y0), (32, 32));
+
+        let hilbert = tiles(64, 64, 16, TileOrder::Hilbert);
+        for pair in hilbert.windows(2) {
+            let dx = pair[0].x0.abs_diff(pair[1].x0);
+            let dy = pair[0].y0.abs_diff(pair[1].y0);
+            assert_eq!(dx + dy, 16);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use crate::geom::*;
+
+/// Maps linear scene radiance to display values in 0..1.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ToneMap {
+    /// Clip at 1.
+    #[default]
+    Clamp,
+    /// c / (1 + c)
+    Reinhard,
+    /// Reinhard that maps `white` and everything above it to 1.
+    ExtendedReinhard { white: Float },
+    /// Stephen Hill's fit of the ACES reference and sRGB output transforms.
+    AcesFitted,
+    /// John Hable's filmic curve from Uncharted 2.
+    Hable,
+}
+
+const ACES_INPUT: [[Float; 3]; 3] = [
+    [0.59719, 0.35458, 0.04823],
+    [0.07600, 0.90834, 0.01566],
+    [0.02840, 0.13383, 0.83777],
+];
+
+const ACES_OUTPUT: [[Float; 3]; 3] = [
+    [1.60475, -0.53108, -0.07367],
+    [-0.10208, 1.10813, -0.00605],
+    [-0.00327, -0.07276, 1.07602],
+];
+
+fn mat_mul(m: &[[Float; 3]; 3], c: Color) -> Color {
+    color(
+        m[0][0] * c.x + m[0][1] * c.y + m[0][2] * c.z,
+        m[1][0] * c.x + m[1][1] * c.y + m[1][2] * c.z,
+        m[2][0] * c.x + m[2][1] * c.y + m[2][2] * c.z,
+    )
+}
+
+fn map(c: Color, f: impl Fn(Float) -> Float) -> Color {
+    color(f(c.x), f(c.y), f(c.z))
+}
+
+fn hable_partial(x: Float) -> Float {
+    const A: Float = 0.15;
+    const B: Float = 0.50;
+    const C: Float = 0.10;
+    const D: Float = 0.20;
+    const E: Float = 0.02;
+    const F: Float = 0.30;
+    (x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F) - E / F
+}
+
+impl ToneMap {
+    pub fn apply(&self, c: Color) -> Color {
+        let c = map(c, |v| v.max(0.0));
+        let mapped = match *self {
+            ToneMap::Clamp => c,
+            ToneMap::Reinhard => map(c, |v| v / (1.0 + v)),
+            ToneMap::ExtendedReinhard { white } => {
+                let w2 = white * white;
+                map(c, |v| v * (1.0 + v / w2) / (1.0 + v))
+            }
+            ToneMap::AcesFitted => {
+                let v = mat_mul(&ACES_INPUT, c);
+                let v = map(v, |v| {
+                    (v * (v + 0.0245786) - 0.000090537)
+                        / (v * (0.983729 * v + 0.432951) + 0.238081)
+                });
+                mat_mul(&ACES_OUTPUT, v)
+            }
+            ToneMap::Hable => {
+                const EXPOSURE_BIAS: Float = 2.0;
+                const WHITE: Float = 11.2;
+                let scale = 1.0 / hable_partial(WHITE);
+                map(c, |v| hable_partial(EXPOSURE_BIAS * v) * scale)
+            }
+        };
+        map(mapped, |v| v.clamp(0.0, 1.0))
+    }
+}
+
+/// The sRGB opto-electronic transfer function, linear 0..1 to encoded 0..1.
+pub fn srgb_oetf(v: Float) -> Float {
+    if v <= 0.0031308 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Scale by `exposure` stops, tone map and encode as 8 bit sRGB.
+pub fn to_srgb8(pixels: &[Color], tone_map: ToneMap, exposure: Float) -> Vec<u8> {
+    let scale = exposure.exp2();
+    let mut data = Vec::with_capacity(3 * pixels.len());
+    for &pixel in pixels {
+        let c = tone_map.apply(scale * pixel);
+        for v in [c.x, c.y, c.z] {
+            data.push((255.0 * srgb_oetf(v) + 0.5) as u8);
+        }
+    }
+    data
+}
+
+/// Clamp to [0, 1] and encode as 8 bit without tone mapping or the sRGB
+/// transfer function, for data such as normals and depth.
+pub fn to_linear8(pixels: &[Color]) -> Vec<u8> {
+    pixels
+        .iter()
+        .flat_map(|c| [c.x, c.y, c.z])
+        .map(|v| (255.0 * v.clamp(0.0, 1.0) + 0.5) as u8)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATORS: [ToneMap; 5] = [
+        ToneMap::Clamp,
+        ToneMap::Reinhard,
+        ToneMap::ExtendedReinhard { white: 4.0 },
+        ToneMap::AcesFitted,
+        ToneMap::Hable,
+    ];
+
+    #[test]
+    fn test_srgb_oetf() {
+        assert_eq!(srgb_oetf(0.0), 0.0);
+        assert!((srgb_oetf(1.0) - 1.0).abs() < 1e-6);
+        assert!((srgb_oetf(0.18) - 0.4613).abs() < 1e-3);
+        // Both pieces meet at the break point.
+        let below = 12.92 * 0.0031308;
+        assert!((srgb_oetf(0.0031309) - below).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_operators_are_monotonic_and_bounded() {
+        for op in OPERATORS {
+            let black = op.apply(BLACK);
+            assert!(black.length() < 1e-3, "{:?}", op);
+            let mut last = -1.0;
+            for i in 0..200 {
+                let v = op.apply(WHITE * (i as Float * 0.1)).y;
+                assert!((0.0..=1.0).contains(&v) && v >= last, "{:?}", op);
+                last = v;
+            }
+        }
+        let white = ToneMap::ExtendedReinhard { white: 4.0 }.apply(WHITE * 4.0);
+        assert!((white.x - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_exposure() {
+        let pixels = [color(0.25, 0.5, 1.0)];
+        let brighter = to_srgb8(&pixels, ToneMap::Clamp, 1.0);
+        assert_eq!(
+            brighter,
+            to_srgb8(&[color(0.5, 1.0, 2.0)], ToneMap::Clamp, 0.0)
+        );
+        assert_eq!(brighter[2], 255);
+    }
+
+    #[test]
+    fn test_linear8() {
+        let data = to_linear8(&[color(-1.0, 0.5, 2.0)]);
+        assert_eq!(data, [0, 128, 255]);
+    }
+}
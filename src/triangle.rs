@@ -0,0 +1,418 @@
+use crate::aabb::*;
+use crate::bvh::FlatBvh;
+use crate::geom::*;
+use crate::material::Material;
+use crate::object::*;
+use rand::{Rng, RngCore};
+use rayon::prelude::*;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Möller-Trumbore intersection. Returns (t, b1, b2) where the hit point is
+/// (1 - b1 - b2) * p0 + b1 * p1 + b2 * p2.
+pub fn intersect_triangle(
+    p: &[Point3; 3],
+    r: &Ray,
+    t_min: Float,
+    t_max: Float,
+) -> Option<(Float, Float, Float)> {
+    let e1 = p[1] - p[0];
+    let e2 = p[2] - p[0];
+    let pvec = cross(r.direction, e2);
+    let det = dot(e1, pvec);
+    if det.abs() < 1.0e-12 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let tvec = r.origin - p[0];
+    let b1 = dot(tvec, pvec) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+    let qvec = cross(tvec, e1);
+    let b2 = dot(r.direction, qvec) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+    let t = dot(e2, qvec) * inv_det;
+    if t < t_min || t > t_max {
+        return None;
+    }
+    Some((t, b1, b2))
+}
+
+pub fn triangle_area(p: &[Point3; 3]) -> Float {
+    0.5 * cross(p[1] - p[0], p[2] - p[0]).length()
+}
+
+pub fn triangle_box(p: &[Point3; 3]) -> Aabb {
+    let b = surrounding_box(Aabb::new(p[0], p[0]), Aabb::new(p[1], p[1]));
+    surrounding_box(b, Aabb::new(p[2], p[2])).padded()
+}
+
+// Uniformly distributed point on the triangle.
+fn sample_triangle(rng: &mut dyn RngCore, p: &[Point3; 3]) -> Point3 {
+    let su = rng.gen::<Float>().sqrt();
+    let b0 = 1.0 - su;
+    let b1 = rng.gen::<Float>() * su;
+    b0 * p[0] + b1 * p[1] + (1.0 - b0 - b1) * p[2]
+}
+
+// The unit geometric normal, on the side the winding order gives.
+fn triangle_normal(p: &[Point3; 3]) -> Vec3 {
+    cross(p[1] - p[0], p[2] - p[0]).normalize()
+}
+
+// Solid angle pdf of the direction `v`, which hits triangle `p` at `t`, when
+// points are picked uniformly on a surface of total area `area`.
+fn triangle_pdf(p: &[Point3; 3], v: Vec3, t: Float, area: Float) -> Float {
+    let ng = cross(p[1] - p[0], p[2] - p[0]);
+    let distance_squared = t * t * v.length2();
+    let cosine = (dot(v, ng) / (v.length() * ng.length())).abs();
+    if cosine <= 0.0 {
+        return 0.0;
+    }
+    distance_squared / (cosine * area)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn triangle_record(
+    r: &Ray,
+    t: Float,
+    b1: Float,
+    b2: Float,
+    p: &[Point3; 3],
+    n: Option<[Vec3; 3]>,
+    uv: [(Float, Float); 3],
+    material: Arc<dyn Material>,
+) -> HitRecord {
+    let b0 = 1.0 - b1 - b2;
+    let mut ng = cross(p[1] - p[0], p[2] - p[0]).normalize();
+    let ns = match n {
+        Some(n) => {
+            let ns = b0 * n[0] + b1 * n[1] + b2 * n[2];
+            if ns.near_zero() {
+                ng
+            } else {
+                ns.normalize()
+            }
+        }
+        None => ng,
+    };
+    // The winding order decides the geometric normal, make it agree with the
+    // shading normal.
+    if dot(ng, ns) < 0.0 {
+        ng = -ng;
+    }
+    let front_face = dot(r.direction, ng) < 0.0;
+    let normal = if front_face { ns } else { -ns };
+    let u = b0 * uv[0].0 + b1 * uv[1].0 + b2 * uv[2].0;
+    let v = b0 * uv[0].1 + b1 * uv[1].1 + b2 * uv[2].1;
+    HitRecord::new(r.at(t), normal, material, t, u, v, front_face)
+}
+
+const DEFAULT_UVS: [(Float, Float); 3] = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)];
+
+#[derive(Clone)]
+pub struct Triangle {
+    pub vertices: [Point3; 3],
+    pub normals: Option<[Vec3; 3]>,
+    pub uvs: [(Float, Float); 3],
+    pub material: Arc<dyn Material>,
+}
+
+impl Triangle {
+    pub fn new(p0: Point3, p1: Point3, p2: Point3, material: Arc<dyn Material>) -> Self {
+        Self {
+            vertices: [p0, p1, p2],
+            normals: None,
+            uvs: DEFAULT_UVS,
+            material,
+        }
+    }
+
+    pub fn with_normals(mut self, normals: [Vec3; 3]) -> Self {
+        self.normals = Some(normals);
+        self
+    }
+
+    pub fn with_uvs(mut self, uvs: [(Float, Float); 3]) -> Self {
+        self.uvs = uvs;
+        self
+    }
+
+    pub fn area(&self) -> Float {
+        triangle_area(&self.vertices)
+    }
+}
+
+impl Object for Triangle {
+    fn hit(&self, _rng: &mut dyn RngCore, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let (t, b1, b2) = intersect_triangle(&self.vertices, r, t_min, t_max)?;
+        Some(triangle_record(
+            r,
+            t,
+            b1,
+            b2,
+            &self.vertices,
+            self.normals,
+            self.uvs,
+            self.material.clone(),
+        ))
+    }
+
+    fn bounding_box(&self, _time_range: &Range<Float>) -> Option<Aabb> {
+        Some(triangle_box(&self.vertices))
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> Float {
+        match intersect_triangle(&self.vertices, &Ray::new(o, v, 0.0), 0.001, Float::MAX) {
+            Some((t, _, _)) => triangle_pdf(&self.vertices, v, t, self.area()),
+            None => 0.0,
+        }
+    }
+
+    fn random(&self, rng: &mut dyn RngCore, o: Vec3) -> Vec3 {
+        sample_triangle(rng, &self.vertices) - o
+    }
+
+    fn sample_area(&self, rng: &mut dyn RngCore, _time: Float) -> Option<(Point3, Vec3, Float)> {
+        let p = sample_triangle(rng, &self.vertices);
+        Some((p, triangle_normal(&self.vertices), 1.0 / self.area()))
+    }
+}
+
+/// Vertex buffers that can be shared by several meshes. `normals` and `uvs`
+/// are either empty or have one entry per position.
+pub struct MeshData {
+    pub positions: Vec<Point3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<(Float, Float)>,
+    pub indices: Vec<[u32; 3]>,
+}
+
+impl MeshData {
+    pub fn new(
+        positions: Vec<Point3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<(Float, Float)>,
+        indices: Vec<[u32; 3]>,
+    ) -> Self {
+        debug_assert!(normals.is_empty() || normals.len() == positions.len());
+        debug_assert!(uvs.is_empty() || uvs.len() == positions.len());
+        Self {
+            positions,
+            normals,
+            uvs,
+            indices,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    pub fn vertices(&self, i: usize) -> [Point3; 3] {
+        let [a, b, c] = self.indices[i];
+        [
+            self.positions[a as usize],
+            self.positions[b as usize],
+            self.positions[c as usize],
+        ]
+    }
+
+    pub fn normals(&self, i: usize) -> Option<[Vec3; 3]> {
+        if self.normals.is_empty() {
+            return None;
+        }
+        let [a, b, c] = self.indices[i];
+        Some([
+            self.normals[a as usize],
+            self.normals[b as usize],
+            self.normals[c as usize],
+        ])
+    }
+
+    pub fn uvs(&self, i: usize) -> [(Float, Float); 3] {
+        if self.uvs.is_empty() {
+            return DEFAULT_UVS;
+        }
+        let [a, b, c] = self.indices[i];
+        [
+            self.uvs[a as usize],
+            self.uvs[b as usize],
+            self.uvs[c as usize],
+        ]
+    }
+}
+
+const MESH_LEAF_SIZE: usize = 4;
+
+/// An indexed triangle mesh with its own bounding volume hierarchy.
+pub struct TriangleMesh {
+    pub data: Arc<MeshData>,
+    pub material: Arc<dyn Material>,
+    bvh: FlatBvh,
+    area_cdf: Vec<Float>,
+}
+
+impl TriangleMesh {
+    pub fn new(data: Arc<MeshData>, material: Arc<dyn Material>) -> Self {
+        let boxes: Vec<Aabb> = (0..data.len())
+            .into_par_iter()
+            .map(|i| triangle_box(&data.vertices(i)))
+            .collect();
+        let bvh = FlatBvh::new(&boxes, MESH_LEAF_SIZE);
+        let mut total = 0.0;
+        let area_cdf = (0..data.len())
+            .map(|i| {
+                total += triangle_area(&data.vertices(i));
+                total
+            })
+            .collect();
+        Self {
+            data,
+            material,
+            bvh,
+            area_cdf,
+        }
+    }
+
+    pub fn area(&self) -> Float {
+        self.area_cdf.last().copied().unwrap_or(0.0)
+    }
+
+    // A triangle picked with probability proportional to its area.
+    fn pick_triangle(&self, rng: &mut dyn RngCore) -> Option<usize> {
+        if self.area_cdf.is_empty() {
+            return None;
+        }
+        let target = rng.gen::<Float>() * self.area();
+        let tri = self.area_cdf.partition_point(|&a| a < target);
+        Some(tri.min(self.data.len() - 1))
+    }
+
+    // Closest hit as (triangle, t, b1, b2).
+    fn intersect(
+        &self,
+        r: &Ray,
+        t_min: Float,
+        t_max: Float,
+    ) -> Option<(usize, Float, Float, Float)> {
+        let mut found = None;
+        self.bvh.traverse(r, t_min, t_max, |i, closest| {
+            let tri = self.bvh.order[i] as usize;
+            let (t, b1, b2) = intersect_triangle(&self.data.vertices(tri), r, t_min, closest)?;
+            found = Some((tri, t, b1, b2));
+            Some(t)
+        });
+        found
+    }
+}
+
+impl Object for TriangleMesh {
+    fn hit(&self, _rng: &mut dyn RngCore, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let (tri, t, b1, b2) = self.intersect(r, t_min, t_max)?;
+        Some(triangle_record(
+            r,
+            t,
+            b1,
+            b2,
+            &self.data.vertices(tri),
+            self.data.normals(tri),
+            self.data.uvs(tri),
+            self.material.clone(),
+        ))
+    }
+
+    fn bounding_box(&self, _time_range: &Range<Float>) -> Option<Aabb> {
+        self.bvh.bounding_box()
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> Float {
+        match self.intersect(&Ray::new(o, v, 0.0), 0.001, Float::MAX) {
+            Some((tri, t, _, _)) => triangle_pdf(&self.data.vertices(tri), v, t, self.area()),
+            None => 0.0,
+        }
+    }
+
+    fn random(&self, rng: &mut dyn RngCore, o: Vec3) -> Vec3 {
+        // An empty mesh is never hit, so its pdf is 0 in every direction.
+        match self.pick_triangle(rng) {
+            Some(tri) => sample_triangle(rng, &self.data.vertices(tri)) - o,
+            None => ZERO,
+        }
+    }
+
+    fn sample_area(&self, rng: &mut dyn RngCore, _time: Float) -> Option<(Point3, Vec3, Float)> {
+        let vertices = self.data.vertices(self.pick_triangle(rng)?);
+        let p = sample_triangle(rng, &vertices);
+        Some((p, triangle_normal(&vertices), 1.0 / self.area()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+    use crate::material::lambertian;
+
+    fn quad_mesh() -> TriangleMesh {
+        let positions = vec![
+            point3(0.0, 0.0, 0.0),
+            point3(1.0, 0.0, 0.0),
+            point3(1.0, 1.0, 0.0),
+            point3(0.0, 1.0, 0.0),
+        ];
+        let normals = vec![vec3(0.0, 0.0, 1.0); 4];
+        let uvs = vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+        let data = MeshData::new(positions, normals, uvs, vec![[0, 1, 2], [0, 2, 3]]);
+        TriangleMesh::new(Arc::new(data), lambertian(0.5, 0.5, 0.5))
+    }
+
+    #[test]
+    fn test_triangle_hit() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let tri = Triangle::new(
+            point3(0.0, 0.0, 0.0),
+            point3(1.0, 0.0, 0.0),
+            point3(0.0, 1.0, 0.0),
+            lambertian(0.5, 0.5, 0.5),
+        );
+        let r = Ray::new(point3(0.25, 0.25, 1.0), vec3(0.0, 0.0, -1.0), 0.0);
+        let rec = tri.hit(&mut rng, &r, 0.001, INFINITY).unwrap();
+        assert!((rec.t - 1.0).abs() < 1e-6);
+        assert!(rec.front_face);
+        assert_eq!(rec.normal, vec3(0.0, 0.0, 1.0));
+        let miss = Ray::new(point3(0.75, 0.75, 1.0), vec3(0.0, 0.0, -1.0), 0.0);
+        assert!(tri.hit(&mut rng, &miss, 0.001, INFINITY).is_none());
+    }
+
+    #[test]
+    fn test_mesh_uv_interpolation() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mesh = quad_mesh();
+        let r = Ray::new(point3(0.25, 0.75, -1.0), vec3(0.0, 0.0, 1.0), 0.0);
+        let rec = mesh.hit(&mut rng, &r, 0.001, INFINITY).unwrap();
+        assert!((rec.u - 0.25).abs() < 1e-6 && (rec.v - 0.75).abs() < 1e-6);
+        assert!(!rec.front_face);
+        assert_eq!(rec.normal, vec3(0.0, 0.0, -1.0));
+        assert!((mesh.area() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_empty_mesh_light() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let data = MeshData::new(Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        let mesh = TriangleMesh::new(Arc::new(data), lambertian(0.5, 0.5, 0.5));
+        let o = point3(0.0, 0.0, 1.0);
+        assert_eq!(mesh.random(&mut rng, o), ZERO);
+        assert_eq!(mesh.pdf_value(o, vec3(0.0, 0.0, -1.0)), 0.0);
+        assert!(mesh.bounding_box(&(0.0..1.0)).is_none());
+    }
+}